@@ -0,0 +1,256 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::events::TreeEvent;
+
+// Entries kept in memory, the oldest are dropped first. The file, when there is one, keeps all.
+pub const AUDIT_CAPACITY: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditEntry {
+    // milliseconds since the unix epoch.
+    pub timestamp: u64,
+    // the tree version the mutation produced, shared by every entry of one mutation.
+    pub version: u64,
+    pub operation: &'static str,
+    pub node_id: Option<i32>,
+    pub detail: String,
+}
+
+/*
+ * AuditLog records every committed mutation, one entry per affected node.
+ * Entries are derived from the TreeEvent the store publishes, so anything subscribers see is
+ * audited too. With a file each entry is also appended to it as a JSON line.
+ */
+pub struct AuditLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<AuditEntry>>,
+    // highest version with an entry dropped past capacity, only changed under the entries lock.
+    evicted_through: AtomicU64,
+    // buffered so a mutation touching many nodes is one write, flushed once per record.
+    file: Option<Mutex<BufWriter<File>>>,
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> Self {
+        AuditLog {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+            evicted_through: AtomicU64::new(0),
+            file: None,
+        }
+    }
+
+    pub fn with_file(capacity: usize, path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Some(Mutex::new(BufWriter::new(file))),
+            ..AuditLog::new(capacity)
+        })
+    }
+
+    pub fn record(&self, version: u64, event: &TreeEvent) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let new_entries: Vec<AuditEntry> = describe(event)
+            .into_iter()
+            .map(|(operation, node_id, detail)| AuditEntry {
+                timestamp,
+                version,
+                operation,
+                node_id,
+                detail,
+            })
+            .collect();
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            let written = new_entries
+                .iter()
+                .try_for_each(|entry| writeln!(file, "{}", serde_json::to_string(entry).unwrap()))
+                .and_then(|()| file.flush());
+            // a failed write must not fail the mutation, it has already happened.
+            if let Err(error) = written {
+                log::error!("failed to write audit entry: {}", error);
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        for entry in new_entries {
+            if entries.len() == self.capacity {
+                if let Some(evicted) = entries.pop_front() {
+                    self.evicted_through
+                        .fetch_max(evicted.version, Ordering::Relaxed);
+                }
+            }
+            entries.push_back(entry);
+        }
+    }
+
+    // Every entry for versions after since up to until, oldest first. None when entries from that
+    // range were already dropped, the caller can't catch up from the log and has to refetch.
+    pub fn since(&self, since: u64, until: u64) -> Option<Vec<AuditEntry>> {
+        let entries = self.entries.lock().unwrap();
+        if since < self.evicted_through.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(
+            entries
+                .iter()
+                .filter(|entry| entry.version > since && entry.version <= until)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    // Up to limit of the newest entries, oldest first.
+    pub fn recent(&self, limit: usize) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap();
+        let skip = entries.len().saturating_sub(limit);
+        entries.iter().skip(skip).cloned().collect()
+    }
+}
+
+// (operation, node_id, detail) for each node the event touched.
+fn describe(event: &TreeEvent) -> Vec<(&'static str, Option<i32>, String)> {
+    match event {
+        TreeEvent::NodeAdded { id } => vec![("add", Some(*id), String::new())],
+        TreeEvent::NodesImported { ids } => ids
+            .iter()
+            .map(|id| ("add", Some(*id), "imported".to_string()))
+            .collect(),
+        TreeEvent::NodeMoved { id } => vec![("move", Some(*id), String::new())],
+        TreeEvent::MetadataUpdated { id } => vec![("set_metadata", Some(*id), String::new())],
+        TreeEvent::NodeTouched { id } => vec![("touch", Some(*id), String::new())],
+        TreeEvent::SubtreeCloned { id, source_id } => {
+            vec![("clone", Some(*id), format!("cloned from {}", source_id))]
+        }
+        TreeEvent::NodesSwapped { a, b } => {
+            vec![("swap", Some(*a), format!("swapped with {}", b))]
+        }
+        TreeEvent::TreeRestored => vec![("restore", None, "restored from snapshot".to_string())],
+        TreeEvent::IdsCompacted => vec![("compact", None, "ids renumbered".to_string())],
+        TreeEvent::IndexesRebuilt => {
+            vec![("reindex", None, "indexes rebuilt".to_string())]
+        }
+        TreeEvent::LeavesPruned { ids } => ids
+            .iter()
+            .map(|id| ("delete", Some(*id), "pruned leaf".to_string()))
+            .collect(),
+        TreeEvent::NodesRemoved { ids } => ids
+            .iter()
+            .map(|id| ("delete", Some(*id), String::new()))
+            .collect(),
+        TreeEvent::NodesRelabeled { ids } => ids
+            .iter()
+            .map(|id| ("rename", Some(*id), String::new()))
+            .collect(),
+        TreeEvent::NodeFlattened { id, parent_id } => vec![(
+            "delete",
+            Some(*id),
+            format!("flattened, children moved to {}", parent_id),
+        )],
+        TreeEvent::ChainCollapsed { id, removed } => {
+            let mut entries = vec![("collapse", Some(*id), format!("merged {:?}", removed))];
+            entries.extend(
+                removed
+                    .iter()
+                    .map(|removed_id| ("delete", Some(*removed_id), format!("merged into {}", id))),
+            );
+            entries
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_an_entry_per_node() {
+        let log = AuditLog::new(10);
+        log.record(1, &TreeEvent::NodeAdded { id: 1 });
+        log.record(2, &TreeEvent::LeavesPruned { ids: vec![2, 3] });
+
+        let entries: Vec<_> = log
+            .recent(10)
+            .into_iter()
+            .map(|entry| (entry.operation, entry.node_id))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![("add", Some(1)), ("delete", Some(2)), ("delete", Some(3))]
+        );
+        assert_eq!(log.recent(1)[0].node_id, Some(3));
+    }
+
+    #[test]
+    fn drops_oldest_past_capacity() {
+        let log = AuditLog::new(2);
+        for id in 1..=3 {
+            log.record(id as u64, &TreeEvent::NodeAdded { id });
+        }
+
+        let ids: Vec<_> = log.recent(10).iter().map(|entry| entry.node_id).collect();
+        assert_eq!(ids, vec![Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn appends_json_lines_to_file() {
+        let path = std::env::temp_dir().join(format!("tree-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = AuditLog::with_file(1, &path).unwrap();
+        log.record(1, &TreeEvent::NodeAdded { id: 1 });
+        log.record(2, &TreeEvent::NodeMoved { id: 1 });
+        // several entries from one record go out in a single flush.
+        log.record(3, &TreeEvent::LeavesPruned { ids: vec![2, 3] });
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let operations: Vec<String> = contents
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["operation"].to_string()
+            })
+            .collect();
+        assert_eq!(
+            operations,
+            vec![r#""add""#, r#""move""#, r#""delete""#, r#""delete""#]
+        );
+        assert_eq!(log.recent(10).len(), 1);
+    }
+
+    #[test]
+    fn since_covers_only_retained_versions() {
+        let log = AuditLog::new(3);
+        log.record(1, &TreeEvent::NodeAdded { id: 1 });
+        log.record(2, &TreeEvent::LeavesPruned { ids: vec![2, 3] });
+        log.record(3, &TreeEvent::NodeAdded { id: 4 });
+
+        let versions = |entries: Vec<AuditEntry>| -> Vec<u64> {
+            entries.iter().map(|entry| entry.version).collect()
+        };
+        assert_eq!(versions(log.since(1, 3).unwrap()), vec![2, 2, 3]);
+        assert_eq!(versions(log.since(1, 2).unwrap()), vec![2, 2]);
+        assert_eq!(log.since(3, 3).unwrap(), vec![]);
+        // version 1 was dropped, a client at 0 missed it.
+        assert!(log.since(0, 3).is_none());
+        assert!(log.since(1, 3).is_some());
+
+        // dropping one of version 2's two entries means nobody before 2 can catch up.
+        log.record(4, &TreeEvent::NodeAdded { id: 5 });
+        assert!(log.since(1, 4).is_none());
+        assert_eq!(versions(log.since(2, 4).unwrap()), vec![3, 4]);
+    }
+}
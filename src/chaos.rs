@@ -0,0 +1,114 @@
+use std::{
+    future::{ready, Ready},
+    time::Duration,
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use futures_util::future::LocalBoxFuture;
+
+/*
+ * ChaosDelay holds every response back for the duration after the handler has produced it, so
+ * clients can exercise their retry and timeout paths against a slow server. The handler's work is
+ * already done by then, a retried write has been applied once. Only meant for development.
+ */
+pub struct ChaosDelay {
+    // None responds straight away.
+    delay: Option<Duration>,
+}
+
+impl ChaosDelay {
+    pub fn new(delay: Option<Duration>) -> Self {
+        ChaosDelay { delay }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ChaosDelay
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ChaosDelayMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ChaosDelayMiddleware {
+            service,
+            delay: self.delay,
+        }))
+    }
+}
+
+pub struct ChaosDelayMiddleware<S> {
+    service: S,
+    delay: Option<Duration>,
+}
+
+impl<S, B> Service<ServiceRequest> for ChaosDelayMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let delay = self.delay;
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let response = response.await;
+            if let Some(delay) = delay {
+                actix_rt::time::sleep(delay).await;
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    const DELAY: Duration = Duration::from_millis(50);
+
+    #[actix_rt::test]
+    async fn delays_the_response() {
+        let app = test::init_service(
+            App::new()
+                .wrap(ChaosDelay::new(Some(DELAY)))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let started = Instant::now();
+        let req = test::TestRequest::get().uri("/").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        assert!(started.elapsed() >= DELAY);
+    }
+
+    #[actix_rt::test]
+    async fn responds_straight_away_without_a_delay() {
+        let app = test::init_service(
+            App::new()
+                .wrap(ChaosDelay::new(None))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let started = Instant::now();
+        let req = test::TestRequest::get().uri("/").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        assert!(started.elapsed() < DELAY);
+    }
+}
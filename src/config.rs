@@ -0,0 +1,243 @@
+use std::{env, fmt, path::PathBuf, str::FromStr, time::Duration};
+
+use log::LevelFilter;
+use regex::Regex;
+
+use crate::logging::LogFormat;
+
+/*
+ * TreeConfig holds the limits and behaviour toggles for a tree.
+ * It is read from the environment once at startup in main, tests build it directly
+ * so they don't race on process wide env vars.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct TreeConfig {
+    // None means unlimited.
+    pub max_nodes: Option<usize>,
+    // Maximum number of levels in a tree, a lone root has a depth of 1.
+    pub max_depth: Option<usize>,
+    // Maximum number of direct children per node, None means unlimited.
+    pub max_children: Option<usize>,
+    // Maximum number of roots, Some(1) keeps a single tree rather than a forest.
+    pub max_roots: Option<usize>,
+    // Reject a label that is already used by a sibling under the same parent, like filenames in
+    // a directory. Roots are not checked against each other.
+    pub unique_sibling_labels: bool,
+    // Collapse runs of whitespace inside labels to a single space. Surrounding whitespace is
+    // always trimmed.
+    pub normalize_labels: bool,
+    // Every audit entry is also appended to this file as a JSON line, the in-memory log is
+    // bounded but the file is not.
+    pub audit_log: Option<PathBuf>,
+    // Label of the root POST /api/tree/root and auto_root create, "root" when unset.
+    pub root_label: Option<String>,
+    // Label for a POST /api/tree body without one, e.g. "untitled". Unset the label is required.
+    pub default_label: Option<String>,
+    // The store starts with a root node and the tree keeps to that single root, see AutoRoot.
+    pub auto_root: Option<AutoRoot>,
+    // When set a label must match it in full and may not contain control characters. Build it
+    // with label_pattern() so it is anchored.
+    pub label_pattern: Option<Regex>,
+}
+
+pub const DEFAULT_ROOT_LABEL: &str = "root";
+
+// What adding a node without a parent does once the auto root exists.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AutoRoot {
+    // The add fails, every node has to name its parent.
+    Reject,
+    // The node is added under the root instead.
+    Attach,
+}
+
+impl FromStr for AutoRoot {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "true" | "reject" => Ok(AutoRoot::Reject),
+            "attach" => Ok(AutoRoot::Attach),
+            _ => Err(format!("unknown auto root mode {:?}", value)),
+        }
+    }
+}
+
+// Sample trees TreeStore can be seeded with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Seed {
+    // TreeStore::seed_demo.
+    Demo,
+}
+
+impl FromStr for Seed {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "demo" => Ok(Seed::Demo),
+            _ => Err(format!("unknown seed {:?}", value)),
+        }
+    }
+}
+
+impl TreeConfig {
+    pub fn from_env() -> Self {
+        TreeConfig {
+            max_nodes: parse_env("TREE_MAX_NODES"),
+            max_depth: parse_env("TREE_MAX_DEPTH"),
+            max_children: parse_env("TREE_MAX_CHILDREN"),
+            max_roots: parse_env("TREE_MAX_ROOTS"),
+            unique_sibling_labels: parse_env("TREE_UNIQUE_SIBLING_LABELS").unwrap_or(false),
+            normalize_labels: parse_env("TREE_NORMALIZE_LABELS").unwrap_or(false),
+            audit_log: parse_env("TREE_AUDIT_LOG"),
+            root_label: parse_env("TREE_ROOT_LABEL"),
+            default_label: parse_env("TREE_DEFAULT_LABEL"),
+            // "true" or "reject", or "attach".
+            auto_root: parse_env("TREE_AUTO_ROOT"),
+            // a typo here would silently turn the check off, so it fails startup instead.
+            label_pattern: env::var("TREE_LABEL_PATTERN").ok().map(|pattern| {
+                label_pattern(&pattern)
+                    .unwrap_or_else(|error| panic!("invalid TREE_LABEL_PATTERN: {}", error))
+            }),
+        }
+    }
+
+    pub fn root_label(&self) -> &str {
+        self.root_label.as_deref().unwrap_or(DEFAULT_ROOT_LABEL)
+    }
+}
+
+/*
+ * ServerConfig holds the settings for the http layer rather than the tree itself.
+ */
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    // Requests per second per client ip on POST /api/tree, None means unlimited.
+    pub rate_limit: Option<f64>,
+    // Largest request body accepted by any endpoint, bigger ones get a 413.
+    pub max_payload_bytes: usize,
+    // Number of http worker threads, None keeps actix's default of one per cpu core.
+    pub workers: Option<usize>,
+    // Browser origins allowed to call the api cross-origin, "*" allows any. Empty disables CORS.
+    pub cors_origins: Vec<String>,
+    // Requests without a response after this long get a 503, None waits indefinitely.
+    pub request_timeout: Option<Duration>,
+    // Latency added to every /api/tree response for chaos testing, None adds none.
+    pub chaos_delay: Option<Duration>,
+    // Least severe log level written, from RUST_LOG, e.g. "warn" or "debug".
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    // Sample data loaded into the store at startup, from TREE_SEED.
+    pub seed: Option<Seed>,
+    // How long an idle connection is kept open for its next request, zero closes it after every
+    // response. Defaults to actix's 5 seconds.
+    pub keep_alive: Duration,
+    // Open connections each worker accepts before it stops accepting, defaults to actix's 25k.
+    pub max_connections: usize,
+}
+
+const DEFAULT_MAX_PAYLOAD_BYTES: usize = 1024 * 1024;
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 5;
+const DEFAULT_MAX_CONNECTIONS: usize = 25_000;
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            rate_limit: None,
+            max_payload_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+            workers: None,
+            cors_origins: vec![],
+            request_timeout: None,
+            chaos_delay: None,
+            log_level: LevelFilter::Info,
+            log_format: LogFormat::Text,
+            seed: None,
+            keep_alive: Duration::from_secs(DEFAULT_KEEP_ALIVE_SECS),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        ServerConfig {
+            rate_limit: parse_env("TREE_RATE_LIMIT"),
+            max_payload_bytes: parse_env("TREE_MAX_PAYLOAD_BYTES")
+                .unwrap_or(DEFAULT_MAX_PAYLOAD_BYTES),
+            // actix panics on zero workers.
+            workers: parse_env("TREE_WORKERS").filter(|workers| *workers > 0),
+            cors_origins: env::var("TREE_CORS_ORIGINS")
+                .map(|origins| {
+                    origins
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|origin| !origin.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            request_timeout: parse_env("TREE_REQUEST_TIMEOUT_MS")
+                .filter(|millis| *millis > 0)
+                .map(Duration::from_millis),
+            chaos_delay: parse_env("TREE_CHAOS_DELAY_MS")
+                .filter(|millis| *millis > 0)
+                .map(Duration::from_millis),
+            // only a single level, not env_logger's per-module directives.
+            log_level: parse_env("RUST_LOG").unwrap_or(LevelFilter::Info),
+            log_format: parse_env("TREE_LOG_FORMAT").unwrap_or_default(),
+            seed: parse_env("TREE_SEED"),
+            keep_alive: Duration::from_secs(
+                parse_env("TREE_KEEP_ALIVE_SECS").unwrap_or(DEFAULT_KEEP_ALIVE_SECS),
+            ),
+            // a limit of zero would never accept anything.
+            max_connections: parse_env("TREE_MAX_CONNECTIONS")
+                .filter(|max| *max > 0)
+                .unwrap_or(DEFAULT_MAX_CONNECTIONS),
+        }
+    }
+}
+
+// Compiles pattern to match whole labels, e.g. "[a-z ]+" rejects "abc!" rather than finding "abc".
+pub fn label_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
+
+// None when unset or empty, so the caller's default applies. A value that doesn't parse fails
+// startup like TREE_LABEL_PATTERN does, falling back would hide the typo, e.g. TREE_MAX_NODES=10k
+// quietly running without a limit.
+fn parse_env<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: fmt::Display,
+{
+    let value = env::var(name).ok()?;
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(error) => panic!("invalid {} {:?}: {}", name, value, error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_env_reads_set_values_and_skips_unset_ones() {
+        env::set_var("TREE_TEST_PARSE_ENV_SET", " 12 ");
+        env::set_var("TREE_TEST_PARSE_ENV_EMPTY", "");
+        assert_eq!(parse_env::<usize>("TREE_TEST_PARSE_ENV_SET"), Some(12));
+        assert_eq!(parse_env::<usize>("TREE_TEST_PARSE_ENV_EMPTY"), None);
+        assert_eq!(parse_env::<usize>("TREE_TEST_PARSE_ENV_UNSET"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid TREE_TEST_PARSE_ENV_BAD")]
+    fn parse_env_fails_on_malformed_values() {
+        env::set_var("TREE_TEST_PARSE_ENV_BAD", "10k");
+        parse_env::<usize>("TREE_TEST_PARSE_ENV_BAD");
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    error::Error as StdError,
+    future::{ready, Ready},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{self, ContentType, ETag, EntityTag, TryIntoHeaderValue},
+    web::{self, Bytes},
+    Error,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+
+use crate::tree_store::TreeStore;
+
+#[derive(Deserialize)]
+struct EnvelopeQuery {
+    #[serde(default)]
+    envelope: bool,
+}
+
+/*
+ * Envelope wraps successful JSON responses as {"data": ..., "meta": {"count": n, "version": v}}
+ * when the request has ?envelope=true, everything else passes through untouched. The handler's
+ * body is spliced in as it is, never parsed, so streamed bodies stay streamed and nesting depth
+ * doesn't matter. The meta is read after the handler ran, a write landing in between can make it
+ * newer than the data.
+ */
+pub struct Envelope;
+
+impl<S, B> Transform<S, ServiceRequest> for Envelope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = EnvelopeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(EnvelopeMiddleware { service }))
+    }
+}
+
+pub struct EnvelopeMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for EnvelopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // an unparsable value is left for the handler's own query to reject.
+        let enveloped = web::Query::<EnvelopeQuery>::from_query(req.query_string())
+            .is_ok_and(|query| query.envelope);
+        let tree_store = req.app_data::<web::Data<TreeStore>>().cloned();
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let response = response.await?.map_into_boxed_body();
+            match tree_store {
+                Some(tree_store) if enveloped && is_json_success(&response) => {
+                    Ok(wrap(response, &tree_store))
+                }
+                _ => Ok(response),
+            }
+        })
+    }
+}
+
+fn is_json_success(response: &ServiceResponse<BoxBody>) -> bool {
+    response.status().is_success()
+        && response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with(ContentType::json().0.essence_str()))
+}
+
+fn wrap(response: ServiceResponse<BoxBody>, tree_store: &TreeStore) -> ServiceResponse<BoxBody> {
+    let (req, response) = response.into_parts();
+    let (mut response, body) = response.into_parts();
+    // nothing to put under data.
+    if matches!(body.size(), BodySize::None | BodySize::Sized(0)) {
+        return ServiceResponse::new(req, response.set_body(body));
+    }
+
+    let count = tree_store.len();
+    let version = tree_store.version();
+    let body = Spliced {
+        prefix: Some(Bytes::from_static(b"{\"data\":")),
+        body,
+        suffix: Some(Bytes::from(format!(
+            r#","meta":{{"count":{},"version":{}}}}}"#,
+            count, version
+        ))),
+    };
+
+    // each representation needs its own tag, as with msgpack.
+    let tag = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok()?.parse::<EntityTag>().ok());
+    if let Some(tag) = tag {
+        let tag = EntityTag::new_weak(format!("{}-envelope", tag.tag()));
+        if let Ok(value) = ETag(tag).try_into_value() {
+            response.headers_mut().insert(header::ETAG, value);
+        }
+    }
+    let response = response.set_body(BoxBody::new(body));
+    ServiceResponse::new(req, response)
+}
+
+// The handler's body between the envelope's opening and its meta, passed on chunk by chunk.
+struct Spliced {
+    prefix: Option<Bytes>,
+    body: BoxBody,
+    suffix: Option<Bytes>,
+}
+
+impl MessageBody for Spliced {
+    type Error = Box<dyn StdError>;
+
+    fn size(&self) -> BodySize {
+        match self.body.size() {
+            BodySize::Sized(len) => {
+                let extra = [&self.prefix, &self.suffix]
+                    .into_iter()
+                    .flatten()
+                    .map(|bytes| bytes.len() as u64)
+                    .sum::<u64>();
+                BodySize::Sized(len + extra)
+            }
+            size => size,
+        }
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(prefix) = this.prefix.take() {
+            return Poll::Ready(Some(Ok(prefix)));
+        }
+        match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Ready(None) => Poll::Ready(this.suffix.take().map(Ok)),
+            poll => poll,
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use serde::Serialize;
+
+/*
+ * TreeEvent is published by the TreeStore after every successful mutation.
+ * Subscribers receive them as small JSON documents such as {"type":"node_added","id":5}.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TreeEvent {
+    NodeAdded { id: i32 },
+    // one bulk import, ids in insertion order.
+    NodesImported { ids: Vec<i32> },
+    NodeMoved { id: i32 },
+    MetadataUpdated { id: i32 },
+    NodeTouched { id: i32 },
+    SubtreeCloned { id: i32, source_id: i32 },
+    NodesSwapped { a: i32, b: i32 },
+    TreeRestored,
+    LeavesPruned { ids: Vec<i32> },
+    // every removed node, subtrees included.
+    NodesRemoved { ids: Vec<i32> },
+    NodesRelabeled { ids: Vec<i32> },
+    // every id may have changed, clients refetch.
+    IdsCompacted,
+    // rebuild_indexes found and fixed drift.
+    IndexesRebuilt,
+    // id was removed, its children now sit under parent_id in its place.
+    NodeFlattened { id: i32, parent_id: i32 },
+    // removed were merged into id, which was relabeled and took over their children.
+    ChainCollapsed { id: i32, removed: Vec<i32> },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serializes_with_type_tag() {
+        let json = serde_json::to_string(&TreeEvent::NodeAdded { id: 5 }).unwrap();
+        assert_eq!(json, r#"{"type":"node_added","id":5}"#);
+    }
+}
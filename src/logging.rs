@@ -0,0 +1,216 @@
+use std::{
+    io::{self, Write},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::json;
+
+use crate::request_id;
+
+// How log lines are written to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    // 1700000000000 WARN interviews_services_nsherred: message, with the request id after the
+    // target as "... interviews_services_nsherred [id]: message" during a request.
+    #[default]
+    Text,
+    // {"timestamp":1700000000000,"level":"WARN","target":"...","message":"..."}, plus
+    // "request_id" during a request.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}", value)),
+        }
+    }
+}
+
+/*
+ * Logger writes every record at or above level to stderr, one line each. That covers actix's own
+ * records too, they go through the same log facade.
+ */
+pub struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+impl Logger {
+    pub fn new(level: LevelFilter, format: LogFormat) -> Self {
+        Logger { level, format }
+    }
+
+    // Installs the logger for the whole process, it can only be done once.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let level = self.level;
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let request_id = request_id::current();
+            let line = format_line(record, self.format, timestamp(), request_id.as_deref());
+            // nowhere left to report a failed write to.
+            let _ = writeln!(io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+// milliseconds since the unix epoch, like the audit log.
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn format_line(
+    record: &Record,
+    format: LogFormat,
+    timestamp: u64,
+    request_id: Option<&str>,
+) -> String {
+    match format {
+        LogFormat::Text => {
+            let request_id = request_id.map_or(String::new(), |id| format!(" [{}]", id));
+            format!(
+                "{} {} {}{}: {}",
+                timestamp,
+                record.level(),
+                record.target(),
+                request_id,
+                record.args()
+            )
+        }
+        LogFormat::Json => {
+            let mut line = json!({
+                "timestamp": timestamp,
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            if let Some(id) = request_id {
+                line["request_id"] = json!(id);
+            }
+            line.to_string()
+        }
+    }
+}
+
+// Records logged on the current thread, for tests to assert on. Tests run on their own threads
+// so they don't see each other's records, an actix test service handles requests on the test's.
+#[cfg(test)]
+pub mod capture {
+    use std::{cell::RefCell, sync::Once};
+
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    thread_local! {
+        static RECORDS: RefCell<Vec<(Level, String)>> = const { RefCell::new(vec![]) };
+    }
+
+    struct CaptureLogger;
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            RECORDS.with(|records| {
+                records
+                    .borrow_mut()
+                    .push((record.level(), record.args().to_string()))
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    // Installs the capturing logger on first use and drops the thread's earlier records.
+    pub fn start() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+        RECORDS.with(|records| records.borrow_mut().clear());
+    }
+
+    pub fn take() -> Vec<(Level, String)> {
+        RECORDS.with(|records| records.take())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::Level;
+
+    fn warning<R>(check: impl FnOnce(&Record) -> R) -> R {
+        check(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("tree")
+                .args(format_args!("node \"x\" rejected"))
+                .build(),
+        )
+    }
+
+    #[test]
+    fn formats_text_lines() {
+        let line = warning(|record| format_line(record, LogFormat::Text, 42, None));
+        assert_eq!(line, "42 WARN tree: node \"x\" rejected");
+        let line = warning(|record| format_line(record, LogFormat::Text, 42, Some("abc")));
+        assert_eq!(line, "42 WARN tree [abc]: node \"x\" rejected");
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let line = warning(|record| format_line(record, LogFormat::Json, 42, None));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "timestamp": 42,
+                "level": "WARN",
+                "target": "tree",
+                "message": "node \"x\" rejected",
+            })
+        );
+    }
+
+    #[test]
+    fn filters_below_the_level() {
+        let logger = Logger::new(LevelFilter::Warn, LogFormat::Text);
+        let metadata = |level| Metadata::builder().level(level).build();
+        assert!(logger.enabled(&metadata(Level::Error)));
+        assert!(logger.enabled(&metadata(Level::Warn)));
+        assert!(!logger.enabled(&metadata(Level::Info)));
+    }
+
+    #[test]
+    fn adds_the_request_id_to_json_lines() {
+        let line = warning(|record| format_line(record, LogFormat::Json, 42, Some("abc")));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["request_id"], "abc");
+    }
+}
@@ -1,185 +1,4741 @@
+mod audit;
+mod chaos;
+mod config;
+mod envelope;
+mod events;
+mod logging;
 mod node;
+mod openapi;
+mod rate_limit;
+mod request_id;
+mod timeout;
 mod tree;
 mod tree_store;
 
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_cors::Cors;
+use actix_web::{
+    dev::Server,
+    error::{ErrorConflict, InternalError, JsonPayloadError},
+    http::header::{
+        self, Accept, ContentEncoding, ContentType, ETag, EntityTag, IfMatch, IfNoneMatch,
+    },
+    middleware::{Compress, NormalizePath},
+    web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use actix_ws::Message;
+use chaos::ChaosDelay;
+use config::{Seed, ServerConfig, TreeConfig};
+use envelope::Envelope;
+use futures_util::StreamExt;
+use logging::Logger;
+use node::{JsonOptions, RcNodeRef};
+use rate_limit::RateLimiter;
+use request_id::RequestId;
 use serde::{Deserialize, Serialize};
-use tree_store::TreeStore;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::TcpListener,
+};
+use timeout::{Deadline, Timeout};
+use tokio::sync::broadcast::error::RecvError;
+use tree::{
+    AddNodeError, CloneError, CollapseError, Direction, EnsureRootError, FlattenError, ImportError,
+    ImportNode, LabelUpdate, MoveError, PathError, RenameError, RestoreError, SiblingEnd, Snapshot,
+    SwapError, Tree,
+};
+use tree_store::{TreeStore, TreeWrite, WriteError};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // actix will spin up a thread pool.
     // We have to ensure that the Arc is created outside of the lambda.
-    let tree_store = web::Data::new(TreeStore::default());
+    let tree_store = web::Data::new(TreeStore::with_config(TreeConfig::from_env()));
 
-    HttpServer::new(move || App::new().configure(|cfg| setup_app(cfg, tree_store.clone())))
-        .bind(("127.0.0.1", 3001))?
-        .run()
-        .await
+    let server_config = ServerConfig::from_env();
+    Logger::new(server_config.log_level, server_config.log_format)
+        .init()
+        .expect("the logger is only installed once");
+    if server_config.seed == Some(Seed::Demo) {
+        tree_store
+            .seed_demo()
+            .expect("TREE_SEED=demo needs the demo tree to fit the configured limits");
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", 3001))?;
+    build_server(tree_store, server_config, listener)?.await?;
+
+    // Every in-flight request has finished by now. The store is in memory only so there is
+    // nothing to flush, persistence would be written out here.
+    log::info!("server stopped");
+    Ok(())
+}
+
+// How long in-flight requests get to finish once a shutdown starts.
+const SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+// On SIGTERM/SIGINT (or ServerHandle::stop(true)) actix stops accepting new connections and
+// waits up to SHUTDOWN_TIMEOUT_SECS for in-flight requests before the returned future resolves.
+fn build_server(
+    tree_store: web::Data<TreeStore>,
+    server_config: ServerConfig,
+    listener: TcpListener,
+) -> std::io::Result<Server> {
+    // Shared by every worker for the same reason as the store.
+    let rate_limiter = web::Data::new(RateLimiter::new(server_config.rate_limit));
+    let workers = server_config.workers;
+    let keep_alive = server_config.keep_alive;
+    let max_connections = server_config.max_connections;
+    let mut server = HttpServer::new(move || {
+        App::new().configure(|cfg| {
+            setup_app(
+                cfg,
+                tree_store.clone(),
+                rate_limiter.clone(),
+                &server_config,
+            )
+        })
+    })
+    .keep_alive(keep_alive)
+    .max_connections(max_connections);
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    Ok(server
+        .listen(listener)?
+        .shutdown_timeout(SHUTDOWN_TIMEOUT_SECS)
+        .run())
+}
+
+fn setup_app(
+    cfg: &mut web::ServiceConfig,
+    tree_store: web::Data<TreeStore>,
+    rate_limiter: web::Data<RateLimiter>,
+    server_config: &ServerConfig,
+) {
+    cfg.app_data(tree_store)
+        .app_data(rate_limiter)
+        // Bodies past the limit are rejected with a 413 before they are buffered or parsed.
+        // JsonConfig covers web::Json, PayloadConfig covers handlers reading the raw web::Bytes.
+        .app_data(
+            web::JsonConfig::default()
+                .limit(server_config.max_payload_bytes)
+                .error_handler(json_error_handler),
+        )
+        .app_data(web::PayloadConfig::new(server_config.max_payload_bytes))
+        .route("/api/openapi.json", web::get().to(openapi_document))
+        // unmatched paths get a JSON 404 like the api's own errors, instead of actix's empty one.
+        .default_service(web::to(not_found))
+        .service(
+            web::scope("/api/tree")
+                // TREE_CHAOS_DELAY_MS of added latency, inside the timeout so it can trip it.
+                .wrap(ChaosDelay::new(server_config.chaos_delay))
+                // a 503 past TREE_REQUEST_TIMEOUT_MS, inner so only the handler is timed.
+                .wrap(Timeout::new(server_config.request_timeout))
+                // ?envelope=true, inside Compress so it sees the plain body.
+                .wrap(Envelope)
+                // gzip/deflate/brotli/zstd, picked from the request's Accept-Encoding.
+                .wrap(Compress::default())
+                // "/api/tree/" and "/api/tree/1/children/" route like their unslashed forms.
+                .wrap(NormalizePath::trim())
+                // outside everything but RequestId, so preflights are answered before routing.
+                .wrap(cors(&server_config.cors_origins))
+                // X-Request-Id in and out, outermost so every line logged for the request has it.
+                .wrap(RequestId)
+                .route("", web::get().to(get_tree))
+                .route("", web::post().to(add_node))
+                .route("", web::delete().to(remove_nodes))
+                .route("/subscribe", web::get().to(subscribe))
+                .route("/events", web::get().to(event_stream))
+                .route("/swap", web::post().to(swap_nodes))
+                .route("/root", web::post().to(ensure_root))
+                .route("/lca", web::get().to(lowest_common_ancestor))
+                .route("/distance", web::get().to(distance))
+                .route("/can-move", web::get().to(can_move))
+                .route("/roots", web::get().to(get_roots))
+                .route("/by-path", web::get().to(get_by_path))
+                .route("/subtrees", web::get().to(get_subtrees))
+                .route("/snapshot", web::get().to(get_snapshot))
+                .route("/restore", web::post().to(restore_snapshot))
+                .route("/import", web::post().to(import_nodes))
+                .route("/diff", web::post().to(diff_snapshots))
+                .route("/verify", web::get().to(verify))
+                .route("/ids", web::get().to(get_ids))
+                .route("/duplicates", web::get().to(get_duplicates))
+                .route("/nodes", web::get().to(get_flat_nodes))
+                .route("/metrics", web::get().to(get_metrics))
+                .route("/autocomplete", web::get().to(autocomplete))
+                .route("/search", web::get().to(search_by_label))
+                .route("/audit", web::get().to(get_audit))
+                .route("/changes", web::get().to(get_changes))
+                .route("/export/dot", web::get().to(export_dot))
+                .route("/export/markdown", web::get().to(export_markdown))
+                .route("/export/ndjson", web::get().to(export_ndjson))
+                .route("/prune-leaves", web::post().to(prune_leaves))
+                .route("/compact", web::post().to(compact_ids))
+                .route("/reindex", web::post().to(reindex))
+                .route("/labels", web::patch().to(rename_nodes))
+                .route("/{id}", web::head().to(node_exists))
+                .route("/{id}/position", web::put().to(move_node_to_position))
+                .route("/{id}/reorder", web::post().to(reorder_node))
+                .route("/{id}/nudge", web::post().to(nudge_node))
+                .route("/{id}/metadata", web::patch().to(set_metadata))
+                .route("/{id}/label", web::patch().to(rename_node))
+                .route("/{id}/clone", web::post().to(clone_subtree))
+                .route("/{id}/collapse-chain", web::post().to(collapse_chain))
+                .route("/{id}/touch", web::post().to(touch_node))
+                .route("/{id}/flatten", web::post().to(flatten_node))
+                .route("/{id}/root", web::get().to(root_of))
+                .route("/{id}/children", web::get().to(children_of))
+                .route("/{id}/siblings", web::get().to(siblings_of))
+                .route("/{id}/size", web::get().to(subtree_size))
+                .route("/{id}/locate", web::get().to(locate_node))
+                .route("/{id}/leaf-paths", web::get().to(leaf_paths))
+                .route("/{a}/is-ancestor-of/{b}", web::get().to(is_ancestor)),
+        );
+}
+
+// Response headers a browser script may read, the conditional write and rate limit flows use them
+// and x-request-id correlates a response with the server logs.
+const CORS_EXPOSED_HEADERS: [&str; 3] = ["etag", "retry-after", "x-request-id"];
+
+// Requests from origins that aren't listed are served without the Access-Control-* headers, the
+// browser then refuses to hand the response to the page. "*" allows any origin.
+fn cors(origins: &[String]) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"])
+        .allow_any_header()
+        .expose_headers(CORS_EXPOSED_HEADERS)
+        .max_age(3600);
+    for origin in origins {
+        cors = match origin.as_str() {
+            "*" => cors.allow_any_origin(),
+            origin => cors.allowed_origin(origin),
+        };
+    }
+    cors
+}
+
+async fn not_found(req: HttpRequest) -> impl Responder {
+    HttpResponse::NotFound().json(json!({ "error": "not found", "path": req.path() }))
+}
+
+async fn openapi_document() -> impl Responder {
+    HttpResponse::Ok().json(openapi::document())
+}
+
+const CONTENT_TYPE_ERROR: &str = "Content-Type must be application/json";
+
+// application/json, or a structured +json type such as application/merge-patch+json.
+fn is_json(req: &HttpRequest) -> bool {
+    matches!(req.mime_type(), Ok(Some(mime))
+        if mime.essence_str() == "application/json"
+            || mime.suffix().is_some_and(|suffix| suffix.as_str() == "json"))
+}
+
+// web::Json answers a wrong content type with a generic 400, make it a 415 like POST /api/tree.
+fn json_error_handler(error: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    match error {
+        JsonPayloadError::ContentType => InternalError::from_response(
+            error,
+            HttpResponse::UnsupportedMediaType().body(CONTENT_TYPE_ERROR),
+        )
+        .into(),
+        error => error.into(),
+    }
+}
+
+fn tree_response(tree: &[RcNodeRef]) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(node::to_json(tree))
+}
+
+// Takes the write lock for a mutating handler. An If-Match header carrying an ETag from GET
+// /api/tree makes the write conditional on the tree still being at that version, 412 otherwise.
+fn write_tree(
+    tree_store: &TreeStore,
+    if_match: Option<web::Header<IfMatch>>,
+) -> Result<TreeWrite<'_>, HttpResponse> {
+    let if_version = match if_match {
+        None | Some(web::Header(IfMatch::Any)) => None,
+        // actix parses a missing header as an empty list.
+        Some(web::Header(IfMatch::Items(tags))) if tags.is_empty() => None,
+        Some(web::Header(IfMatch::Items(tags))) => {
+            // every representation's tag starts with the version, e.g. W/"4" or W/"4-msgpack".
+            // The tags are weak because gzip and identity bodies share them, the version is all
+            // that is compared.
+            let version = tags
+                .iter()
+                .find_map(|tag| tag.tag().split('-').next()?.parse().ok());
+            match version {
+                Some(version) => Some(version),
+                None => {
+                    return Err(HttpResponse::PreconditionFailed()
+                        .body("If-Match does not name a tree version"))
+                }
+            }
+        }
+    };
+
+    tree_store.write(if_version).map_err(|error| match error {
+        WriteError::VersionMismatch { current, .. } => HttpResponse::PreconditionFailed()
+            .insert_header(ETag(EntityTag::new_weak(current.to_string())))
+            .body(error.to_string()),
+        WriteError::LockPoisoned => HttpResponse::InternalServerError().body(error.to_string()),
+    })
+}
+
+#[derive(Deserialize)]
+struct GetTreeQuery {
+    // comma separated node fields to include, e.g. "id,children".
+    fields: Option<String>,
+    #[serde(default)]
+    pretty: bool,
+    // JSON only, sent in chunks as it is written instead of as one buffered body.
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    format: TreeFormat,
+    // sort=key orders siblings by their sort_key.
+    sort: Option<SortOrder>,
+    // empty=404 answers an empty tree with a 404 instead of 200 [].
+    #[serde(default)]
+    empty: EmptyTree,
+    // JSON only, leaves are written without "children":[].
+    #[serde(default)]
+    omit_empty_children: bool,
+}
+
+#[derive(Default, Deserialize, PartialEq)]
+enum EmptyTree {
+    #[default]
+    #[serde(rename = "200")]
+    Ok,
+    #[serde(rename = "404")]
+    NotFound,
+}
+
+fn empty_tree_response() -> HttpResponse {
+    HttpResponse::NotFound().json(json!({ "error": "tree is empty" }))
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    Key,
+}
+
+#[derive(Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TreeFormat {
+    // the nested forest, as JSON or MessagePack.
+    #[default]
+    Nested,
+    // {"nodes": [...], "edges": [[parent, child], ...]} for graph libraries, always JSON.
+    Adjacency,
+}
+
+const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+// True when the client's most preferred media type is MessagePack, JSON stays the default.
+fn prefers_msgpack(accept: Option<web::Header<Accept>>) -> bool {
+    accept.is_some_and(|web::Header(accept)| {
+        accept.ranked().first().is_some_and(|mime| {
+            ["application/msgpack", "application/x-msgpack"].contains(&mime.essence_str())
+        })
+    })
+}
+
+fn is_not_modified(if_none_match: Option<web::Header<IfNoneMatch>>, etag: &EntityTag) -> bool {
+    match if_none_match {
+        Some(web::Header(IfNoneMatch::Any)) => true,
+        Some(web::Header(IfNoneMatch::Items(items))) => items.iter().any(|item| item.weak_eq(etag)),
+        None => false,
+    }
+}
+
+async fn get_tree(
+    query: web::Query<GetTreeQuery>,
+    accept: Option<web::Header<Accept>>,
+    if_none_match: Option<web::Header<IfNoneMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let msgpack = prefers_msgpack(accept);
+    let mut options = JsonOptions {
+        pretty: query.pretty,
+        sort_by_key: query.sort == Some(SortOrder::Key),
+        omit_empty_children: query.omit_empty_children,
+        ..JsonOptions::default()
+    };
+    if let Some(fields) = &query.fields {
+        match fields.parse() {
+            Ok(fields) => options.fields = fields,
+            Err(error) => return HttpResponse::BadRequest().body(error),
+        }
+    }
+
+    if query.format == TreeFormat::Adjacency {
+        let (version, adjacency) = match tree_store.get_versioned_adjacency() {
+            Ok(result) => result,
+            Err(error) => return HttpResponse::InternalServerError().body(error.to_string()),
+        };
+        if query.empty == EmptyTree::NotFound && adjacency.nodes.is_empty() {
+            return empty_tree_response();
+        }
+        let etag = EntityTag::new_weak(format!("{}-adjacency", version));
+        if is_not_modified(if_none_match, &etag) {
+            return HttpResponse::NotModified()
+                .insert_header(ETag(etag))
+                .finish();
+        }
+        return HttpResponse::Ok().insert_header(ETag(etag)).json(adjacency);
+    }
+
+    let (version, tree) = match tree_store.get_versioned_tree() {
+        Ok(result) => result,
+        Err(error) => return HttpResponse::InternalServerError().body(error.to_string()),
+    };
+    if query.empty == EmptyTree::NotFound && tree.is_empty() {
+        return empty_tree_response();
+    }
+
+    // each representation needs its own tag. They are weak, Compress encodes the body after the
+    // tag is set so the gzip and identity bytes differ under the same tag.
+    let etag = if msgpack {
+        EntityTag::new_weak(format!("{}-msgpack", version))
+    } else {
+        EntityTag::new_weak(version.to_string())
+    };
+    if is_not_modified(if_none_match, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .append_header((header::VARY, "accept"))
+            .finish();
+    }
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(ETag(etag))
+        .append_header((header::VARY, "accept"));
+    if msgpack {
+        response
+            .content_type(MSGPACK_CONTENT_TYPE)
+            .body(node::to_msgpack_with(
+                &tree,
+                options.fields,
+                options.sort_by_key,
+            ))
+    } else if query.stream {
+        // each chunk is written under the read lock at the version the ETag names, a write
+        // landing mid-stream cuts the response off rather than mixing two versions.
+        let tree_store = tree_store.clone();
+        let mut chunks = node::to_json_chunks(tree, options);
+        let chunks =
+            std::iter::from_fn(
+                move || match tree_store.at_version(version, || chunks.next()) {
+                    Some(chunk) => chunk.map(|chunk| Ok(web::Bytes::from(chunk))),
+                    None => Some(Err(ErrorConflict(
+                        "Tree changed while it was being streamed",
+                    ))),
+                },
+            );
+        response
+            .content_type(ContentType::json())
+            .streaming(futures_util::stream::iter(chunks))
+    } else {
+        response
+            .content_type(ContentType::json())
+            .body(node::to_json_with(&tree, &options))
+    }
+}
+
+// Each root's subtree as its own document: [{"root_id":1,"tree":{...}}, ...] in id order.
+async fn get_roots(tree_store: web::Data<TreeStore>) -> impl Responder {
+    let roots = match tree_store.get_tree() {
+        Ok(roots) => roots,
+        Err(error) => return HttpResponse::InternalServerError().body(error.to_string()),
+    };
+
+    let documents: Vec<String> = roots
+        .iter()
+        .map(|root| {
+            let root_id = root.lock().unwrap().id;
+            format!(
+                "{{\"root_id\":{},\"tree\":{}}}",
+                root_id,
+                node::node_to_json(root)
+            )
+        })
+        .collect();
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(format!("[{}]", documents.join(",")))
+}
+
+// {"consistent":true}, or {"consistent":false,"discrepancies":[...]} describing each mismatch.
+async fn verify(tree_store: web::Data<TreeStore>) -> impl Responder {
+    match tree_store.verify() {
+        Ok(Ok(())) => HttpResponse::Ok().json(json!({ "consistent": true })),
+        Ok(Err(discrepancies)) => HttpResponse::Ok().json(json!({
+            "consistent": false,
+            "discrepancies": discrepancies,
+        })),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Every node id as a sorted flat array, no structure.
+async fn get_ids(tree_store: web::Data<TreeStore>) -> impl Responder {
+    match tree_store.ids() {
+        Ok(ids) => HttpResponse::Ok().json(ids),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct DuplicatesQuery {
+    #[serde(default)]
+    ci: bool,
+}
+
+// Ids of nodes sharing a label as [[2,4],[3,5]], ?ci=true ignores case.
+async fn get_duplicates(
+    query: web::Query<DuplicatesQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match tree_store.duplicate_labels(query.ci) {
+        Ok(groups) => HttpResponse::Ok().json(groups),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Every node as {"id","label","parent_id"} sorted by id, for table views.
+async fn get_flat_nodes(tree_store: web::Data<TreeStore>) -> impl Responder {
+    match tree_store.flat_nodes() {
+        Ok(nodes) => HttpResponse::Ok().json(nodes),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Shape of the forest for layout: {"height":3,"max_width":3,"width_per_level":[1,2,3]}.
+async fn get_metrics(tree_store: web::Data<TreeStore>) -> impl Responder {
+    match tree_store.level_widths() {
+        Ok(widths) => HttpResponse::Ok().json(json!({
+            "height": widths.len(),
+            "max_width": widths.iter().max().unwrap_or(&0),
+            "width_per_level": widths,
+        })),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    #[serde(default = "default_audit_limit")]
+    limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+// Recent mutations, oldest first: [{"timestamp":..,"operation":"add","node_id":1,"detail":""}].
+async fn get_audit(
+    query: web::Query<AuditQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    HttpResponse::Ok().json(tree_store.audit(query.limit))
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: u64,
+}
+
+// {"version":14,"resync":false,"changes":[audit entries after since]} for clients catching up.
+// resync is true, with no changes, once the audit log has dropped entries the client needs, it
+// has to refetch the whole tree instead.
+async fn get_changes(
+    query: web::Query<ChangesQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let (version, changes) = tree_store.changes_since(query.since);
+    HttpResponse::Ok().json(json!({
+        "version": version,
+        "resync": changes.is_none(),
+        "changes": changes.unwrap_or_default(),
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DotColorBy {
+    Depth,
+}
+
+#[derive(Deserialize)]
+struct ExportDotQuery {
+    color_by: Option<DotColorBy>,
+}
+
+// The forest as graphviz source, `dot -Tsvg` renders it.
+async fn export_dot(
+    query: web::Query<ExportDotQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let color_by_depth = matches!(query.color_by, Some(DotColorBy::Depth));
+    match tree_store.to_dot(color_by_depth) {
+        Ok(dot) => HttpResponse::Ok()
+            .content_type("text/vnd.graphviz; charset=utf-8")
+            .body(dot),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportMarkdownQuery {
+    // backslash escape Markdown special characters in labels.
+    #[serde(default)]
+    escape: bool,
+}
+
+// The forest as a nested bullet list for documentation.
+async fn export_markdown(
+    query: web::Query<ExportMarkdownQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match tree_store.to_markdown(query.escape) {
+        Ok(markdown) => HttpResponse::Ok()
+            .content_type("text/markdown; charset=utf-8")
+            .body(markdown),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Every node as a flat {"id","label","parent_id"} object, one per line, for data pipelines.
+async fn export_ndjson(tree_store: web::Data<TreeStore>) -> impl Responder {
+    match tree_store.to_ndjson() {
+        Ok(ndjson) => HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .body(ndjson),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
 }
 
-fn setup_app(cfg: &mut web::ServiceConfig, tree_store: web::Data<TreeStore>) {
-    cfg.app_data(tree_store).service(
-        web::scope("/api/tree")
-            .route("", web::get().to(get_tree))
-            .route("", web::post().to(add_node)),
-    );
-}
+#[derive(Deserialize)]
+struct AutocompleteQuery {
+    prefix: String,
+    #[serde(default = "default_autocomplete_limit")]
+    limit: usize,
+}
+
+fn default_autocomplete_limit() -> usize {
+    10
+}
+
+// Nodes whose label starts with prefix, ignoring case: [{"id":2,"label":"Robin"}, ...].
+async fn autocomplete(
+    query: web::Query<AutocompleteQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match tree_store.labels_with_prefix(&query.prefix, query.limit) {
+        Ok(matches) => HttpResponse::Ok().json(
+            matches
+                .into_iter()
+                .map(|(id, label)| json!({ "id": id, "label": label }))
+                .collect::<Vec<_>>(),
+        ),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    label: String,
+}
+
+// Sorted ids of the nodes labelled exactly ?label=, case sensitive, from the label index.
+async fn search_by_label(
+    query: web::Query<SearchQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match tree_store.find_by_label(&query.label) {
+        Ok(ids) => HttpResponse::Ok().json(ids),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+async fn node_exists(path: web::Path<i32>, tree_store: web::Data<TreeStore>) -> impl Responder {
+    match tree_store.contains(path.into_inner()) {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct AddNodeRequest {
+    // missing falls back to the configured default_label.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    parent_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sort_key: Option<i64>,
+}
+
+impl AddNodeRequest {
+    // Checks the raw body field by field so the error names the offending field,
+    // web::Json only reports a generic deserialization failure. Typos like "labl" are left to
+    // deny_unknown_fields, serde names the field and lists the ones it expected.
+    fn parse(body: &[u8]) -> Result<Self, String> {
+        let value: Value =
+            serde_json::from_slice(body).map_err(|_| "body must be valid JSON".to_string())?;
+        let object = value.as_object().ok_or("body must be a JSON object")?;
+
+        match object.get("label") {
+            None => {}
+            Some(Value::Null) => return Err("label is required".to_string()),
+            Some(Value::String(_)) => {}
+            Some(_) => return Err("label must be a string".to_string()),
+        }
+        match object.get("parent_id") {
+            None | Some(Value::Null) => {}
+            Some(parent_id)
+                if parent_id
+                    .as_i64()
+                    .and_then(|id| i32::try_from(id).ok())
+                    .is_some() => {}
+            Some(_) => return Err("parent_id must be an integer".to_string()),
+        }
+        match object.get("metadata") {
+            None => {}
+            Some(Value::Object(entries)) if entries.values().all(Value::is_string) => {}
+            Some(_) => return Err("metadata must be an object of strings".to_string()),
+        }
+        match object.get("sort_key") {
+            None | Some(Value::Null) => {}
+            Some(sort_key) if sort_key.is_i64() => {}
+            Some(_) => return Err("sort_key must be an integer".to_string()),
+        }
+
+        serde_json::from_value(value).map_err(|error| error.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct AddNodeQuery {
+    // validate only, reports the would-be id without inserting.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+async fn add_node(
+    req: HttpRequest,
+    query: web::Query<AddNodeQuery>,
+    body: web::Bytes,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+    rate_limiter: web::Data<RateLimiter>,
+) -> impl Responder {
+    let client = req.peer_addr().map(|address| address.ip());
+    if let Err(retry_after) = rate_limiter.check(client) {
+        // Retry-After only takes whole seconds.
+        let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        return HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, seconds.to_string()))
+            .json(json!({ "error": "rate limit exceeded" }));
+    }
+
+    if !is_json(&req) {
+        return HttpResponse::UnsupportedMediaType().json(json!({ "error": CONTENT_TYPE_ERROR }));
+    }
+
+    let payload = match AddNodeRequest::parse(&body) {
+        Ok(payload) => payload,
+        Err(error) => return HttpResponse::BadRequest().json(json!({ "error": error })),
+    };
+    let label = match payload.label {
+        Some(label) => label,
+        None => match tree_store.default_label() {
+            Ok(Some(label)) => label,
+            Ok(None) => {
+                return HttpResponse::BadRequest().json(json!({ "error": "label is required" }))
+            }
+            Err(error) => {
+                return HttpResponse::InternalServerError()
+                    .json(json!({ "error": error.to_string() }))
+            }
+        },
+    };
+
+    if query.dry_run {
+        return match tree_store.validate_add(&label, payload.parent_id) {
+            Ok((id, label)) => HttpResponse::Ok().json(json!({
+                "dry_run": true,
+                "id": id,
+                "label": label,
+            })),
+            Err(error) => add_node_error_response(error),
+        };
+    }
+
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => {
+            tree.add_sorted_node(label, payload.parent_id, payload.metadata, payload.sort_key)
+        }
+        Err(response) => return response,
+    };
+    if let Err(result) = result {
+        log::warn!("error adding node: {:?}", result);
+        return add_node_error_response(result);
+    }
+
+    match tree_store.get_tree() {
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+        Ok(result) => tree_response(&result),
+    }
+}
+
+// Malformed or over-limit requests are 400s, a child that already has a parent is a conflict
+// with the tree's state rather than a bad request. The body is {"error": ...} like the
+// validation errors POST /api/tree answers with.
+fn add_node_error_response(error: AddNodeError) -> HttpResponse {
+    let mut response = match error {
+        AddNodeError::ParentNotFound(_) => HttpResponse::NotFound(),
+        AddNodeError::AlreadyHasParent(_) => HttpResponse::Conflict(),
+        AddNodeError::LockPoisoned => HttpResponse::InternalServerError(),
+        _ => HttpResponse::BadRequest(),
+    };
+    response.json(json!({ "error": error.to_string() }))
+}
+
+fn move_error_response(error: MoveError) -> HttpResponse {
+    match error {
+        MoveError::NodeNotFound(_) => HttpResponse::NotFound().body(error.to_string()),
+        MoveError::LockPoisoned => HttpResponse::InternalServerError().body(error.to_string()),
+        _ => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct MoveToPositionRequest {
+    parent_id: i32,
+    // slot among the new siblings, the end when neither this nor after_sibling_id is given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+    // present places the node right after that sibling, null places it first.
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    after_sibling_id: Option<Option<i32>>,
+}
+
+// The two branches a move changed, so clients can refresh just those. old_parent_id is null
+// when the node was a root.
+#[derive(Serialize)]
+struct MoveResponse {
+    moved_id: i32,
+    old_parent_id: Option<i32>,
+    new_parent_id: i32,
+}
+
+// Tells an explicit null (Some(None)) apart from a missing field (None, via default).
+fn present<'de, D, V>(deserializer: D) -> Result<Option<Option<V>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+async fn move_node_to_position(
+    path: web::Path<i32>,
+    payload: web::Json<MoveToPositionRequest>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let payload = payload.into_inner();
+    let id = path.into_inner();
+
+    let result = match (payload.index, payload.after_sibling_id) {
+        (Some(_), Some(_)) => {
+            return HttpResponse::BadRequest().body("Give either index or after_sibling_id")
+        }
+        (index, None) => match write_tree(&tree_store, if_match) {
+            Ok(mut tree) => tree.move_node(id, payload.parent_id, index.unwrap_or(usize::MAX)),
+            Err(response) => return response,
+        },
+        (None, Some(after_sibling_id)) => match write_tree(&tree_store, if_match) {
+            Ok(mut tree) => tree.move_after(id, payload.parent_id, after_sibling_id),
+            Err(response) => return response,
+        },
+    };
+    match result {
+        Ok(old_parent_id) => HttpResponse::Ok().json(MoveResponse {
+            moved_id: id,
+            old_parent_id,
+            new_parent_id: payload.parent_id,
+        }),
+        Err(error) => move_error_response(error),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct ReorderRequest {
+    to: SiblingEnd,
+}
+
+// Shortcut for moving a node to the first or last slot under its current parent.
+async fn reorder_node(
+    path: web::Path<i32>,
+    payload: web::Json<ReorderRequest>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.reorder(path.into_inner(), payload.to),
+        Err(response) => return response,
+    };
+    if let Err(error) = result {
+        return move_error_response(error);
+    }
+
+    match tree_store.get_tree() {
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+        Ok(result) => tree_response(&result),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct NudgeRequest {
+    direction: Direction,
+}
+
+// Swaps a node with its previous (up) or next (down) sibling, for up/down arrows. Already being
+// first or last isn't an error, the tree comes back unchanged.
+async fn nudge_node(
+    path: web::Path<i32>,
+    payload: web::Json<NudgeRequest>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.nudge(path.into_inner(), payload.direction),
+        Err(response) => return response,
+    };
+    if let Err(error) = result {
+        return move_error_response(error);
+    }
+
+    match tree_store.get_tree() {
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+        Ok(result) => tree_response(&result),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct SetMetadataRequest {
+    key: String,
+    value: String,
+}
+
+async fn set_metadata(
+    path: web::Path<i32>,
+    payload: web::Json<SetMetadataRequest>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let payload = payload.into_inner();
+
+    let node = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.set_metadata(id, payload.key, payload.value),
+        Err(response) => return response,
+    };
+    match node {
+        Some(node) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(node::node_to_json(&node)),
+        None => HttpResponse::NotFound().body(format!("Node {} does not exist", id)),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct RenameNodesRequest {
+    updates: Vec<LabelUpdate>,
+}
+
+// Relabels every listed node under one write lock, or none of them if any update is rejected.
+async fn rename_nodes(
+    payload: web::Json<RenameNodesRequest>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.rename_many(payload.into_inner().updates),
+        Err(response) => return response,
+    };
+    match result {
+        Ok(()) => match tree_store.get_tree() {
+            Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+            Ok(result) => tree_response(&result),
+        },
+        Err(error @ RenameError::NodeNotFound(_)) => {
+            HttpResponse::NotFound().body(error.to_string())
+        }
+        Err(error @ RenameError::LockPoisoned) => {
+            HttpResponse::InternalServerError().body(error.to_string())
+        }
+        Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct RenameNodeRequest {
+    label: String,
+}
+
+// Without If-Match the rename runs under the read lock and only waits on the node itself, a
+// conditional one needs the version to hold still and goes through the write lock.
+async fn rename_node(
+    path: web::Path<i32>,
+    payload: web::Json<RenameNodeRequest>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let label = payload.into_inner().label;
+
+    let result = match if_match {
+        None => tree_store.rename_node(id, &label),
+        Some(if_match) => match write_tree(&tree_store, Some(if_match)) {
+            Ok(mut tree) => tree.rename_many(vec![LabelUpdate { id, label }]),
+            Err(response) => return response,
+        },
+    };
+    match result {
+        Ok(()) => match tree_store.get_nodes(&[id]) {
+            Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+            Ok(nodes) => match &nodes[0] {
+                Some(node) => HttpResponse::Ok()
+                    .content_type(ContentType::json())
+                    .body(node::node_to_json(node)),
+                None => HttpResponse::NotFound().body(format!("Node {} does not exist", id)),
+            },
+        },
+        Err(error @ RenameError::NodeNotFound(_)) => {
+            HttpResponse::NotFound().body(error.to_string())
+        }
+        Err(error @ RenameError::LockPoisoned) => {
+            HttpResponse::InternalServerError().body(error.to_string())
+        }
+        Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct CloneSubtreeRequest {
+    parent_id: Option<i32>,
+}
+
+async fn clone_subtree(
+    path: web::Path<i32>,
+    payload: web::Json<CloneSubtreeRequest>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.clone_subtree(path.into_inner(), payload.parent_id),
+        Err(response) => return response,
+    };
+    match result {
+        Ok(clone) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(node::node_to_json(&clone)),
+        Err(error @ CloneError::NodeNotFound(_)) => {
+            HttpResponse::NotFound().body(error.to_string())
+        }
+        Err(error @ CloneError::LockPoisoned) => {
+            HttpResponse::InternalServerError().body(error.to_string())
+        }
+        Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+// Removes the node, its children take its place under its parent. Answers with the tree.
+async fn flatten_node(
+    path: web::Path<i32>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.flatten_node(path.into_inner()),
+        Err(response) => return response,
+    };
+    match result {
+        Ok(_) => match tree_store.get_tree() {
+            Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+            Ok(result) => tree_response(&result),
+        },
+        Err(error @ FlattenError::NodeNotFound(_)) => {
+            HttpResponse::NotFound().body(error.to_string())
+        }
+        Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+// Bumps the node's updated_at and the tree version, answering {"id":3,"updated_at":...}.
+async fn touch_node(
+    path: web::Path<i32>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.touch(id),
+        Err(response) => return response,
+    };
+    match result {
+        Ok(updated_at) => HttpResponse::Ok().json(json!({ "id": id, "updated_at": updated_at })),
+        Err(error) => HttpResponse::NotFound().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct CollapseChainQuery {
+    #[serde(default = "default_chain_separator")]
+    separator: String,
+}
+
+fn default_chain_separator() -> String {
+    "/".to_string()
+}
+
+// Merges the run of single-child nodes below the node into it, answering with the node.
+async fn collapse_chain(
+    path: web::Path<i32>,
+    query: web::Query<CollapseChainQuery>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.collapse_chain(path.into_inner(), &query.separator),
+        Err(response) => return response,
+    };
+    match result {
+        Ok(node) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(node::node_to_json(&node)),
+        Err(error @ CollapseError::NodeNotFound(_)) => {
+            HttpResponse::NotFound().body(error.to_string())
+        }
+        Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct SwapNodesRequest {
+    a: i32,
+    b: i32,
+}
+
+async fn swap_nodes(
+    payload: web::Json<SwapNodesRequest>,
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.swap_nodes(payload.a, payload.b),
+        Err(response) => return response,
+    };
+    if let Err(error) = result {
+        return match error {
+            SwapError::NodeNotFound(_) => HttpResponse::NotFound().body(error.to_string()),
+            SwapError::IsAncestor { .. }
+            | SwapError::LabelTaken { .. }
+            | SwapError::TooDeep { .. } => HttpResponse::BadRequest().body(error.to_string()),
+            SwapError::LockPoisoned => HttpResponse::InternalServerError().body(error.to_string()),
+        };
+    }
+
+    match tree_store.get_tree() {
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+        Ok(result) => tree_response(&result),
+    }
+}
+
+// {"version":3,"next_id":4,"nodes":[{"id":1,"label":"root","parent_id":null},...]}
+// version is the tree version the snapshot was taken at, it's informational on restore.
+#[derive(Deserialize, Serialize)]
+struct SnapshotDocument {
+    #[serde(default)]
+    version: u64,
+    #[serde(flatten)]
+    snapshot: Snapshot,
+}
+
+async fn get_snapshot(tree_store: web::Data<TreeStore>) -> impl Responder {
+    match tree_store.snapshot() {
+        Ok((version, snapshot)) => HttpResponse::Ok().json(SnapshotDocument { version, snapshot }),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+async fn restore_snapshot(
+    payload: web::Json<SnapshotDocument>,
+    if_match: Option<web::Header<IfMatch>>,
+    deadline: Option<web::ReqData<Deadline>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.restore(payload.into_inner().snapshot, Deadline::of(deadline)),
+        Err(response) => return response,
+    };
+    if let Err(error) = result {
+        return match error {
+            RestoreError::LockPoisoned => {
+                HttpResponse::InternalServerError().body(error.to_string())
+            }
+            RestoreError::TimedOut => HttpResponse::ServiceUnavailable().body(error.to_string()),
+            _ => HttpResponse::BadRequest().body(error.to_string()),
+        };
+    }
+
+    match tree_store.get_tree() {
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+        Ok(result) => tree_response(&result),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct ImportRequest {
+    nodes: Vec<ImportNode>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ImportMode {
+    #[default]
+    AllOrNothing,
+    BestEffort,
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    #[serde(default)]
+    mode: ImportMode,
+}
+
+// Adds a batch of nodes to the tree, all or nothing. Entries refer to each other by client key,
+// {"id_map":{"clientTmp1":7}} gives the id each keyed node got. With ?mode=best_effort every
+// entry that can be added is and the response is a 207 listing the inserted ids and the
+// failed entries by index.
+async fn import_nodes(
+    payload: web::Json<ImportRequest>,
+    query: web::Query<ImportQuery>,
+    if_match: Option<web::Header<IfMatch>>,
+    deadline: Option<web::ReqData<Deadline>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let mut tree = match write_tree(&tree_store, if_match) {
+        Ok(tree) => tree,
+        Err(response) => return response,
+    };
+    let nodes = payload.into_inner().nodes;
+    let deadline = Deadline::of(deadline);
+    match query.mode {
+        ImportMode::AllOrNothing => match tree.import(nodes, deadline) {
+            // sorted so the mapping reads the same every time.
+            Ok(imported) => {
+                HttpResponse::Ok().json(json!({ "id_map": BTreeMap::from_iter(imported.id_map) }))
+            }
+            Err(error @ ImportError::TimedOut(_)) => {
+                HttpResponse::ServiceUnavailable().body(error.to_string())
+            }
+            Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+        },
+        ImportMode::BestEffort => {
+            let (imported, failed) = tree.import_best_effort(nodes, deadline);
+            let failed: Vec<Value> = failed
+                .iter()
+                .map(|error| json!({ "index": error.index(), "error": error.to_string() }))
+                .collect();
+            HttpResponse::MultiStatus().json(json!({
+                "inserted": imported.ids,
+                "failed": failed,
+                "id_map": BTreeMap::from_iter(imported.id_map),
+            }))
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct DiffRequest {
+    before: SnapshotDocument,
+    after: SnapshotDocument,
+}
+
+// Compares two snapshots from GET /snapshot, the live tree isn't touched. Both go through
+// Tree::restore, so a snapshot restore would reject (an id at i32::MAX included) is a 400 here.
+async fn diff_snapshots(payload: web::Json<DiffRequest>) -> impl Responder {
+    let payload = payload.into_inner();
+    let mut trees = vec![];
+    for document in [payload.before, payload.after] {
+        let mut tree = Tree::default();
+        if let Err(error) = tree.restore(document.snapshot, None) {
+            return HttpResponse::BadRequest().body(error.to_string());
+        }
+        trees.push(tree);
+    }
+
+    HttpResponse::Ok().json(Tree::diff(&trees[0], &trees[1]))
+}
+
+#[derive(Deserialize, Serialize)]
+struct RemoveNodesRequest {
+    ids: Vec<i32>,
+}
+
+// Removes every listed node with its subtree under one write lock, {"removed":[ids]} lists all
+// of them. Ids that are already gone are skipped.
+async fn remove_nodes(
+    payload: web::Json<RemoveNodesRequest>,
+    if_match: Option<web::Header<IfMatch>>,
+    deadline: Option<web::ReqData<Deadline>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => tree.remove_many(&payload.ids, Deadline::of(deadline)),
+        Err(response) => return response,
+    };
+    match result {
+        Ok(removed) => HttpResponse::Ok().json(json!({ "removed": removed })),
+        Err(error) => HttpResponse::ServiceUnavailable().body(error.to_string()),
+    }
+}
+
+// Removes the current leaves (one level only) and returns {"removed":[ids]}.
+async fn prune_leaves(
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => HttpResponse::Ok().json(json!({ "removed": tree.prune_leaves() })),
+        Err(response) => response,
+    }
+}
+
+// Renumbers the ids to 1..=n, the body maps every old id to its new one.
+async fn compact_ids(
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match write_tree(&tree_store, if_match) {
+        // sorted so the mapping reads in old id order.
+        Ok(mut tree) => HttpResponse::Ok().json(BTreeMap::from_iter(tree.compact())),
+        Err(response) => response,
+    }
+}
+
+// Repairs what GET /verify reports, {"rebuilt":true} when anything had to change.
+async fn reindex(
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => HttpResponse::Ok().json(json!({ "rebuilt": tree.rebuild_indexes() })),
+        Err(response) => response,
+    }
+}
+
+async fn ensure_root(
+    if_match: Option<web::Header<IfMatch>>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let result = match write_tree(&tree_store, if_match) {
+        Ok(mut tree) => {
+            let label = tree.config().root_label().to_string();
+            tree.ensure_root(&label)
+        }
+        Err(response) => return response,
+    };
+    match result {
+        Ok(root) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(node::node_to_json(&root)),
+        Err(error @ EnsureRootError::MultipleRoots(_)) => {
+            HttpResponse::Conflict().body(error.to_string())
+        }
+        Err(error @ EnsureRootError::AddNode(_)) => {
+            HttpResponse::BadRequest().body(error.to_string())
+        }
+        Err(error @ EnsureRootError::LockPoisoned) => {
+            HttpResponse::InternalServerError().body(error.to_string())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NodePairQuery {
+    a: i32,
+    b: i32,
+}
+
+async fn lowest_common_ancestor(
+    query: web::Query<NodePairQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match tree_store.lca(query.a, query.b) {
+        Ok(Some(lca)) => HttpResponse::Ok().json(json!({ "lca": lca })),
+        Ok(None) => {
+            HttpResponse::NotFound().body(format!("Node {} or {} does not exist", query.a, query.b))
+        }
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+async fn distance(
+    query: web::Query<NodePairQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match tree_store.distance(query.a, query.b) {
+        Ok(Some(distance)) => HttpResponse::Ok().json(json!({ "distance": distance })),
+        Ok(None) => {
+            HttpResponse::NotFound().body(format!("Node {} or {} does not exist", query.a, query.b))
+        }
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct CanMoveQuery {
+    child: i32,
+    parent: i32,
+}
+
+// Dry run of a move for drag and drop UIs, a rejected move is still a 200 with the reason.
+async fn can_move(
+    query: web::Query<CanMoveQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    match tree_store.validate_move(query.child, query.parent) {
+        Ok(()) => HttpResponse::Ok().json(json!({ "allowed": true })),
+        Err(MoveError::LockPoisoned) => {
+            HttpResponse::InternalServerError().body(MoveError::LockPoisoned.to_string())
+        }
+        Err(error) => {
+            let reason = match error {
+                MoveError::Cycle { .. } => "would create cycle".to_string(),
+                error => error.to_string(),
+            };
+            HttpResponse::Ok().json(json!({ "allowed": false, "reason": reason }))
+        }
+    }
+}
+
+// One level of the tree for lazily expanding UIs, has_children drives the expand arrow.
+#[derive(Serialize)]
+struct ChildSummary {
+    id: i32,
+    label: String,
+    has_children: bool,
+}
+
+async fn children_of(path: web::Path<i32>, tree_store: web::Data<TreeStore>) -> impl Responder {
+    let id = path.into_inner();
+    match tree_store.children_of(id) {
+        Ok(Some(children)) => HttpResponse::Ok().json(summarize(&children)),
+        Ok(None) => HttpResponse::NotFound().body(format!("Node {} does not exist", id)),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+fn summarize(nodes: &[RcNodeRef]) -> Vec<ChildSummary> {
+    nodes
+        .iter()
+        .map(|node| {
+            let node = node.lock().unwrap();
+            ChildSummary {
+                id: node.id,
+                label: node.label.clone(),
+                has_children: node.len() > 0,
+            }
+        })
+        .collect()
+}
+
+// For "move up / move down" controls, the node itself is left out.
+async fn siblings_of(path: web::Path<i32>, tree_store: web::Data<TreeStore>) -> impl Responder {
+    let id = path.into_inner();
+    match tree_store.siblings(id) {
+        Ok(Some(siblings)) => HttpResponse::Ok().json(summarize(&siblings)),
+        Ok(None) => HttpResponse::NotFound().body(format!("Node {} does not exist", id)),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Nodes in the subtree including {id} itself, e.g. for "N items hidden" on a collapsed node.
+async fn subtree_size(path: web::Path<i32>, tree_store: web::Data<TreeStore>) -> impl Responder {
+    let id = path.into_inner();
+    match tree_store.subtree_size(id) {
+        Ok(Some(size)) => HttpResponse::Ok().json(json!({ "size": size })),
+        Ok(None) => HttpResponse::NotFound().body(format!("Node {} does not exist", id)),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// {"parent_id":3,"index":1}, where a client rendering the nested tree should put the node. A
+// root has a null parent_id and its index among the roots.
+async fn locate_node(path: web::Path<i32>, tree_store: web::Data<TreeStore>) -> impl Responder {
+    let id = path.into_inner();
+    match tree_store.locate(id) {
+        Ok(Some((parent_id, index))) => {
+            HttpResponse::Ok().json(json!({ "parent_id": parent_id, "index": index }))
+        }
+        Ok(None) => HttpResponse::NotFound().body(format!("Node {} does not exist", id)),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct SubtreesQuery {
+    // comma separated, e.g. "1,5,9".
+    ids: String,
+    // 404 when any id is missing instead of leaving it out.
+    #[serde(default)]
+    strict: bool,
+}
+
+// Several pinned branches in one request, each full subtree in the order the ids were given.
+async fn get_subtrees(
+    query: web::Query<SubtreesQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let ids: Result<Vec<i32>, _> = query
+        .ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse::<i32>().map_err(|_| id))
+        .collect();
+    let ids = match ids {
+        Ok(ids) => ids,
+        Err(id) => return HttpResponse::BadRequest().body(format!("Invalid node id {:?}", id)),
+    };
+    let nodes = match tree_store.get_nodes(&ids) {
+        Ok(nodes) => nodes,
+        Err(error) => return HttpResponse::InternalServerError().body(error.to_string()),
+    };
+    if query.strict {
+        if let Some((id, _)) = ids.iter().zip(&nodes).find(|(_, node)| node.is_none()) {
+            return HttpResponse::NotFound().body(format!("Node {} does not exist", id));
+        }
+    }
+    let nodes: Vec<RcNodeRef> = nodes.into_iter().flatten().collect();
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(node::to_json(&nodes))
+}
+
+// Every path from {id} down to a leaf, e.g. the outcomes of a decision tree.
+async fn leaf_paths(path: web::Path<i32>, tree_store: web::Data<TreeStore>) -> impl Responder {
+    let id = path.into_inner();
+    match tree_store.leaf_paths(id) {
+        Ok(Some(paths)) => HttpResponse::Ok().json(paths),
+        Ok(None) => HttpResponse::NotFound().body(format!("Node {} does not exist", id)),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ByPathQuery {
+    path: String,
+}
+
+// ?path=root/child/leaf follows labels down from a root, 409 when a segment matches more than one
+// sibling.
+async fn get_by_path(
+    query: web::Query<ByPathQuery>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let segments: Vec<&str> = query.path.split('/').filter(|s| !s.is_empty()).collect();
+    match tree_store.resolve_path(&segments) {
+        Ok(Ok(node)) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(node::node_to_json(&node)),
+        Ok(Err(error @ PathError::Empty)) => HttpResponse::BadRequest().body(error.to_string()),
+        Ok(Err(error @ PathError::NotFound(_))) => HttpResponse::NotFound().body(error.to_string()),
+        Ok(Err(error @ PathError::Ambiguous(_))) => {
+            HttpResponse::Conflict().body(error.to_string())
+        }
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+async fn root_of(path: web::Path<i32>, tree_store: web::Data<TreeStore>) -> impl Responder {
+    let id = path.into_inner();
+    match tree_store.root_of(id) {
+        Ok(Some(root)) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(node::node_to_json(&root)),
+        Ok(None) => HttpResponse::NotFound().body(format!("Node {} does not exist", id)),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+async fn is_ancestor(
+    path: web::Path<(i32, i32)>,
+    tree_store: web::Data<TreeStore>,
+) -> impl Responder {
+    let (a, b) = path.into_inner();
+    match tree_store.is_ancestor(a, b) {
+        Ok(Some(result)) => HttpResponse::Ok().json(json!({ "result": result })),
+        Ok(None) => HttpResponse::NotFound().body(format!("Node {} or {} does not exist", a, b)),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Upgrades to a websocket that receives a JSON TreeEvent for every mutation.
+async fn subscribe(
+    req: HttpRequest,
+    body: web::Payload,
+    tree_store: web::Data<TreeStore>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let mut events = tree_store.subscribe();
+
+    actix_rt::spawn(async move {
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        let json = serde_json::to_string(&event).unwrap();
+                        if session.text(json).await.is_err() {
+                            return;
+                        }
+                    }
+                    // a slow client misses the events it lagged behind on rather than blocking writers.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                message = messages.next() => match message {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+// Server-sent events alternative to the websocket, one `data:` frame per mutation.
+async fn event_stream(tree_store: web::Data<TreeStore>) -> impl Responder {
+    let events = tree_store.subscribe();
+    let frames = futures_util::stream::unfold(events, |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let frame = format!("data: {}\n\n", serde_json::to_string(&event).unwrap());
+                    return Some((Ok::<_, actix_web::Error>(web::Bytes::from(frame)), events));
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("cache-control", "no-cache"))
+        // an encoder may hold frames back until it has enough to compress.
+        .insert_header(ContentEncoding::Identity)
+        .streaming(frames)
+}
+
+#[cfg(test)]
+// the original tests pass json bodies by reference, left as they were written.
+#[allow(clippy::needless_borrows_for_generic_args)]
+mod integration_tests {
+    use super::*;
+    use actix_web::{
+        body::{BodySize, MessageBody},
+        test,
+        web::Bytes,
+    };
+    use request_id::X_REQUEST_ID;
+    use std::{env, future::poll_fn, pin::Pin, time::Duration};
+
+    macro_rules! test_app {
+        ( ) => {{
+            test_app!(TreeStore::default())
+        }};
+        ( $tree_store:expr ) => {{
+            test_app!($tree_store, ServerConfig::default())
+        }};
+        ( $tree_store:expr, $server_config:expr ) => {{
+            {
+                let tree_store = web::Data::new($tree_store);
+                let server_config: ServerConfig = $server_config;
+                let rate_limiter = web::Data::new(RateLimiter::new(server_config.rate_limit));
+                let cfg = App::new().configure(|cfg| {
+                    setup_app(
+                        cfg,
+                        tree_store.clone(),
+                        rate_limiter.clone(),
+                        &server_config,
+                    )
+                });
+                let app = test::init_service(cfg).await;
+
+                (tree_store, app)
+            }
+        }};
+    }
+
+    #[actix_rt::test]
+    async fn get_node_returns_200() {
+        let (_, app) = test_app!();
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_rt::test]
+    async fn initial_get_node_returns_empty_json_array() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let json = test::read_body(response).await;
+        let expected = Bytes::from(r#"[]"#);
+        assert_eq!(json, expected);
+    }
+
+    #[actix_rt::test]
+    async fn post_node_returns_200() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(&json!({"label": "test", "parent_id": null}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_rt::test]
+    async fn post_failure_returns_400() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(&json!({"label": "test", "parent_id": "not an int"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn happy_path_get_tree() {
+        let (tree_store, app) = test_app!();
+
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+
+        let json = test::read_body(response).await;
+        assert_eq!(json, Bytes::from_static(b"[]"));
+
+        tree_store
+            .clone()
+            .add_node(String::from("root"), None)
+            .unwrap();
+
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+
+        let json = test::read_body(response).await;
+        let expected = Bytes::from(r#"[{"id":1,"label":"root","children":[]}]"#);
+        assert_eq!(json, expected);
+    }
+
+    #[actix_rt::test]
+    async fn happy_path_add_node() {
+        let (tree_store, app) = test_app!();
+
+        assert!(tree_store.len() == 0);
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(&AddNodeRequest {
+                label: Some("root".to_string()),
+                parent_id: None,
+                metadata: HashMap::new(),
+                sort_key: None,
+            })
+            .to_request();
+
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from_static(b"[{\"id\":1,\"label\":\"root\",\"children\":[]}]")
+        );
+
+        assert!(tree_store.len() == 1);
+    }
+
+    #[actix_rt::test]
+    async fn head_node_returns_200_for_existing_id() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/api/tree/1")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let body = test::read_body(response).await;
+        assert!(body.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn head_node_returns_404_for_missing_id() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/api/tree/1")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 404);
+        let body = test::read_body(response).await;
+        assert!(body.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn move_node_to_position_inserts_at_index() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(2)).unwrap();
+        tree_store.add_node("c".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/4/position")
+            .set_json(MoveToPositionRequest {
+                parent_id: 2,
+                index: Some(0),
+                after_sibling_id: None,
+            })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(
+            body,
+            json!({"moved_id": 4, "old_parent_id": 1, "new_parent_id": 2})
+        );
+        let expected = r#"[{"id":1,"label":"root","children":[{"id":2,"label":"a","children":[{"id":4,"label":"c","children":[]},{"id":3,"label":"b","children":[]}]}]}]"#;
+        assert_eq!(node::to_json(&tree_store.get_tree().unwrap()), expected);
+    }
+
+    #[actix_rt::test]
+    async fn move_node_to_position_rejects_cycles() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/1/position")
+            .set_json(MoveToPositionRequest {
+                parent_id: 2,
+                index: Some(0),
+                after_sibling_id: None,
+            })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn move_node_to_position_returns_404_for_missing_node() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/5/position")
+            .set_json(MoveToPositionRequest {
+                parent_id: 1,
+                index: Some(0),
+                after_sibling_id: None,
+            })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn post_node_past_max_size_returns_400() {
+        let (tree_store, app) = test_app!(TreeStore::with_config(TreeConfig {
+            max_nodes: Some(2),
+            ..TreeConfig::default()
+        }));
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"label": "child", "parent_id": 1}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
+        assert_eq!(tree_store.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn post_node_with_metadata_serializes_it() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"label": "root", "metadata": {"color": "red"}}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        let expected =
+            Bytes::from(r#"[{"id":1,"label":"root","metadata":{"color":"red"},"children":[]}]"#);
+        assert_eq!(json, expected);
+    }
+
+    #[actix_rt::test]
+    async fn patch_metadata_sets_and_returns_it() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::patch()
+            .uri("/api/tree/2/metadata")
+            .set_json(SetMetadataRequest {
+                key: "owner".to_string(),
+                value: "sam".to_string(),
+            })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        let expected =
+            Bytes::from(r#"{"id":2,"label":"child","metadata":{"owner":"sam"},"children":[]}"#);
+        assert_eq!(json, expected);
+
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let json = test::read_body(test::call_service(&app, req).await).await;
+        let expected = Bytes::from(
+            r#"[{"id":1,"label":"root","children":[{"id":2,"label":"child","metadata":{"owner":"sam"},"children":[]}]}]"#,
+        );
+        assert_eq!(json, expected);
+    }
+
+    #[actix_rt::test]
+    async fn patch_metadata_returns_404_for_missing_node() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::patch()
+            .uri("/api/tree/1/metadata")
+            .set_json(SetMetadataRequest {
+                key: "owner".to_string(),
+                value: "sam".to_string(),
+            })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn get_tree_returns_etag_and_honours_if_none_match() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        let etag = response.headers().get("etag").unwrap().clone();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree")
+            .insert_header(("if-none-match", etag.clone()))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 304);
+        let body = test::read_body(response).await;
+        assert!(body.is_empty());
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"label": "root", "parent_id": null}))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree")
+            .insert_header(("if-none-match", etag.clone()))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_ne!(response.headers().get("etag").unwrap(), etag);
+    }
+
+    #[actix_rt::test]
+    async fn get_tree_with_fields_omits_unselected_fields() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree?fields=id,children")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(r#"[{"id":1,"children":[{"id":2,"children":[]}]}]"#)
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree?fields=id")
+            .to_request();
+        let json = test::read_body(test::call_service(&app, req).await).await;
+        assert_eq!(json, Bytes::from(r#"[{"id":1}]"#));
+    }
+
+    #[actix_rt::test]
+    async fn get_tree_with_unknown_field_returns_400() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree?fields=id,name")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn subscribers_receive_node_added_events() {
+        let tree_store = web::Data::new(TreeStore::default());
+        let server_store = tree_store.clone();
+        let mut server = actix_test::start(move || {
+            let tree_store = server_store.clone();
+            let rate_limiter = web::Data::new(RateLimiter::new(None));
+            App::new()
+                .configure(|cfg| setup_app(cfg, tree_store, rate_limiter, &ServerConfig::default()))
+        });
+
+        let mut socket = server.ws_at("/api/tree/subscribe").await.unwrap();
+        let response = server
+            .post("/api/tree")
+            .send_json(&json!({"label": "root", "parent_id": null}))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let frame = socket.next().await.unwrap().unwrap();
+        assert_eq!(
+            frame,
+            awc::ws::Frame::Text(Bytes::from(r#"{"type":"node_added","id":1}"#))
+        );
+        assert_eq!(tree_store.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn event_stream_emits_a_frame_per_mutation() {
+        let (tree_store, app) = test_app!();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/events")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let mut body = response.into_body();
+        let frame = poll_fn(|cx| Pin::new(&mut body).poll_next(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            frame,
+            Bytes::from("data: {\"type\":\"node_added\",\"id\":1}\n\n")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn clone_subtree_copies_nodes_with_new_ids() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+        tree_store.add_node("target".to_string(), None).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/1/clone")
+            .set_json(CloneSubtreeRequest { parent_id: Some(3) })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(
+                r#"{"id":4,"label":"root","children":[{"id":5,"label":"child","children":[]}]}"#
+            )
+        );
+        assert_eq!(tree_store.len(), 5);
+    }
+
+    #[actix_rt::test]
+    async fn clone_subtree_returns_404_for_missing_node() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/1/clone")
+            .set_json(CloneSubtreeRequest { parent_id: None })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn swap_nodes_exchanges_positions() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b1".to_string(), Some(3)).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/swap")
+            .set_json(SwapNodesRequest { a: 2, b: 4 })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(
+                r#"[{"id":1,"label":"root","children":[{"id":4,"label":"b1","children":[]},{"id":3,"label":"b","children":[{"id":2,"label":"a","children":[]}]}]}]"#
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn swap_nodes_rejects_ancestors() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/swap")
+            .set_json(SwapNodesRequest { a: 2, b: 1 })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn post_without_label_names_the_field() {
+        let (tree_store, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"parent_id": null}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
+        let json = test::read_body(response).await;
+        assert_eq!(json, Bytes::from(r#"{"error":"label is required"}"#));
+        assert_eq!(tree_store.len(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn post_without_label_uses_the_configured_default() {
+        let (tree_store, app) = test_app!(TreeStore::with_config(TreeConfig {
+            default_label: Some("untitled".to_string()),
+            ..TreeConfig::default()
+        }));
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"parent_id": null}))
+            .to_request();
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            r#"[{"id":1,"label":"untitled","children":[]}]"#
+        );
+
+        // an explicit null is still rejected.
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"label": null}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(tree_store.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn post_with_non_integer_parent_names_the_field() {
+        let (_, app) = test_app!();
+
+        for parent_id in [json!("1"), json!(1.5), json!(i64::MAX)] {
+            let req = test::TestRequest::post()
+                .uri("/api/tree")
+                .set_json(json!({"label": "child", "parent_id": parent_id}))
+                .to_request();
+            let response = test::call_service(&app, req).await;
+
+            assert_eq!(response.status(), 400);
+            let json = test::read_body(response).await;
+            assert_eq!(
+                json,
+                Bytes::from(r#"{"error":"parent_id must be an integer"}"#)
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn post_with_malformed_body_returns_json_error() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .insert_header(ContentType::json())
+            .set_payload("[1, 2]")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(r#"{"error":"body must be a JSON object"}"#)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn post_with_unknown_fields_names_the_field() {
+        let (tree_store, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"label": "x", "parent_id": null, "extra": 1}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(
+                r#"{"error":"unknown field `extra`, expected one of `label`, `parent_id`, `metadata`, `sort_key`"}"#
+            )
+        );
+        assert_eq!(tree_store.len(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn ensure_root_creates_then_reuses_root() {
+        let (tree_store, app) = test_app!();
+
+        for _ in 0..2 {
+            let req = test::TestRequest::post().uri("/api/tree/root").to_request();
+            let response = test::call_service(&app, req).await;
+
+            assert_eq!(response.status(), 200);
+            let json = test::read_body(response).await;
+            assert_eq!(
+                json,
+                Bytes::from(r#"{"id":1,"label":"root","children":[]}"#)
+            );
+        }
+        assert_eq!(tree_store.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn ensure_root_with_multiple_roots_returns_409() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("a".to_string(), None).unwrap();
+        tree_store.add_node("b".to_string(), None).unwrap();
+
+        let req = test::TestRequest::post().uri("/api/tree/root").to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 409);
+    }
+
+    #[actix_rt::test]
+    async fn lca_returns_shared_ancestor_or_null() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/lca?a=2&b=3")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(json, Bytes::from(r#"{"lca":1}"#));
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/lca?a=2&b=4")
+            .to_request();
+        let json = test::read_body(test::call_service(&app, req).await).await;
+        assert_eq!(json, Bytes::from(r#"{"lca":null}"#));
+    }
+
+    #[actix_rt::test]
+    async fn lca_returns_404_for_missing_node() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/lca?a=1&b=5")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn distance_counts_edges_or_returns_null() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b1".to_string(), Some(3)).unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+
+        for (query, expected) in [
+            ("a=1&b=2", r#"{"distance":1}"#),
+            ("a=2&b=4", r#"{"distance":3}"#),
+            ("a=4&b=5", r#"{"distance":null}"#),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/tree/distance?{}", query))
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), 200);
+            let json = test::read_body(response).await;
+            assert_eq!(json, Bytes::from(expected));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn is_ancestor_of_reports_ancestry() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+
+        for (uri, expected) in [
+            ("/api/tree/1/is-ancestor-of/2", r#"{"result":true}"#),
+            ("/api/tree/3/is-ancestor-of/2", r#"{"result":false}"#),
+            ("/api/tree/1/is-ancestor-of/1", r#"{"result":false}"#),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), 200);
+            let json = test::read_body(response).await;
+            assert_eq!(json, Bytes::from(expected));
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/1/is-ancestor-of/9")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn server_stops_cleanly() {
+        let tree_store = web::Data::new(TreeStore::default());
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = build_server(tree_store.clone(), ServerConfig::default(), listener).unwrap();
+        let handle = server.handle();
+        let running = actix_rt::spawn(server);
+
+        let response = awc::Client::new()
+            .post(format!("http://{}/api/tree", address))
+            .force_close()
+            .send_json(&json!({"label": "root"}))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        handle.stop(true).await;
+        assert!(running.await.unwrap().is_ok());
+        assert_eq!(tree_store.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn get_roots_wraps_each_root_in_id_order() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("first".to_string(), None).unwrap();
+        tree_store.add_node("second".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get().uri("/api/tree/roots").to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(
+                r#"[{"root_id":1,"tree":{"id":1,"label":"first","children":[{"id":3,"label":"child","children":[]}]}},{"root_id":2,"tree":{"id":2,"label":"second","children":[]}}]"#
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn get_tree_pretty_prints_on_request() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree?pretty=true")
+            .to_request();
+        let json = test::read_body(test::call_service(&app, req).await).await;
+        assert_eq!(
+            json,
+            Bytes::from(
+                "[\n  {\n    \"id\": 1,\n    \"label\": \"root\",\n    \"children\": []\n  }\n]"
+            )
+        );
+
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let json = test::read_body(test::call_service(&app, req).await).await;
+        assert!(!json.contains(&b'\n'));
+    }
+
+    #[actix_rt::test]
+    async fn get_tree_compresses_when_accepted() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree")
+            .insert_header(("accept-encoding", "gzip"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+        let body = test::read_body(response).await;
+        // gzip magic number
+        assert_eq!(&body[..2], &[0x1f, 0x8b]);
+    }
+
+    #[actix_rt::test]
+    async fn event_stream_is_never_compressed() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/events")
+            .insert_header(("accept-encoding", "gzip"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(
+            response.headers().get("content-encoding").unwrap(),
+            "identity"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn add_node_past_rate_limit_returns_429() {
+        let (tree_store, app) = test_app!(
+            TreeStore::default(),
+            ServerConfig {
+                rate_limit: Some(2.0),
+                ..ServerConfig::default()
+            }
+        );
+        let client = "10.0.0.1:4000".parse().unwrap();
+
+        let mut statuses = vec![];
+        for _ in 0..3 {
+            let req = test::TestRequest::post()
+                .uri("/api/tree")
+                .peer_addr(client)
+                .set_json(json!({"label": "node"}))
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            statuses.push(response.status().as_u16());
+            if response.status() == 429 {
+                assert_eq!(response.headers().get("retry-after").unwrap(), "1");
+            }
+        }
+
+        assert_eq!(statuses, vec![200, 200, 429]);
+        assert_eq!(tree_store.len(), 2);
+
+        // other clients have their own bucket.
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .set_json(json!({"label": "node"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_rt::test]
+    async fn restore_round_trips_snapshot() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store
+            .write(None)
+            .unwrap()
+            .set_metadata(2, "k".to_string(), "v".to_string());
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/snapshot")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        let snapshot = test::read_body(response).await;
+        let mut document: Value = serde_json::from_slice(&snapshot).unwrap();
+        let updated_at = tree_store.get_tree().unwrap()[0].lock().unwrap().updated_at;
+        assert_eq!(document["nodes"][0]["updated_at"], updated_at);
+        for node in document["nodes"].as_array_mut().unwrap() {
+            node.as_object_mut().unwrap().remove("updated_at");
+        }
+        assert_eq!(
+            document,
+            json!({"version": 3, "next_id": 3, "nodes": [
+                {"id": 1, "label": "root", "parent_id": null},
+                {"id": 2, "label": "a", "parent_id": 1, "metadata": {"k": "v"}}
+            ]})
+        );
+        let expected = node::to_json(&tree_store.get_tree().unwrap());
+
+        tree_store.add_node("b".to_string(), Some(2)).unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/restore")
+            .insert_header(ContentType::json())
+            .set_payload(snapshot)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(test::read_body(response).await, Bytes::from(expected));
+        assert_eq!(tree_store.len(), 2);
+        // the timestamps come back with the nodes.
+        let (_, restored) = tree_store.snapshot().unwrap();
+        assert_eq!(restored.nodes[0].updated_at, Some(updated_at));
+    }
+
+    #[actix_rt::test]
+    async fn restore_rejects_orphaned_nodes() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/restore")
+            .set_json(json!({"next_id": 3, "nodes": [{"id": 2, "label": "a", "parent_id": 9}]}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
+        assert_eq!(tree_store.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn diff_reports_added_and_relabeled_nodes() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/diff")
+            .set_json(json!({
+                "before": {"next_id": 3, "nodes": [
+                    {"id": 1, "label": "root", "parent_id": null},
+                    {"id": 2, "label": "a", "parent_id": 1}
+                ]},
+                "after": {"version": 7, "next_id": 4, "nodes": [
+                    {"id": 1, "label": "root", "parent_id": null, "metadata": {"k": "v"}},
+                    {"id": 2, "label": "renamed", "parent_id": 1},
+                    {"id": 3, "label": "b", "parent_id": 2}
+                ]}
+            }))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(r#"{"added":[3],"removed":[],"relabeled":[2],"moved":[],"changed":[1]}"#)
+        );
+        // the live tree is untouched.
+        assert_eq!(tree_store.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn oversized_bodies_return_413() {
+        let (tree_store, app) = test_app!(
+            TreeStore::default(),
+            ServerConfig {
+                max_payload_bytes: 64,
+                ..ServerConfig::default()
+            }
+        );
+        let label = "x".repeat(100);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({ "label": label }))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 413);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/restore")
+            .set_json(
+                json!({"next_id": 2, "nodes": [{"id": 1, "label": label, "parent_id": null}]}),
+            )
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 413);
+        assert_eq!(tree_store.len(), 0);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"label": "small"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_rt::test]
+    async fn root_of_returns_the_containing_root() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+        tree_store
+            .add_node("grandchild".to_string(), Some(2))
+            .unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/3/root")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(
+                r#"{"id":1,"label":"root","children":[{"id":2,"label":"child","children":[{"id":3,"label":"grandchild","children":[]}]}]}"#
+            )
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/4/root")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(r#"{"id":4,"label":"other","children":[]}"#)
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/9/root")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn verify_reports_well_formed_tree_as_consistent() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(2)).unwrap();
+        tree_store.write(None).unwrap().move_node(3, 1, 0).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/verify")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(json, Bytes::from(r#"{"consistent":true}"#));
+    }
+
+    #[actix_rt::test]
+    async fn children_returns_one_level() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("a1".to_string(), Some(2)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/1/children")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(
+                r#"[{"id":2,"label":"a","has_children":true},{"id":4,"label":"b","has_children":false}]"#
+            )
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/9/children")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn get_tree_negotiates_msgpack() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree")
+            .insert_header(("accept", "application/msgpack"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/msgpack"
+        );
+        let body = test::read_body(response).await;
+        let decoded: Value = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(
+            decoded,
+            json!([{"id": 1, "label": "root", "children": [{"id": 2, "label": "child", "children": []}]}])
+        );
+
+        // JSON stays the default and wins when preferred.
+        let req = test::TestRequest::get()
+            .uri("/api/tree")
+            .insert_header(("accept", "application/json, application/msgpack;q=0.5"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn stale_if_match_returns_412() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let response = test::call_service(&app, req).await;
+        let etag = response.headers().get("etag").unwrap().clone();
+
+        // someone else writes after our read.
+        tree_store.add_node("c".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/3/position")
+            .insert_header(("if-match", etag))
+            .set_json(MoveToPositionRequest {
+                parent_id: 2,
+                index: Some(0),
+                after_sibling_id: None,
+            })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 412);
+        assert_eq!(response.headers().get("etag").unwrap(), "W/\"4\"");
+        assert_eq!(tree_store.version(), 4);
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/3/position")
+            .insert_header(("if-match", "\"4\""))
+            .set_json(MoveToPositionRequest {
+                parent_id: 2,
+                index: Some(0),
+                after_sibling_id: None,
+            })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+
+        // without If-Match writes stay unconditional.
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"label": "d"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_rt::test]
+    async fn ids_returns_sorted_flat_array() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(2)).unwrap();
+
+        let req = test::TestRequest::get().uri("/api/tree/ids").to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(json, Bytes::from("[1,2,3]"));
+    }
+
+    #[actix_rt::test]
+    async fn prune_leaves_removes_only_current_leaves() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("a1".to_string(), Some(2)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/prune-leaves")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(json, Bytes::from(r#"{"removed":[3,4]}"#));
+        assert_eq!(
+            node::to_json(&tree_store.get_tree().unwrap()),
+            r#"[{"id":1,"label":"root","children":[{"id":2,"label":"a","children":[]}]}]"#
+        );
+    }
+
+    #[actix_rt::test]
+    async fn reorder_moves_middle_child_to_front_and_back() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        for label in ["a", "b", "c"] {
+            tree_store.add_node(label.to_string(), Some(1)).unwrap();
+        }
+
+        for (to, expected) in [
+            (SiblingEnd::Front, vec![3, 2, 4]),
+            (SiblingEnd::Back, vec![2, 4, 3]),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/api/tree/3/reorder")
+                .set_json(ReorderRequest { to })
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), 200);
+
+            let children = tree_store.children_of(1).unwrap().unwrap();
+            let ids: Vec<i32> = children
+                .iter()
+                .map(|child| child.lock().unwrap().id)
+                .collect();
+            assert_eq!(ids, expected);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/1/reorder")
+            .set_json(json!({"to": "front"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn metrics_reports_width_per_level() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        for parent_id in [1, 1, 2, 2, 3] {
+            tree_store
+                .add_node("child".to_string(), Some(parent_id))
+                .unwrap();
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/metrics")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(r#"{"height":3,"max_width":3,"width_per_level":[1,2,3]}"#)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn post_with_non_json_content_type_returns_415() {
+        let (tree_store, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .insert_header(ContentType::plaintext())
+            .set_payload(r#"{"label": "root"}"#)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 415);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(r#"{"error":"Content-Type must be application/json"}"#)
+        );
+        assert_eq!(tree_store.len(), 0);
+
+        // the web::Json endpoints answer the same way.
+        let req = test::TestRequest::post()
+            .uri("/api/tree/swap")
+            .insert_header(ContentType::plaintext())
+            .set_payload(r#"{"a": 1, "b": 2}"#)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 415);
+    }
+
+    #[actix_rt::test]
+    async fn dry_run_validates_without_inserting() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree?dry_run=true")
+            .set_json(json!({"label": "child", "parent_id": 1}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(r#"{"dry_run":true,"id":2,"label":"child"}"#)
+        );
+        assert_eq!(tree_store.len(), 1);
+        assert_eq!(tree_store.version(), 1);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree?dry_run=true")
+            .set_json(json!({"label": "child", "parent_id": 9}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(tree_store.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn server_starts_with_one_worker() {
+        let tree_store = web::Data::new(TreeStore::default());
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = build_server(
+            tree_store.clone(),
+            ServerConfig {
+                workers: Some(1),
+                ..ServerConfig::default()
+            },
+            listener,
+        )
+        .unwrap();
+        let handle = server.handle();
+        let running = actix_rt::spawn(server);
+
+        let response = awc::Client::new()
+            .post(format!("http://{}/api/tree", address))
+            .force_close()
+            .send_json(&json!({"label": "root"}))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        handle.stop(true).await;
+        assert!(running.await.unwrap().is_ok());
+        assert_eq!(tree_store.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn server_starts_with_keep_alive_and_connection_limit_from_env() {
+        // no other test reads these two.
+        env::set_var("TREE_KEEP_ALIVE_SECS", "0");
+        env::set_var("TREE_MAX_CONNECTIONS", "8");
+        let server_config = ServerConfig::from_env();
+        assert_eq!(server_config.keep_alive, Duration::ZERO);
+        assert_eq!(server_config.max_connections, 8);
+
+        let tree_store = web::Data::new(TreeStore::default());
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = build_server(
+            tree_store.clone(),
+            ServerConfig {
+                workers: Some(1),
+                ..server_config
+            },
+            listener,
+        )
+        .unwrap();
+        let handle = server.handle();
+        let running = actix_rt::spawn(server);
+
+        let response = awc::Client::new()
+            .post(format!("http://{}/api/tree", address))
+            .send_json(&json!({"label": "root"}))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        handle.stop(true).await;
+        assert!(running.await.unwrap().is_ok());
+        assert_eq!(tree_store.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn autocomplete_matches_label_prefix() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("Robin".to_string(), Some(1)).unwrap();
+        tree_store.add_node("crow".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/autocomplete?prefix=ro&limit=10")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(r#"[{"id":2,"label":"Robin"},{"id":1,"label":"root"}]"#)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn autocomplete_returns_empty_without_matches() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/autocomplete?prefix=zz")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(json, Bytes::from("[]"));
+    }
+
+    #[actix_rt::test]
+    async fn subtree_size_counts_node_and_descendants() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(2)).unwrap();
+
+        for (uri, expected) in [
+            ("/api/tree/1/size", r#"{"size":3}"#),
+            ("/api/tree/3/size", r#"{"size":1}"#),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), 200);
+            let json = test::read_body(response).await;
+            assert_eq!(json, Bytes::from(expected));
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/9/size")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn post_node_with_missing_parent_returns_404() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"label": "child", "parent_id": 9}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 404);
+        let body = test::read_body(response).await;
+        assert_eq!(
+            body,
+            Bytes::from(r#"{"error":"Cannot add connection, parent 9 does not exist"}"#)
+        );
+        assert_eq!(tree_store.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn trailing_slash_routes_like_without() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+
+        for path in ["/api/tree", "/api/tree/1/children"] {
+            let req = test::TestRequest::get().uri(path).to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), 200);
+            let expected = test::read_body(response).await;
+
+            let req = test::TestRequest::get()
+                .uri(&format!("{}/", path))
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(test::read_body(response).await, expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn cors_headers_for_allowed_origins() {
+        let (_, app) = test_app!(
+            TreeStore::default(),
+            ServerConfig {
+                cors_origins: vec!["https://app.example.com".to_string()],
+                ..ServerConfig::default()
+            }
+        );
+        // Vary lists header names case-insensitively, possibly several to a value.
+        let varies_on_origin = |headers: &header::HeaderMap| {
+            headers.get_all(header::VARY).any(|value| {
+                value
+                    .to_str()
+                    .unwrap()
+                    .split(',')
+                    .any(|name| name.trim().eq_ignore_ascii_case("origin"))
+            })
+        };
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree")
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://app.example.com"
+        );
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api/tree")
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://app.example.com"
+        );
+        assert!(headers
+            .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .contains("POST"));
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap(),
+            "content-type"
+        );
+        assert_eq!(headers.get_all(header::VARY).count(), 1);
+        assert!(varies_on_origin(headers));
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree")
+            .insert_header((header::ORIGIN, "https://other.example.com"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+        // a cache keyed without Origin would hand this response to the allowed origin too.
+        assert!(varies_on_origin(response.headers()));
+    }
+
+    #[actix_rt::test]
+    async fn rename_nodes_applies_all_updates() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::patch()
+            .uri("/api/tree/labels")
+            .set_json(json!({"updates": [{"id": 1, "label": "top"}, {"id": 2, "label": "b"}]}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let json = test::read_body(response).await;
+        assert_eq!(
+            json,
+            Bytes::from(
+                r#"[{"id":1,"label":"top","children":[{"id":2,"label":"b","children":[]}]}]"#
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn rename_nodes_rolls_back_on_unknown_id() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::patch()
+            .uri("/api/tree/labels")
+            .set_json(json!({"updates": [{"id": 1, "label": "top"}, {"id": 9, "label": "b"}]}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 404);
+        assert_eq!(tree_store.version(), 2);
+        let tree = tree_store.get_tree().unwrap();
+        assert_eq!(tree[0].lock().unwrap().label, "root");
+    }
+
+    #[actix_rt::test]
+    async fn export_dot_colors_nodes_by_depth() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/export/dot?color_by=depth")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let dot = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        let fill_color = |id: &str| {
+            let line = dot
+                .lines()
+                .find(|line| line.trim_start().starts_with(&format!("{} [", id)))
+                .unwrap()
+                .to_string();
+            line.split("fillcolor=").nth(1).unwrap().to_string()
+        };
+        assert_ne!(fill_color("1"), fill_color("2"));
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/export/dot")
+            .to_request();
+        let dot = test::read_body(test::call_service(&app, req).await).await;
+        assert_eq!(
+            dot,
+            Bytes::from(
+                "digraph tree {\n  1 [label=\"root\"];\n  2 [label=\"child\"];\n  1 -> 2;\n}\n"
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn audit_lists_each_mutation() {
+        let (_, app) = test_app!();
+        for payload in [
+            json!({"label": "root"}),
+            json!({"label": "child", "parent_id": 1}),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/api/tree")
+                .set_json(payload)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/audit?limit=100")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        let entries: Value = test::read_body_json(response).await;
+        let entries = entries.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        for (entry, id) in entries.iter().zip([1, 2]) {
+            assert_eq!(entry["operation"], "add");
+            assert_eq!(entry["node_id"], id);
+            assert!(entry["timestamp"].as_u64().unwrap() > 0);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn can_move_allows_a_legal_move() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/can-move?child=3&parent=2")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(test::read_body(response).await, r#"{"allowed":true}"#);
+        // nothing moved.
+        assert_eq!(tree_store.is_ancestor(2, 3).unwrap(), Some(false));
+    }
+
+    #[actix_rt::test]
+    async fn can_move_rejects_a_cycle() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/can-move?child=1&parent=2")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(
+            body,
+            json!({ "allowed": false, "reason": "would create cycle" })
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/can-move?child=9&parent=1")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["allowed"], false);
+    }
+
+    #[actix_rt::test]
+    async fn envelope_wraps_json_responses_on_request() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/1/size?envelope=true")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body,
+            json!({ "data": { "size": 2 }, "meta": { "count": 2, "version": 2 } })
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree?envelope=true")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"][0]["label"], "root");
+        assert_eq!(body["meta"], json!({ "count": 2, "version": 2 }));
+
+        // the bare form stays the default.
+        let req = test::TestRequest::get()
+            .uri("/api/tree/1/size")
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, r#"{"size":2}"#);
+
+        // errors are not wrapped.
+        let req = test::TestRequest::get()
+            .uri("/api/tree/9/size?envelope=true")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(test::read_body(response).await, "Node 9 does not exist");
+    }
+
+    #[actix_rt::test]
+    async fn siblings_of_an_interior_node() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+        tree_store.add_node("c".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b1".to_string(), Some(3)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/3/siblings")
+            .to_request();
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            r#"[{"id":2,"label":"a","has_children":false},{"id":4,"label":"c","has_children":false}]"#
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/5/siblings")
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "[]");
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/9/siblings")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn siblings_of_a_root_are_the_other_roots() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("first".to_string(), None).unwrap();
+        tree_store.add_node("second".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(2)).unwrap();
+        tree_store.add_node("third".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/2/siblings")
+            .to_request();
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            r#"[{"id":1,"label":"first","has_children":false},{"id":4,"label":"third","has_children":false}]"#
+        );
+    }
+
+    #[actix_rt::test]
+    async fn get_tree_streams_the_same_document() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("first".to_string(), None).unwrap();
+        tree_store.add_node("second".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+        tree_store.add_node("third".to_string(), None).unwrap();
+
+        for query in ["", "&pretty=true"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/tree?stream=true{}", query))
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.response().body().size(), BodySize::Stream);
+            let streamed = test::read_body(response).await;
+
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/tree?{}", query))
+                .to_request();
+            let buffered = test::call_and_read_body(&app, req).await;
+            assert_eq!(streamed, buffered);
+            let roots: Value = serde_json::from_slice(&streamed).unwrap();
+            assert_eq!(roots.as_array().unwrap().len(), 3);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn compact_returns_the_id_mapping() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b1".to_string(), Some(3)).unwrap();
+        // removes the leaves 2 and 4, leaving 1 and 3.
+        let req = test::TestRequest::post()
+            .uri("/api/tree/prune-leaves")
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/compact")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(test::read_body(response).await, r#"{"1":1,"3":2}"#);
+        assert_eq!(tree_store.ids().unwrap(), vec![1, 2]);
+        assert_eq!(tree_store.is_ancestor(1, 2).unwrap(), Some(true));
+    }
+
+    #[actix_rt::test]
+    async fn get_tree_as_adjacency_list() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+        tree_store.add_node("a1".to_string(), Some(2)).unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree?format=adjacency")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(
+            body,
+            json!({
+                "nodes": [
+                    {"id": 1, "label": "root"},
+                    {"id": 2, "label": "a"},
+                    {"id": 3, "label": "b"},
+                    {"id": 4, "label": "a1"},
+                    {"id": 5, "label": "other"}
+                ],
+                "edges": [[1, 2], [1, 3], [2, 4]]
+            })
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree?format=graph")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn subtrees_returns_each_requested_branch() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("a1".to_string(), Some(2)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/subtrees?ids=4,2,9")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            test::read_body(response).await,
+            r#"[{"id":4,"label":"b","children":[]},{"id":2,"label":"a","children":[{"id":3,"label":"a1","children":[]}]}]"#
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/subtrees?ids=2,x")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn subtrees_strict_rejects_missing_ids() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/subtrees?ids=1,9&strict=true")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(test::read_body(response).await, "Node 9 does not exist");
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/subtrees?ids=1&strict=true")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_rt::test]
+    async fn get_tree_sorts_children_by_sort_key() {
+        let (_, app) = test_app!();
+        for (label, parent_id, sort_key) in [
+            ("root", None, None),
+            ("third", Some(1), Some(30)),
+            ("unkeyed", Some(1), None),
+            ("first", Some(1), Some(-5)),
+            ("second", Some(1), Some(10)),
+            ("also second", Some(1), Some(10)),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/api/tree")
+                .set_json(json!({"label": label, "parent_id": parent_id, "sort_key": sort_key}))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 200);
+        }
+
+        let labels = |body: Value| -> Vec<String> {
+            body[0]["children"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|child| child["label"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let req = test::TestRequest::get()
+            .uri("/api/tree?sort=key")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            labels(body.clone()),
+            vec!["first", "second", "also second", "third", "unkeyed"]
+        );
+        assert_eq!(body[0]["children"][0]["sort_key"], -5);
+
+        // stored order is untouched without sort=key.
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            labels(body),
+            vec!["third", "unkeyed", "first", "second", "also second"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn export_markdown_writes_a_nested_list() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b_c".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/export/markdown")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(test::read_body(response).await, "- root\n  - a\n  - b_c\n");
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/export/markdown?escape=true")
+            .to_request();
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            "- root\n  - a\n  - b\\_c\n"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn leaf_paths_lists_a_path_per_leaf() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("a1".to_string(), Some(2)).unwrap();
+        tree_store.add_node("a1x".to_string(), Some(3)).unwrap();
+        tree_store.add_node("a2".to_string(), Some(2)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/2/leaf-paths")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(test::read_body(response).await, "[[2,3,4],[2,5]]");
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/6/leaf-paths")
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "[[6]]");
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/9/leaf-paths")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn move_after_sibling_places_node_between() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("x".to_string(), Some(1)).unwrap();
+        tree_store.add_node("y".to_string(), Some(1)).unwrap();
+        tree_store.add_node("dragged".to_string(), None).unwrap();
+
+        let child_ids = |tree_store: &TreeStore| -> Vec<i32> {
+            tree_store
+                .children_of(1)
+                .unwrap()
+                .unwrap()
+                .iter()
+                .map(|child| child.lock().unwrap().id)
+                .collect()
+        };
+        let req = test::TestRequest::put()
+            .uri("/api/tree/4/position")
+            .set_json(json!({"parent_id": 1, "after_sibling_id": 2}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        assert_eq!(child_ids(&tree_store), vec![2, 4, 3]);
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/3/position")
+            .set_json(json!({"parent_id": 1, "after_sibling_id": null}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        assert_eq!(child_ids(&tree_store), vec![3, 2, 4]);
+
+        for body in [
+            json!({"parent_id": 1, "after_sibling_id": 9}),
+            json!({"parent_id": 1, "index": 0, "after_sibling_id": 2}),
+        ] {
+            let req = test::TestRequest::put()
+                .uri("/api/tree/4/position")
+                .set_json(body)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 400);
+        }
+        assert_eq!(child_ids(&tree_store), vec![3, 2, 4]);
+    }
+
+    #[actix_rt::test]
+    async fn serves_the_openapi_document() {
+        let (_, app) = test_app!();
+        let req = test::TestRequest::get()
+            .uri("/api/openapi.json")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 200);
+
+        let body = test::read_body(res).await;
+        let document: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(document["openapi"], "3.0.3");
+        assert!(document["paths"]["/api/tree"]["get"].is_object());
+        assert!(document["paths"]["/api/tree"]["post"]["requestBody"].is_object());
+        assert!(document["paths"]["/api/tree/{id}/position"]["put"].is_object());
+        assert_eq!(
+            document["paths"]["/api/tree/duplicates"]["get"]["parameters"][0]["name"],
+            "ci"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn failed_add_logs_a_warning() {
+        logging::capture::start();
+        let (_, app) = test_app!();
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .set_json(json!({"label": "orphan", "parent_id": 9}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+        let records = logging::capture::take();
+        assert!(
+            records
+                .iter()
+                .any(|(level, message)| *level == log::Level::Warn
+                    && message.starts_with("error adding node: ParentNotFound(9)")),
+            "{:?}",
+            records
+        );
+    }
+
+    #[actix_rt::test]
+    async fn lists_nodes_flat() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(2)).unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get().uri("/api/tree/nodes").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body,
+            json!([
+                {"id": 1, "label": "root", "parent_id": null},
+                {"id": 2, "label": "a", "parent_id": 1},
+                {"id": 3, "label": "b", "parent_id": 2},
+                {"id": 4, "label": "other", "parent_id": null},
+            ])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn move_response_names_a_former_root_parent_as_null() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(2)).unwrap();
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/2/position")
+            .set_json(json!({"parent_id": 1}))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body,
+            json!({"moved_id": 2, "old_parent_id": null, "new_parent_id": 1})
+        );
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/3/position")
+            .set_json(json!({"parent_id": 1, "after_sibling_id": 2}))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body,
+            json!({"moved_id": 3, "old_parent_id": 2, "new_parent_id": 1})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn collapses_a_chain_above_a_branch() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("A".to_string(), None).unwrap();
+        tree_store.add_node("B".to_string(), Some(1)).unwrap();
+        tree_store.add_node("C".to_string(), Some(2)).unwrap();
+        tree_store.add_node("x".to_string(), Some(3)).unwrap();
+        tree_store.add_node("y".to_string(), Some(3)).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/1/collapse-chain?separator=/")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(
+            body,
+            r#"{"id":1,"label":"A/B","children":[{"id":3,"label":"C","children":[{"id":4,"label":"x","children":[]},{"id":5,"label":"y","children":[]}]}]}"#
+        );
+        assert_eq!(tree_store.ids().unwrap(), vec![1, 3, 4, 5]);
+        assert_eq!(tree_store.version(), 6);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/2/collapse-chain")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn lists_changes_since_a_version() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        let since = tree_store.version();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.write(None).unwrap().move_node(2, 1, 0).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/tree/changes?since={}", since))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["version"], 3);
+        assert_eq!(body["resync"], false);
+        let changes: Vec<_> = body["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| (change["version"].clone(), change["operation"].clone()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![(json!(2), json!("add")), (json!(3), json!("move"))]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn changes_past_the_audit_log_ask_for_a_resync() {
+        let (tree_store, app) = test_app!();
+        for _ in 0..=audit::AUDIT_CAPACITY {
+            tree_store.add_node("root".to_string(), None).unwrap();
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/changes?since=0")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body,
+            json!({"version": audit::AUDIT_CAPACITY + 1, "resync": true, "changes": []})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn unmatched_paths_get_a_json_404() {
+        let (_, app) = test_app!();
+        for path in ["/nope", "/api/tree/1/nope"] {
+            let req = test::TestRequest::get().uri(path).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), 404);
+            assert_eq!(
+                res.headers().get(header::CONTENT_TYPE).unwrap(),
+                "application/json"
+            );
+            let body: Value = test::read_body_json(res).await;
+            assert_eq!(body, json!({"error": "not found", "path": path}));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn touch_only_moves_updated_at() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        let node = tree_store
+            .write(None)
+            .unwrap()
+            .add_node_with_metadata(
+                "a".to_string(),
+                Some(1),
+                HashMap::from([("k".to_string(), "v".to_string())]),
+            )
+            .unwrap();
+        let created = node.lock().unwrap().updated_at;
+        let before = node::to_json(&tree_store.get_tree().unwrap());
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/2/touch")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["id"], 2);
+        let updated_at = body["updated_at"].as_u64().unwrap();
+        assert!(updated_at > created);
+        assert_eq!(node.lock().unwrap().updated_at, updated_at);
+        assert_eq!(node::to_json(&tree_store.get_tree().unwrap()), before);
+        assert_eq!(tree_store.version(), 3);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/9/touch")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+        assert_eq!(tree_store.version(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn empty_tree_is_200_by_default() {
+        let (_, app) = test_app!();
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(test::read_body(res).await, "[]");
+    }
+
+    #[actix_rt::test]
+    async fn empty_tree_is_404_when_asked() {
+        let (tree_store, app) = test_app!();
+        for uri in [
+            "/api/tree?empty=404",
+            "/api/tree?empty=404&format=adjacency",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), 404);
+            let body: Value = test::read_body_json(res).await;
+            assert_eq!(body, json!({"error": "tree is empty"}));
+        }
+
+        tree_store.add_node("root".to_string(), None).unwrap();
+        let req = test::TestRequest::get()
+            .uri("/api/tree?empty=404")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::get()
+            .uri("/api/tree?empty=500")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn restore_rejects_snapshots_past_max_depth_up_front() {
+        let (tree_store, app) = test_app!(TreeStore::with_config(TreeConfig {
+            max_depth: Some(50),
+            ..TreeConfig::default()
+        }));
+        tree_store.add_node("root".to_string(), None).unwrap();
+        let before = tree_store.snapshot().unwrap();
+
+        // one node past the limit, at the very end of the chain.
+        let nodes: Vec<Value> = (1..=51)
+            .map(|id| json!({"id": id, "label": "n", "parent_id": (id > 1).then(|| id - 1)}))
+            .collect();
+        let req = test::TestRequest::post()
+            .uri("/api/tree/restore")
+            .set_json(json!({"next_id": 52, "nodes": nodes}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400);
+        assert_eq!(
+            test::read_body(res).await,
+            "Cannot restore snapshot, tree would exceed its maximum depth of 50"
+        );
+        assert_eq!(tree_store.snapshot().unwrap(), before);
+    }
+
+    #[actix_rt::test]
+    async fn flatten_promotes_children_in_place() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("x".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
+        tree_store.add_node("c1".to_string(), Some(3)).unwrap();
+        tree_store.add_node("c2".to_string(), Some(3)).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/3/flatten")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(
+            body,
+            r#"[{"id":1,"label":"root","children":[{"id":2,"label":"a","children":[]},{"id":5,"label":"c1","children":[]},{"id":6,"label":"c2","children":[]},{"id":4,"label":"b","children":[]}]}]"#
+        );
+        assert_eq!(tree_store.ids().unwrap(), vec![1, 2, 4, 5, 6]);
+
+        for (id, status) in [(3, 404), (1, 400)] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/api/tree/{}/flatten", id))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn attaching_a_parented_child_is_a_conflict() {
+        // no route attaches an existing node yet, so this goes straight to the mapping.
+        let response = add_node_error_response(AddNodeError::AlreadyHasParent(2));
+        assert_eq!(response.status(), 409);
+        assert_eq!(
+            actix_web::body::to_bytes(response.into_body())
+                .await
+                .unwrap(),
+            r#"{"error":"Cannot add connection, child 2 already has a parent"}"#
+        );
+
+        for error in [AddNodeError::EmptyLabel, AddNodeError::SelfLoop(1)] {
+            assert_eq!(add_node_error_response(error).status(), 400);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn bulk_delete_skips_descendants_already_removed() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(2)).unwrap();
+        tree_store.add_node("c".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::delete()
+            .uri("/api/tree")
+            .set_json(RemoveNodesRequest { ids: vec![2, 3, 3] })
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({"removed": [2, 3]}));
+        assert_eq!(
+            node::to_json(&tree_store.get_tree().unwrap()),
+            r#"[{"id":1,"label":"root","children":[{"id":4,"label":"c","children":[]}]}]"#
+        );
+        assert_eq!(tree_store.version(), 5);
+
+        // nothing left to remove, nothing committed.
+        let req = test::TestRequest::delete()
+            .uri("/api/tree")
+            .set_json(RemoveNodesRequest { ids: vec![2] })
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({"removed": []}));
+        assert_eq!(tree_store.version(), 5);
+    }
+
+    #[actix_rt::test]
+    async fn import_returns_the_id_map() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/import")
+            .set_json(json!({"nodes": [
+                {"key": "clientTmp1", "label": "a", "parent_id": 1},
+                {"key": "clientTmp2", "label": "b", "parent_key": "clientTmp1"},
+            ]}))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({"id_map": {"clientTmp1": 2, "clientTmp2": 3}}));
+        assert_eq!(
+            node::to_json(&tree_store.get_tree().unwrap()),
+            r#"[{"id":1,"label":"root","children":[{"id":2,"label":"a","children":[{"id":3,"label":"b","children":[]}]}]}]"#
+        );
+        assert_eq!(tree_store.version(), 2);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/import")
+            .set_json(json!({"nodes": [
+                {"key": "x", "label": "c", "parent_id": 1},
+                {"label": "d", "parent_id": 9},
+            ]}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400);
+        assert_eq!(
+            test::read_body(res).await,
+            "Cannot import node 1: Cannot add connection, parent 9 does not exist"
+        );
+        assert_eq!(tree_store.ids().unwrap(), vec![1, 2, 3]);
+        assert_eq!(tree_store.version(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn best_effort_import_partitions_the_entries() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/import?mode=best_effort")
+            .set_json(json!({"nodes": [
+                {"key": "a", "label": "a", "parent_id": 1},
+                {"label": "b", "parent_key": "a"},
+                {"label": "c", "parent_id": 9},
+                {"label": "d", "parent_key": "missing"},
+                {"label": "e"},
+            ]}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 207);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(
+            body,
+            json!({
+                "inserted": [2, 3, 4],
+                "failed": [
+                    {"index": 2, "error": "Cannot import node 2: Cannot add connection, parent 9 does not exist"},
+                    {"index": 3, "error": "Cannot import node 3, parent_key \"missing\" does not name an earlier node"},
+                ],
+                "id_map": {"a": 2},
+            })
+        );
+        assert_eq!(tree_store.ids().unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(tree_store.version(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn gets_a_node_by_label_path() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("child".to_string(), Some(1)).unwrap();
+        tree_store.add_node("leaf".to_string(), Some(2)).unwrap();
+        tree_store.add_node("twin".to_string(), Some(1)).unwrap();
+        tree_store.add_node("twin".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/by-path?path=root/child/leaf")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            test::read_body(res).await,
+            r#"{"id":3,"label":"leaf","children":[]}"#
+        );
 
-async fn get_tree(tree_store: web::Data<TreeStore>) -> impl Responder {
-    match tree_store.get_tree() {
-        Ok(tree) => HttpResponse::Ok().json(tree),
-        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+        let req = test::TestRequest::get()
+            .uri("/api/tree/by-path?path=root/leaf")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(
+            test::read_body(res).await,
+            "Cannot resolve path, no node labelled \"leaf\""
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/by-path?path=root/twin")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 409);
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/by-path?path=")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400);
     }
-}
 
-#[derive(Deserialize, Serialize)]
-struct AddNodeRequest {
-    label: String,
-    parent_id: Option<i32>,
-}
+    #[actix_rt::test]
+    async fn duplicates_group_case_insensitively_only_with_ci() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("Foo".to_string(), Some(1)).unwrap();
+        tree_store.add_node("foo".to_string(), Some(1)).unwrap();
 
-async fn add_node(
-    payload: web::Json<AddNodeRequest>,
-    tree_store: web::Data<TreeStore>,
-) -> impl Responder {
-    let payload = payload.into_inner();
+        let req = test::TestRequest::get()
+            .uri("/api/tree/duplicates")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!([]));
 
-    if let Err(result) = tree_store.add_node(payload.label, payload.parent_id) {
-        println!("error adding node: {:?}", result);
-        return HttpResponse::BadRequest().body(result.message);
+        let req = test::TestRequest::get()
+            .uri("/api/tree/duplicates?ci=true")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!([[2, 3]]));
     }
 
-    match tree_store.get_tree() {
-        Err(error) => return HttpResponse::InternalServerError().body(error.to_string()),
-        Ok(result) => return HttpResponse::Ok().json(result),
-    }
-}
+    #[actix_rt::test]
+    async fn nudges_a_node_among_its_siblings() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        for label in ["a", "b", "c"] {
+            tree_store.add_node(label.to_string(), Some(1)).unwrap();
+        }
+        let child_ids = |body: &Value| -> Vec<i64> {
+            body[0]["children"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|child| child["id"].as_i64().unwrap())
+                .collect()
+        };
 
-#[cfg(test)]
-mod integration_tests {
-    use super::*;
-    use actix_web::{test, web::Bytes};
-    use serde_json::json;
+        let req = test::TestRequest::post()
+            .uri("/api/tree/3/nudge")
+            .set_json(json!({"direction": "up"}))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(child_ids(&body), vec![3, 2, 4]);
+        assert_eq!(tree_store.version(), 5);
 
-    macro_rules! test_app {
-        ( ) => {{
-            {
-                let tree_store = web::Data::new(TreeStore::default());
-                let cfg = App::new().configure(|cfg| setup_app(cfg, tree_store.clone()));
-                let app = test::init_service(cfg).await;
+        // 3 is first now, nudging it up changes nothing.
+        let req = test::TestRequest::post()
+            .uri("/api/tree/3/nudge")
+            .set_json(json!({"direction": "up"}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 200);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(child_ids(&body), vec![3, 2, 4]);
+        assert_eq!(tree_store.version(), 5);
 
-                (tree_store, app)
-            }
-        }};
+        let req = test::TestRequest::post()
+            .uri("/api/tree/1/nudge")
+            .set_json(json!({"direction": "down"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
     }
 
     #[actix_rt::test]
-    async fn get_node_returns_200() {
-        let (_, app) = test_app!();
+    async fn omits_empty_children_only_when_asked() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("leaf".to_string(), Some(1)).unwrap();
+
         let req = test::TestRequest::get().uri("/api/tree").to_request();
-        let response = test::call_service(&app, req).await;
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            r#"[{"id":1,"label":"root","children":[{"id":2,"label":"leaf","children":[]}]}]"#
+        );
 
-        assert_eq!(response.status(), 200);
+        let req = test::TestRequest::get()
+            .uri("/api/tree?omit_empty_children=true")
+            .to_request();
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            r#"[{"id":1,"label":"root","children":[{"id":2,"label":"leaf"}]}]"#
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree?omit_empty_children=true&pretty=true&fields=children")
+            .to_request();
+        assert_eq!(
+            test::call_and_read_body(&app, req).await,
+            "[\n  {\n    \"children\": [\n      {}\n    ]\n  }\n]"
+        );
     }
 
     #[actix_rt::test]
-    async fn initial_get_node_returns_empty_json_array() {
-        let (_, app) = test_app!();
+    async fn locates_a_node_by_parent_and_index() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(1)).unwrap();
 
-        let req = test::TestRequest::get().uri("/api/tree").to_request();
-        let response = test::call_service(&app, req).await;
+        for (uri, expected) in [
+            ("/api/tree/3/locate", json!({"parent_id": 1, "index": 1})),
+            ("/api/tree/1/locate", json!({"parent_id": null, "index": 0})),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let body: Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body, expected);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/9/locate")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn export_ndjson_writes_a_line_per_node() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("b".to_string(), Some(2)).unwrap();
 
+        let req = test::TestRequest::get()
+            .uri("/api/tree/export/ndjson")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
         assert_eq!(
-            response.headers().get("content-type").unwrap(),
-            "application/json"
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-ndjson"
+        );
+        let body = test::read_body(response).await;
+        let lines: Vec<Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                json!({"id": 1, "label": "root", "parent_id": null}),
+                json!({"id": 2, "label": "a", "parent_id": 1}),
+                json!({"id": 3, "label": "b", "parent_id": 2}),
+            ]
         );
-        let json = test::read_body(response).await;
-        let expected = Bytes::from(r#"[]"#);
-        assert_eq!(json, expected);
     }
 
     #[actix_rt::test]
-    async fn post_node_returns_200() {
-        let (_, app) = test_app!();
+    async fn echoes_the_request_id() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree")
+            .insert_header((X_REQUEST_ID, "trace-42"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(&X_REQUEST_ID).unwrap(), "trace-42");
+
+        // errors carry one too, generated when the request has none.
+        let req = test::TestRequest::get()
+            .uri("/api/tree/9/root")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.headers().get(&X_REQUEST_ID).unwrap().len(), 36);
+    }
+
+    #[actix_rt::test]
+    async fn reindex_leaves_a_consistent_tree_alone() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/reindex")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({"rebuilt": false}));
+        assert_eq!(tree_store.version(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn restore_and_add_reject_ids_that_would_overflow() {
+        let (tree_store, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/restore")
+            .set_json(json!({"next_id": i32::MAX, "nodes": []}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/restore")
+            .set_json(json!({"next_id": 1, "nodes": [{"id": i32::MAX - 1, "label": "last", "parent_id": null}]}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 200);
 
         let req = test::TestRequest::post()
             .uri("/api/tree")
-            .set_json(&json!({"label": "test", "parent_id": null}))
+            .set_json(json!({"label": "one too many", "parent_id": i32::MAX - 1}))
             .to_request();
         let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(tree_store.len(), 1);
 
+        // the failed add must not have poisoned the store.
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let response = test::call_service(&app, req).await;
         assert_eq!(response.status(), 200);
     }
 
     #[actix_rt::test]
-    async fn post_failure_returns_400() {
-        let (_, app) = test_app!();
+    async fn requests_fail_after_a_write_panicked() {
+        let tree_store = TreeStore::default();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        let root = tree_store.get_tree().unwrap()[0].clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = root.lock().unwrap();
+            panic!("poisoning the root");
+        })
+        .join();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tree_store.add_node("a".to_string(), Some(1))
+        }));
+        assert!(result.is_err());
+        let (_tree_store, app) = test_app!(tree_store);
+
+        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 500);
 
         let req = test::TestRequest::post()
             .uri("/api/tree")
-            .set_json(&json!({"label": "test", "parent_id": "not an int"}))
+            .set_json(json!({"label": "b"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 500);
+    }
+
+    #[actix_rt::test]
+    async fn diff_rejects_ids_that_would_overflow() {
+        let (_, app) = test_app!();
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/diff")
+            .set_json(json!({
+                "before": {"next_id": 1, "nodes": []},
+                "after": {"next_id": 1, "nodes": [
+                    {"id": i32::MAX, "label": "root", "parent_id": null}
+                ]}
+            }))
             .to_request();
         let response = test::call_service(&app, req).await;
 
         assert_eq!(response.status(), 400);
+        let body = test::read_body(response).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("leaves no room for another node"));
     }
 
-    #[actix_web::test]
-    async fn happy_path_get_tree() {
+    #[actix_rt::test]
+    async fn envelope_wraps_trees_too_deep_to_parse() {
         let (tree_store, app) = test_app!();
+        tree_store.add_node("n".to_string(), None).unwrap();
+        // well past serde_json's recursion limit of 128 once each level nests an object and an
+        // array.
+        for parent_id in 1..200 {
+            tree_store
+                .add_node("n".to_string(), Some(parent_id))
+                .unwrap();
+        }
 
-        let req = test::TestRequest::get().uri("/api/tree").to_request();
-        let response = test::call_service(&app, req).await;
+        let req = test::TestRequest::get()
+            .uri("/api/tree?envelope=true")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.starts_with(r#"{"data":[{"#));
+        assert!(body.ends_with(r#"}],"meta":{"count":200,"version":200}}"#));
+    }
 
+    #[actix_rt::test]
+    async fn streamed_tree_is_cut_off_when_the_tree_changes() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        for _ in 0..2000 {
+            tree_store.add_node("child".to_string(), Some(1)).unwrap();
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree?stream=true")
+            .to_request();
+        let response = test::call_service(&app, req).await;
         assert_eq!(response.status(), 200);
+        // the body is written as it is read, after this write.
+        tree_store.add_node("late".to_string(), None).unwrap();
+        assert!(actix_web::body::to_bytes(response.into_body())
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn import_rejects_too_deep_batches_before_building_anything() {
+        let (tree_store, app) = test_app!(TreeStore::with_config(TreeConfig {
+            max_depth: Some(2),
+            ..TreeConfig::default()
+        }));
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/import")
+            .set_json(json!({"nodes": [
+                {"key": "a", "label": "a"},
+                {"key": "b", "label": "b", "parent_key": "a"},
+                {"label": "c", "parent_key": "b"}
+            ]}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 400);
         assert_eq!(
-            response.headers().get("content-type").unwrap(),
-            "application/json"
+            test::read_body(response).await,
+            "Cannot import node 2, tree would exceed its maximum depth of 2"
         );
+        assert_eq!(tree_store.len(), 0);
+        assert_eq!(tree_store.version(), 0);
+    }
 
-        let json = test::read_body(response).await;
-        assert_eq!(json, Bytes::from_static(b"[]"));
+    #[actix_rt::test]
+    async fn bulk_writes_check_the_request_timeout() {
+        // a zero timeout has passed by the time the handler runs, which it does without ever
+        // yielding to the middleware.
+        let (tree_store, app) = test_app!(
+            TreeStore::default(),
+            ServerConfig {
+                request_timeout: Some(Duration::ZERO),
+                ..ServerConfig::default()
+            }
+        );
+        tree_store.add_node("root".to_string(), None).unwrap();
 
-        tree_store
-            .clone()
-            .add_node(String::from("root"), None)
-            .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/api/tree/import")
+            .set_json(json!({"nodes": [{"label": "a", "parent_id": 1}]}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 503);
+        assert_eq!(
+            test::read_body(response).await,
+            "Cannot import node 0, the request timed out"
+        );
 
-        let req = test::TestRequest::get().uri("/api/tree").to_request();
+        let req = test::TestRequest::delete()
+            .uri("/api/tree")
+            .set_json(json!({"ids": [1]}))
+            .to_request();
         let response = test::call_service(&app, req).await;
-        assert_eq!(response.status(), 200);
+        assert_eq!(response.status(), 503);
+        assert_eq!(tree_store.len(), 1);
+        assert_eq!(tree_store.version(), 1);
+    }
 
-        let json = test::read_body(response).await;
-        let expected = Bytes::from(r#"[{"id":1,"label":"root","children":[]}]"#);
-        assert_eq!(json, expected);
+    #[actix_rt::test]
+    async fn can_move_gives_the_same_answer_as_the_move() {
+        let (tree_store, app) = test_app!(TreeStore::with_config(TreeConfig {
+            unique_sibling_labels: true,
+            ..TreeConfig::default()
+        }));
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("docs".to_string(), Some(1)).unwrap();
+        tree_store.add_node("docs".to_string(), Some(2)).unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/can-move?child=4&parent=1")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let expected = "Cannot move node, parent 1 already has a child labelled \"docs\"";
+        assert_eq!(body, json!({ "allowed": false, "reason": expected }));
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/4/position")
+            .set_json(json!({"parent_id": 1}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(test::read_body(response).await, expected);
     }
 
     #[actix_rt::test]
-    async fn happy_path_add_node() {
+    async fn moves_and_swaps_past_max_depth_are_rejected() {
+        let (tree_store, app) = test_app!(TreeStore::with_config(TreeConfig {
+            max_depth: Some(2),
+            ..TreeConfig::default()
+        }));
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+        tree_store.add_node("other".to_string(), None).unwrap();
+        tree_store.add_node("b".to_string(), Some(3)).unwrap();
+
+        let req = test::TestRequest::put()
+            .uri("/api/tree/3/position")
+            .set_json(MoveToPositionRequest {
+                parent_id: 1,
+                index: None,
+                after_sibling_id: None,
+            })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(
+            test::read_body(response).await,
+            "Cannot move node under 1, tree would exceed its maximum depth of 2"
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree/swap")
+            .set_json(SwapNodesRequest { a: 2, b: 3 })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(tree_store.version(), 4);
+    }
+
+    #[actix_rt::test]
+    async fn swapping_a_node_with_itself_commits_nothing() {
         let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
 
-        assert!(tree_store.len() == 0);
         let req = test::TestRequest::post()
+            .uri("/api/tree/swap")
+            .set_json(SwapNodesRequest { a: 2, b: 2 })
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(tree_store.version(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn gzip_and_identity_bodies_share_a_weak_etag() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+
+        let req = test::TestRequest::get()
             .uri("/api/tree")
-            .set_json(&AddNodeRequest {
-                label: "root".to_string(),
-                parent_id: None,
-            })
+            .insert_header(("accept-encoding", "gzip"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+        let etag = response.headers().get("etag").unwrap().clone();
+        assert_eq!(etag, "W/\"1\"");
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree")
+            .insert_header(("if-none-match", etag.clone()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 304);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tree")
+            .insert_header(("if-match", etag))
+            .set_json(json!({"label": "a", "parent_id": 1}))
             .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        assert_eq!(tree_store.version(), 2);
+    }
 
+    #[actix_rt::test]
+    async fn rename_node_relabels_one_node() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("a".to_string(), Some(1)).unwrap();
+
+        let req = test::TestRequest::patch()
+            .uri("/api/tree/2/label")
+            .set_json(json!({"label": "b"}))
+            .to_request();
         let response = test::call_service(&app, req).await;
+
         assert_eq!(response.status(), 200);
-        assert_eq!(
-            response.headers().get("content-type").unwrap(),
-            "application/json"
-        );
         let json = test::read_body(response).await;
-        assert_eq!(
-            json,
-            Bytes::from_static(b"[{\"id\":1,\"label\":\"root\",\"children\":[]}]")
-        );
+        assert_eq!(json, Bytes::from(r#"{"id":2,"label":"b","children":[]}"#));
+        assert_eq!(tree_store.version(), 3);
 
-        assert!(tree_store.len() == 1);
+        let req = test::TestRequest::patch()
+            .uri("/api/tree/9/label")
+            .set_json(json!({"label": "c"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 404);
+
+        let req = test::TestRequest::patch()
+            .uri("/api/tree/2/label")
+            .insert_header(("If-Match", "W/\"1\""))
+            .set_json(json!({"label": "c"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 412);
+        let nodes = tree_store.get_nodes(&[2]).unwrap();
+        assert_eq!(nodes[0].as_ref().unwrap().lock().unwrap().label, "b");
+    }
+
+    #[actix_rt::test]
+    async fn search_finds_nodes_by_exact_label() {
+        let (tree_store, app) = test_app!();
+        tree_store.add_node("root".to_string(), None).unwrap();
+        tree_store.add_node("leaf".to_string(), Some(1)).unwrap();
+        tree_store.add_node("Leaf".to_string(), Some(1)).unwrap();
+        tree_store.add_node("leaf".to_string(), Some(2)).unwrap();
+
+        for (uri, expected) in [
+            ("/api/tree/search?label=leaf", "[2,4]"),
+            ("/api/tree/search?label=Leaf", "[3]"),
+            ("/api/tree/search?label=lea", "[]"),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), 200);
+            let json = test::read_body(response).await;
+            assert_eq!(json, Bytes::from(expected));
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/tree/search")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 400);
     }
 }
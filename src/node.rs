@@ -1,45 +1,524 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use serde::Serialize;
 
-pub type RcNodeRef = Arc<Mutex<Node>>;
+pub type RcNodeRef<T = ()> = Arc<Mutex<Node<T>>>;
+
+/*
+ * Domain data a consumer can hang off every node, written under "data".
+ * Nodes added without a payload carry None and are written without "data", so a Tree without a
+ * payload type writes exactly the same documents as before payloads existed.
+ */
+pub trait Payload: Clone + Serialize {}
+
+impl<T: Clone + Serialize> Payload for T {}
 
 #[derive(Serialize)]
-pub struct Node {
+pub struct Node<T = ()> {
     pub id: i32,
     pub label: String,
-    children: Vec<RcNodeRef>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    // None unless the node was added with a payload.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+    // Orders the node among its siblings in ?sort=key output, the children vec keeps insertion
+    // order regardless.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort_key: Option<i64>,
+    // milliseconds since the unix epoch, set when the node is created and by Tree::touch. It is
+    // not part of the tree documents, GET /api/tree stays byte for byte the same.
+    #[serde(skip)]
+    pub updated_at: u64,
+    children: Vec<RcNodeRef<T>>,
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
 }
 
-pub fn as_rc_ref(node: Node) -> RcNodeRef {
+pub fn as_rc_ref<T>(node: Node<T>) -> RcNodeRef<T> {
     Arc::new(Mutex::new(node))
 }
 
+/*
+ * Locks two different nodes, always lower id first whatever order they are passed in, so two
+ * threads locking the same pair can't each end up holding one lock while waiting on the other.
+ * The id sits behind the lock, so the caller passes it alongside the node.
+ * Writers holding two unrelated nodes at once take them through here, readers nesting a child
+ * lock inside its parent's are already ordered by the tree.
+ * Panics when a and b are the same node, its mutex can't be locked twice.
+ */
+pub fn lock_two<'a, T>(
+    (a_id, a): (i32, &'a RcNodeRef<T>),
+    (b_id, b): (i32, &'a RcNodeRef<T>),
+) -> (MutexGuard<'a, Node<T>>, MutexGuard<'a, Node<T>>) {
+    assert_ne!(a_id, b_id, "lock_two needs two different nodes");
+    if a_id < b_id {
+        let a = a.lock().unwrap();
+        (a, b.lock().unwrap())
+    } else {
+        let b = b.lock().unwrap();
+        (a.lock().unwrap(), b)
+    }
+}
+
 impl Node {
     pub fn new(id: i32, label: String) -> Self {
-        Node {
-            id,
-            label,
-            children: vec![],
-        }
+        Node::new_with_children(id, label, vec![])
     }
     pub fn new_with_children(id: i32, label: String, children: Vec<RcNodeRef>) -> Self {
+        let mut node = Node::with_capacity(id, label, 0);
+        node.children = children;
+        node
+    }
+
+    // Room for cap children up front, for callers that know how many are coming.
+    pub fn with_capacity(id: i32, label: String, cap: usize) -> Self {
+        Node::with_data_and_capacity(id, label, None, cap)
+    }
+}
+
+impl<T> Node<T> {
+    pub fn with_data(id: i32, label: String, data: T) -> Self {
+        Node::with_data_and_capacity(id, label, Some(data), 0)
+    }
+
+    // Adding cap children then never grows the Vec. Growing one child at a time reallocates
+    // about log2(n) times on the way to n children.
+    pub fn with_data_and_capacity(id: i32, label: String, data: Option<T>, cap: usize) -> Self {
         Node {
             id,
             label,
-            children,
+            metadata: HashMap::new(),
+            data,
+            sort_key: None,
+            updated_at: now_millis(),
+            children: Vec::with_capacity(cap),
         }
     }
 
-    pub fn add_child(&mut self, child: RcNodeRef) {
+    pub fn add_child(&mut self, child: RcNodeRef<T>) {
         self.children.push(child);
     }
 
+    // index is clamped to the end of the children, matching Vec::insert without the panic.
+    pub fn insert_child(&mut self, index: usize, child: RcNodeRef<T>) {
+        let index = index.min(self.children.len());
+        self.children.insert(index, child);
+    }
+
+    // For callers that know the slot from the tree's maps, no child is locked to find it.
+    pub fn remove_child_at(&mut self, index: usize) -> RcNodeRef<T> {
+        self.children.remove(index)
+    }
+
+    pub fn remove_child(&mut self, id: i32) -> Option<RcNodeRef<T>> {
+        let index = self
+            .children
+            .iter()
+            .position(|child| child.lock().unwrap().id == id)?;
+        Some(self.children.remove(index))
+    }
+
+    // Hands over every child, leaving the node a leaf.
+    pub fn take_children(&mut self) -> Vec<RcNodeRef<T>> {
+        std::mem::take(&mut self.children)
+    }
+
+    // Keeps only the children whose id passes keep, in order.
+    pub fn retain_children(&mut self, mut keep: impl FnMut(i32) -> bool) {
+        self.children.retain(|child| keep(child.lock().unwrap().id));
+    }
+
+    // Puts child in the slot at index and hands back the child that was there.
+    pub fn replace_child(&mut self, index: usize, child: RcNodeRef<T>) -> RcNodeRef<T> {
+        std::mem::replace(&mut self.children[index], child)
+    }
+
+    pub fn children(&self) -> &[RcNodeRef<T>] {
+        &self.children
+    }
+
     pub fn len(&self) -> i32 {
         self.children.len() as i32
     }
 }
 
+// The derived drop would recurse once per level, so a deep enough chain overflows the stack.
+// Unwrap children we hold the last reference to onto an explicit stack instead.
+impl<T> Drop for Node<T> {
+    fn drop(&mut self) {
+        let mut stack = std::mem::take(&mut self.children);
+        while let Some(child) = stack.pop() {
+            if let Ok(child) = Arc::try_unwrap(child) {
+                let mut child = child.into_inner().unwrap_or_else(PoisonError::into_inner);
+                stack.append(&mut child.children);
+            }
+        }
+    }
+}
+
+// Which node fields are written, all of them by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fields {
+    pub id: bool,
+    pub label: bool,
+    pub metadata: bool,
+    pub data: bool,
+    pub sort_key: bool,
+    pub children: bool,
+}
+
+impl Default for Fields {
+    fn default() -> Self {
+        Fields {
+            id: true,
+            label: true,
+            metadata: true,
+            data: true,
+            sort_key: true,
+            children: true,
+        }
+    }
+}
+
+impl FromStr for Fields {
+    type Err = String;
+
+    // Parses a comma separated selection such as "id,children".
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut fields = Fields {
+            id: false,
+            label: false,
+            metadata: false,
+            data: false,
+            sort_key: false,
+            children: false,
+        };
+        for name in value.split(',').map(str::trim) {
+            match name {
+                "id" => fields.id = true,
+                "label" => fields.label = true,
+                "metadata" => fields.metadata = true,
+                "data" => fields.data = true,
+                "sort_key" => fields.sort_key = true,
+                "children" => fields.children = true,
+                _ => return Err(format!("Unknown field: {:?}", name)),
+            }
+        }
+        Ok(fields)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct JsonOptions {
+    pub fields: Fields,
+    // Indents like serde_json::to_string_pretty.
+    pub pretty: bool,
+    // Writes siblings (roots included) ordered by sort_key instead of stored order.
+    pub sort_by_key: bool,
+    // Leaves are written without a "children" key instead of "children":[].
+    pub omit_empty_children: bool,
+}
+
+// Siblings ordered by sort_key, nodes without one after those with and ties by id.
+pub fn sorted_by_key<T>(nodes: &[RcNodeRef<T>]) -> Vec<RcNodeRef<T>> {
+    let mut keyed: Vec<_> = nodes
+        .iter()
+        .map(|node_ref| {
+            let node = node_ref.lock().unwrap();
+            (
+                (node.sort_key.is_none(), node.sort_key, node.id),
+                node_ref.clone(),
+            )
+        })
+        .collect();
+    keyed.sort_by_key(|(key, _)| *key);
+    keyed.into_iter().map(|(_, node_ref)| node_ref).collect()
+}
+
+// The siblings in the order options asks for.
+fn ordered<T>(nodes: &[RcNodeRef<T>], sort_by_key: bool) -> Vec<RcNodeRef<T>> {
+    if sort_by_key {
+        sorted_by_key(nodes)
+    } else {
+        nodes.to_vec()
+    }
+}
+
+pub fn to_json<T: Serialize>(nodes: &[RcNodeRef<T>]) -> String {
+    to_json_with(nodes, &JsonOptions::default())
+}
+
+struct JsonWriter {
+    json: String,
+    pretty: bool,
+}
+
+impl JsonWriter {
+    // Starts a new line at the given nesting level, a no-op unless pretty printing.
+    fn line(&mut self, level: usize) {
+        if self.pretty {
+            self.json.push('\n');
+            self.json.push_str(&"  ".repeat(level));
+        }
+    }
+
+    fn key(&mut self, key: &str) {
+        self.json.push_str(&serde_json::to_string(key).unwrap());
+        self.json.push(':');
+        if self.pretty {
+            self.json.push(' ');
+        }
+    }
+
+    // Writes the separator and indentation ahead of an object member.
+    fn member(&mut self, first: &mut bool, level: usize, key: &str) {
+        if !*first {
+            self.json.push(',');
+        }
+        *first = false;
+        self.line(level);
+        self.key(key);
+    }
+}
+
+/*
+ * Writes the forest as a JSON array without recursing.
+ * serde's Serializer api recurses into every nested value, which overflows the stack on deep
+ * chains, so the JSON responses are written by hand using an explicit stack of sibling lists.
+ * Each node is only locked long enough to copy its fields and child refs.
+ */
+pub fn to_json_with<T: Serialize>(nodes: &[RcNodeRef<T>], options: &JsonOptions) -> String {
+    let mut forest = ForestWriter::new(nodes, options.clone());
+    while forest.step() {}
+    forest.writer.json
+}
+
+// A streamed response is cut into chunks of roughly this many bytes.
+const CHUNK_BYTES: usize = 16 * 1024;
+
+/*
+ * The same document as to_json_with, produced lazily in chunks of about CHUNK_BYTES as the
+ * iterator is advanced. Only the current chunk and the stack of open sibling lists are held, so
+ * memory stays bounded however large a single root's subtree is. Nodes are read as each chunk is
+ * written, the caller keeps the tree from changing in between.
+ */
+pub fn to_json_chunks<T: Serialize>(
+    nodes: Vec<RcNodeRef<T>>,
+    options: JsonOptions,
+) -> impl Iterator<Item = String> {
+    let mut forest = ForestWriter::new(&nodes, options);
+    let mut done = false;
+    std::iter::from_fn(move || {
+        while !done && forest.writer.json.len() < CHUNK_BYTES {
+            done = !forest.step();
+        }
+        Some(std::mem::take(&mut forest.writer.json)).filter(|chunk| !chunk.is_empty())
+    })
+}
+
+struct ForestWriter<T> {
+    writer: JsonWriter,
+    // only non-empty sibling lists are pushed, empty children are written as [] directly.
+    stack: Vec<(Vec<RcNodeRef<T>>, usize)>,
+    options: JsonOptions,
+}
+
+impl<T: Serialize> ForestWriter<T> {
+    fn new(nodes: &[RcNodeRef<T>], options: JsonOptions) -> Self {
+        let mut writer = JsonWriter {
+            json: String::from("["),
+            pretty: options.pretty,
+        };
+        let mut stack = vec![];
+        if nodes.is_empty() {
+            writer.json.push(']');
+        } else {
+            stack.push((ordered(nodes, options.sort_by_key), 0));
+        }
+        ForestWriter {
+            writer,
+            stack,
+            options,
+        }
+    }
+
+    // Writes the next node or closes the innermost open array, false once the forest is done.
+    fn step(&mut self) -> bool {
+        if self.stack.is_empty() {
+            return false;
+        }
+        let writer = &mut self.writer;
+        let stack = &mut self.stack;
+        let options = &self.options;
+        let fields = options.fields;
+
+        // the items of the nth open array sit at nesting level 2n - 1.
+        let level = 2 * stack.len() - 1;
+        let (siblings, index) = stack.last_mut().unwrap();
+
+        if *index == siblings.len() {
+            stack.pop();
+            writer.line(level - 1);
+            writer.json.push(']');
+            if !stack.is_empty() {
+                // closes the node object that owns this children array.
+                writer.line(level - 2);
+                writer.json.push('}');
+            }
+            return true;
+        }
+
+        if *index > 0 {
+            writer.json.push(',');
+        }
+        let node_ref = siblings[*index].clone();
+        *index += 1;
+
+        let node = node_ref.lock().unwrap();
+        writer.line(level);
+        writer.json.push('{');
+        let mut first = true;
+        if fields.id {
+            writer.member(&mut first, level + 1, "id");
+            writer.json.push_str(&node.id.to_string());
+        }
+        if fields.label {
+            writer.member(&mut first, level + 1, "label");
+            writer
+                .json
+                .push_str(&serde_json::to_string(&node.label).unwrap());
+        }
+        if fields.metadata && !node.metadata.is_empty() {
+            writer.member(&mut first, level + 1, "metadata");
+            writer.json.push('{');
+            // sorted so the output is stable between requests.
+            let metadata: BTreeMap<_, _> = node.metadata.iter().collect();
+            let mut first_entry = true;
+            for (key, value) in metadata {
+                writer.member(&mut first_entry, level + 2, key);
+                writer.json.push_str(&serde_json::to_string(value).unwrap());
+            }
+            writer.line(level + 1);
+            writer.json.push('}');
+        }
+        if let (true, Some(data)) = (fields.data, &node.data) {
+            writer.member(&mut first, level + 1, "data");
+            // the payload is the consumer's own type and assumed shallow, serde writes it.
+            if writer.pretty {
+                let data = serde_json::to_string_pretty(data).unwrap();
+                let indent = format!("\n{}", "  ".repeat(level + 1));
+                writer.json.push_str(&data.replace('\n', &indent));
+            } else {
+                writer.json.push_str(&serde_json::to_string(data).unwrap());
+            }
+        }
+        if let (true, Some(sort_key)) = (fields.sort_key, node.sort_key) {
+            writer.member(&mut first, level + 1, "sort_key");
+            writer.json.push_str(&sort_key.to_string());
+        }
+
+        if fields.children && !(options.omit_empty_children && node.children.is_empty()) {
+            writer.member(&mut first, level + 1, "children");
+            if node.children.is_empty() {
+                writer.json.push_str("[]");
+            } else {
+                writer.json.push('[');
+                stack.push((ordered(&node.children, options.sort_by_key), 0));
+                return true;
+            }
+        }
+        if !first {
+            writer.line(level);
+        }
+        writer.json.push('}');
+        true
+    }
+}
+
+// Same as to_json for a single node, without the surrounding array.
+pub fn node_to_json<T: Serialize>(node: &RcNodeRef<T>) -> String {
+    let json = to_json(std::slice::from_ref(node));
+    json[1..json.len() - 1].to_string()
+}
+
+/*
+ * Writes the forest as MessagePack, the same shape as the JSON (nodes as maps keyed by field
+ * name). Arrays and maps are length prefixed so nodes can be written in pre-order off a plain
+ * stack, nothing has to be closed afterwards.
+ */
+pub fn to_msgpack_with<T: Serialize>(
+    nodes: &[RcNodeRef<T>],
+    fields: Fields,
+    sort_by_key: bool,
+) -> Vec<u8> {
+    // writes into a Vec can't fail.
+    let mut buf = vec![];
+    rmp::encode::write_array_len(&mut buf, nodes.len() as u32).unwrap();
+    let mut stack: Vec<RcNodeRef<T>> = ordered(nodes, sort_by_key).into_iter().rev().collect();
+
+    while let Some(node_ref) = stack.pop() {
+        let node = node_ref.lock().unwrap();
+        let has_metadata = fields.metadata && !node.metadata.is_empty();
+        let has_data = fields.data && node.data.is_some();
+        let has_sort_key = fields.sort_key && node.sort_key.is_some();
+        let len = [
+            fields.id,
+            fields.label,
+            has_metadata,
+            has_data,
+            has_sort_key,
+            fields.children,
+        ]
+        .iter()
+        .filter(|field| **field)
+        .count();
+        rmp::encode::write_map_len(&mut buf, len as u32).unwrap();
+
+        if fields.id {
+            rmp::encode::write_str(&mut buf, "id").unwrap();
+            rmp::encode::write_sint(&mut buf, node.id.into()).unwrap();
+        }
+        if fields.label {
+            rmp::encode::write_str(&mut buf, "label").unwrap();
+            rmp::encode::write_str(&mut buf, &node.label).unwrap();
+        }
+        if has_metadata {
+            rmp::encode::write_str(&mut buf, "metadata").unwrap();
+            rmp::encode::write_map_len(&mut buf, node.metadata.len() as u32).unwrap();
+            let metadata: BTreeMap<_, _> = node.metadata.iter().collect();
+            for (key, value) in metadata {
+                rmp::encode::write_str(&mut buf, key).unwrap();
+                rmp::encode::write_str(&mut buf, value).unwrap();
+            }
+        }
+        if let (true, Some(data)) = (has_data, &node.data) {
+            rmp::encode::write_str(&mut buf, "data").unwrap();
+            rmp_serde::encode::write_named(&mut buf, data).unwrap();
+        }
+        if let (true, Some(sort_key)) = (has_sort_key, node.sort_key) {
+            rmp::encode::write_str(&mut buf, "sort_key").unwrap();
+            rmp::encode::write_sint(&mut buf, sort_key).unwrap();
+        }
+        if fields.children {
+            rmp::encode::write_str(&mut buf, "children").unwrap();
+            rmp::encode::write_array_len(&mut buf, node.children.len() as u32).unwrap();
+            stack.extend(ordered(&node.children, sort_by_key).into_iter().rev());
+        }
+    }
+
+    buf
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -54,16 +533,219 @@ mod test {
         assert_eq!(actual_child.label, "child");
     }
 
+    #[test]
+    fn node_with_capacity_behaves_like_a_new_one() {
+        let mut plain = Node::new(1, "root".to_string());
+        let mut reserved = Node::with_capacity(1, "root".to_string(), 3);
+        assert!(reserved.children().is_empty());
+        for node in [&mut plain, &mut reserved] {
+            for id in 2..=5 {
+                node.add_child(as_rc_ref(Node::new(id, id.to_string())));
+            }
+            node.remove_child(3);
+        }
+        assert_eq!(
+            to_json(&[as_rc_ref(plain)]),
+            to_json(&[as_rc_ref(reserved)])
+        );
+    }
+
+    #[test]
+    fn inserts_and_removes_children() {
+        let mut parent = Node::new(1, "root".to_string());
+        parent.add_child(as_rc_ref(Node::new(2, "first".to_string())));
+        parent.insert_child(0, as_rc_ref(Node::new(3, "second".to_string())));
+        parent.insert_child(10, as_rc_ref(Node::new(4, "third".to_string())));
+        let ids: Vec<i32> = parent
+            .children()
+            .iter()
+            .map(|child| child.lock().unwrap().id)
+            .collect();
+        assert_eq!(ids, vec![3, 2, 4]);
+
+        let replaced = parent.replace_child(2, as_rc_ref(Node::new(5, "fourth".to_string())));
+        assert_eq!(replaced.lock().unwrap().id, 4);
+        assert_eq!(parent.children()[2].lock().unwrap().id, 5);
+
+        let removed = parent.remove_child(2).unwrap();
+        assert_eq!(removed.lock().unwrap().id, 2);
+        assert_eq!(parent.len(), 2);
+        assert!(parent.remove_child(2).is_none());
+    }
+
+    #[test]
+    fn writes_json_matching_serde() {
+        let mut root = Node::new(1, "root \"quoted\"".to_string());
+        let mut child = Node::new(2, "child".to_string());
+        child.add_child(as_rc_ref(Node::new(3, "grandchild".to_string())));
+        root.add_child(as_rc_ref(child));
+        root.add_child(as_rc_ref(Node::new(4, "sibling".to_string())));
+        let forest = vec![
+            as_rc_ref(root),
+            as_rc_ref(Node::new(5, "other".to_string())),
+        ];
+
+        assert_eq!(to_json(&forest), serde_json::to_string(&forest).unwrap());
+        assert_eq!(to_json::<()>(&[]), "[]");
+    }
+
+    #[test]
+    fn writes_metadata_sorted_by_key() {
+        let mut node = Node::new(1, "root".to_string());
+        node.metadata.insert("owner".to_string(), "sam".to_string());
+        node.metadata.insert("color".to_string(), "red".to_string());
+        let node = as_rc_ref(node);
+
+        assert_eq!(
+            node_to_json(&node),
+            r#"{"id":1,"label":"root","metadata":{"color":"red","owner":"sam"},"children":[]}"#
+        );
+    }
+
+    #[test]
+    fn writes_only_selected_fields() {
+        let mut root = Node::new(1, "root".to_string());
+        root.metadata.insert("color".to_string(), "red".to_string());
+        root.add_child(as_rc_ref(Node::new(2, "child".to_string())));
+        let forest = vec![as_rc_ref(root)];
+
+        let ids_only = JsonOptions {
+            fields: "id".parse().unwrap(),
+            ..JsonOptions::default()
+        };
+        assert_eq!(to_json_with(&forest, &ids_only), r#"[{"id":1}]"#);
+
+        let structure = JsonOptions {
+            fields: "id,children".parse().unwrap(),
+            ..JsonOptions::default()
+        };
+        assert_eq!(
+            to_json_with(&forest, &structure),
+            r#"[{"id":1,"children":[{"id":2,"children":[]}]}]"#
+        );
+
+        let labels = JsonOptions {
+            fields: "children, label".parse().unwrap(),
+            ..JsonOptions::default()
+        };
+        assert_eq!(
+            to_json_with(&forest, &labels),
+            r#"[{"label":"root","children":[{"label":"child","children":[]}]}]"#
+        );
+    }
+
+    #[test]
+    fn pretty_prints_like_serde() {
+        let mut root = Node::new(1, "root".to_string());
+        root.metadata.insert("color".to_string(), "red".to_string());
+        let mut child = Node::new(2, "child".to_string());
+        child.add_child(as_rc_ref(Node::new(3, "grandchild".to_string())));
+        root.add_child(as_rc_ref(child));
+        root.add_child(as_rc_ref(Node::new(4, "sibling".to_string())));
+        let forest = vec![
+            as_rc_ref(root),
+            as_rc_ref(Node::new(5, "other".to_string())),
+        ];
+        let options = JsonOptions {
+            pretty: true,
+            ..JsonOptions::default()
+        };
+
+        assert_eq!(
+            to_json_with(&forest, &options),
+            serde_json::to_string_pretty(&forest).unwrap()
+        );
+        assert_eq!(to_json_with::<()>(&[], &options), "[]");
+    }
+
+    #[test]
+    fn pretty_prints_selected_fields() {
+        let mut root = Node::new(1, "root".to_string());
+        root.add_child(as_rc_ref(Node::new(2, "child".to_string())));
+        let forest = vec![as_rc_ref(root)];
+        let options = JsonOptions {
+            fields: "id".parse().unwrap(),
+            pretty: true,
+            ..JsonOptions::default()
+        };
+
+        assert_eq!(
+            to_json_with(&forest, &options),
+            "[\n  {\n    \"id\": 1\n  }\n]"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!("id,name".parse::<Fields>().is_err());
+        assert!("".parse::<Fields>().is_err());
+        assert_eq!(
+            "id,label,metadata,data,sort_key,children".parse(),
+            Ok(Fields::default())
+        );
+    }
+
+    #[test]
+    fn writes_very_deep_chain_without_overflowing() {
+        let depth = 100_000;
+        let mut node = Node::new(depth, "leaf".to_string());
+        for id in (1..depth).rev() {
+            node = Node::new_with_children(id, "link".to_string(), vec![as_rc_ref(node)]);
+        }
+        let forest = [as_rc_ref(node)];
+
+        let json = to_json(&forest);
+
+        assert!(json.starts_with(r#"[{"id":1,"label":"link","children":[{"id":2,"#));
+        assert!(json.ends_with(&format!("{}]", "]}".repeat(depth as usize))));
+
+        let msgpack = to_msgpack_with(&forest, Fields::default(), false);
+        // the leaf's empty children array is the last byte.
+        assert_eq!(msgpack.last(), Some(&0x90));
+    }
+
+    #[test]
+    fn writes_msgpack_matching_rmp_serde() {
+        let mut root = Node::new(1, "root".to_string());
+        root.metadata.insert("owner".to_string(), "sam".to_string());
+        root.add_child(as_rc_ref(Node::new(2, "child".to_string())));
+        let forest = vec![
+            as_rc_ref(root),
+            as_rc_ref(Node::new(-300, "other".to_string())),
+        ];
+
+        assert_eq!(
+            to_msgpack_with(&forest, Fields::default(), false),
+            rmp_serde::to_vec_named(&forest).unwrap()
+        );
+
+        let fields = "id,children".parse().unwrap();
+        let decoded: serde_json::Value =
+            rmp_serde::from_slice(&to_msgpack_with(&forest, fields, false)).unwrap();
+        assert_eq!(
+            decoded,
+            serde_json::json!([{"id": 1, "children": [{"id": 2, "children": []}]}, {"id": -300, "children": []}])
+        );
+    }
+
     #[test]
     fn serializes_recursive_node_tree() {
         let mut node = Node {
             id: 1,
             label: "root".to_string(),
+            metadata: HashMap::new(),
+            data: None::<()>,
+            sort_key: None,
+            updated_at: 0,
             children: vec![],
         };
         let node2 = as_rc_ref(Node {
             id: 2,
             label: "child".to_string(),
+            metadata: HashMap::new(),
+            data: None,
+            sort_key: None,
+            updated_at: 0,
             children: vec![],
         });
         node.add_child(node2);
@@ -73,4 +755,98 @@ mod test {
             r#"{"id":1,"label":"root","children":[{"id":2,"label":"child","children":[]}]}"#
         );
     }
+
+    #[derive(Clone, Default, Serialize)]
+    struct Task {
+        done: bool,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn writes_typed_payload_under_data() {
+        let task = Task {
+            done: true,
+            tags: vec!["urgent".to_string()],
+        };
+        let mut root = Node::with_data(1, "root".to_string(), task);
+        root.add_child(as_rc_ref(Node::with_data(
+            2,
+            "child".to_string(),
+            Task::default(),
+        )));
+        let forest = vec![as_rc_ref(root)];
+
+        assert_eq!(
+            to_json(&forest),
+            r#"[{"id":1,"label":"root","data":{"done":true,"tags":["urgent"]},"children":[{"id":2,"label":"child","data":{"done":false,"tags":[]},"children":[]}]}]"#
+        );
+        assert_eq!(to_json(&forest), serde_json::to_string(&forest).unwrap());
+        let pretty = JsonOptions {
+            pretty: true,
+            ..JsonOptions::default()
+        };
+        assert_eq!(
+            to_json_with(&forest, &pretty),
+            serde_json::to_string_pretty(&forest).unwrap()
+        );
+        assert_eq!(
+            to_msgpack_with(&forest, Fields::default(), false),
+            rmp_serde::to_vec_named(&forest).unwrap()
+        );
+    }
+
+    #[test]
+    fn lock_two_in_opposite_orders_does_not_deadlock() {
+        let a = as_rc_ref(Node::new(1, "a".to_string()));
+        let b = as_rc_ref(Node::new(2, "b".to_string()));
+        let (done, finished) = std::sync::mpsc::channel();
+        for (first, second) in [
+            ((1, a.clone()), (2, b.clone())),
+            ((2, b.clone()), (1, a.clone())),
+        ] {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10_000 {
+                    let (mut first, mut second) =
+                        lock_two((first.0, &first.1), (second.0, &second.1));
+                    std::mem::swap(&mut first.label, &mut second.label);
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..2 {
+            finished
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap();
+        }
+
+        let (a, b) = lock_two((1, &a), (2, &b));
+        assert_eq!((a.id, b.id), (1, 2));
+        assert_eq!((a.label.as_str(), b.label.as_str()), ("a", "b"));
+    }
+
+    #[test]
+    fn chunks_a_single_large_root_by_size() {
+        let mut root = Node::new(1, "root".to_string());
+        for id in 2..2000 {
+            root.add_child(as_rc_ref(Node::new(id, format!("child {}", id))));
+        }
+        let nodes = vec![as_rc_ref(root)];
+
+        for pretty in [false, true] {
+            let options = JsonOptions {
+                pretty,
+                ..JsonOptions::default()
+            };
+            let chunks: Vec<String> = to_json_chunks(nodes.clone(), options.clone()).collect();
+            assert!(chunks.len() > 1);
+            // a chunk stops at the first node past the limit.
+            assert!(chunks.iter().all(|chunk| chunk.len() < CHUNK_BYTES + 100));
+            assert_eq!(chunks.concat(), to_json_with(&nodes, &options));
+        }
+        assert_eq!(
+            to_json_chunks(Vec::<RcNodeRef>::new(), JsonOptions::default()).collect::<Vec<_>>(),
+            vec!["[]"]
+        );
+    }
 }
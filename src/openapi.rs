@@ -0,0 +1,633 @@
+use serde_json::{json, Map, Value};
+
+/*
+ * The OpenAPI 3 document served at GET /api/openapi.json. It is written by hand, so a route or
+ * body change in main.rs has to be mirrored here.
+ * Most errors are a plain text message, POST /api/tree answers with {"error": "..."} instead.
+ */
+pub fn document() -> Value {
+    let id = || path_parameter("id");
+    let tree = || {
+        response(
+            "The whole tree after the change",
+            json_content(schema("Forest")),
+        )
+    };
+    let text_error = |description: &str| response(description, text_content());
+
+    let mut paths = Map::new();
+    let mut add = |path: &str, method: &str, operation: Value| {
+        let item = paths.entry(path).or_insert_with(|| json!({}));
+        item[method] = operation;
+    };
+
+    add(
+        "/api/tree",
+        "get",
+        json!({
+            "summary": "The forest, nested or as an adjacency list",
+            "parameters": [
+                query_parameter("fields", "string", "Comma separated node fields, e.g. id,children"),
+                query_parameter("pretty", "boolean", "Indent the JSON"),
+                query_parameter("stream", "boolean", "Send the JSON in chunks as it is written"),
+                query_enum("format", &["nested", "adjacency"]),
+                query_enum("sort", &["key"]),
+                query_parameter("envelope", "boolean", "Wrap as {data, meta}"),
+                query_enum("empty", &["200", "404"]),
+                query_parameter(
+                    "omit_empty_children",
+                    "boolean",
+                    "Write leaves without \"children\":[]",
+                ),
+            ],
+            "responses": {
+                "200": {
+                    "description": "The forest, JSON unless Accept prefers application/msgpack",
+                    "content": {
+                        "application/json": { "schema": schema("Forest") },
+                        "application/msgpack": {},
+                    },
+                },
+                "304": { "description": "If-None-Match matched the ETag" },
+                "400": text_error("Unknown field or query value"),
+                "404": json_error("The tree is empty and empty=404 was given"),
+            },
+        }),
+    );
+    add(
+        "/api/tree",
+        "post",
+        json!({
+            "summary": "Add a node, a root when parent_id is null",
+            "parameters": [query_parameter("dry_run", "boolean", "Validate without inserting")],
+            "requestBody": request_body("AddNodeRequest"),
+            "responses": {
+                "200": tree(),
+                "400": json_error("Invalid body or label"),
+                "404": json_error("The parent does not exist"),
+                "412": text_error("If-Match names an older version"),
+                "415": json_error("Content-Type is not JSON"),
+                "429": json_error("Rate limit exceeded, see Retry-After"),
+            },
+        }),
+    );
+    add(
+        "/api/tree",
+        "delete",
+        json!({
+            "summary": "Remove nodes with their subtrees",
+            "requestBody": request_body("RemoveNodesRequest"),
+            "responses": {
+                "200": response("Every removed id", json_content(json!({}))),
+                "412": text_error("If-Match names an older version"),
+                "503": text_error("The request timed out, nothing was removed"),
+            },
+        }),
+    );
+
+    for (path, summary) in [
+        ("/api/tree/roots", "The roots and their subtree sizes"),
+        (
+            "/api/tree/snapshot",
+            "A flat snapshot for /restore and /diff",
+        ),
+        ("/api/tree/verify", "Check the internal indexes agree"),
+        ("/api/tree/ids", "Every node id"),
+        (
+            "/api/tree/nodes",
+            "Every node as id, label and parent_id, sorted by id",
+        ),
+        ("/api/tree/metrics", "Counts and depth of the tree"),
+        ("/api/tree/audit", "The most recent mutations"),
+        (
+            "/api/tree/changes",
+            "Mutations after ?since=version, or resync when they are gone",
+        ),
+        ("/api/tree/autocomplete", "Labels starting with a prefix"),
+        ("/api/tree/lca", "Lowest common ancestor of a and b"),
+        ("/api/tree/distance", "Edges between a and b"),
+        (
+            "/api/tree/can-move",
+            "Whether child could be moved under parent",
+        ),
+        ("/api/tree/subtrees", "Several branches by id"),
+        (
+            "/api/tree/by-path",
+            "The node reached by a root/child label path",
+        ),
+    ] {
+        add(path, "get", read(summary, vec![], json_content(json!({}))));
+    }
+    add(
+        "/api/tree/duplicates",
+        "get",
+        read(
+            "Ids of the nodes sharing a label, one sorted group per label",
+            vec![query_parameter(
+                "ci",
+                "boolean",
+                "Compare labels ignoring case",
+            )],
+            json_content(schema("DuplicateGroups")),
+        ),
+    );
+    add(
+        "/api/tree/search",
+        "get",
+        read(
+            "Ids of the nodes labelled exactly label, sorted",
+            vec![query_parameter(
+                "label",
+                "string",
+                "The whole label, case sensitive",
+            )],
+            json_content(
+                json!({ "type": "array", "items": { "type": "integer", "format": "int32" } }),
+            ),
+        ),
+    );
+    add(
+        "/api/tree/export/dot",
+        "get",
+        read("The tree as Graphviz DOT", vec![], text_content()),
+    );
+    add(
+        "/api/tree/export/markdown",
+        "get",
+        read("The tree as a nested Markdown list", vec![], text_content()),
+    );
+    add(
+        "/api/tree/export/ndjson",
+        "get",
+        read(
+            "Every node as a flat JSON object per line",
+            vec![],
+            json!({ "application/x-ndjson": { "schema": { "type": "string" } } }),
+        ),
+    );
+    add(
+        "/api/tree/events",
+        "get",
+        read(
+            "Server-sent TreeEvents",
+            vec![],
+            json!({ "text/event-stream": {} }),
+        ),
+    );
+    add(
+        "/api/tree/subscribe",
+        "get",
+        json!({
+            "summary": "TreeEvents over a WebSocket",
+            "responses": { "101": { "description": "Switching to the WebSocket" } },
+        }),
+    );
+
+    for (path, summary, body) in [
+        (
+            "/api/tree/swap",
+            "Swap the positions of a and b",
+            Some("SwapNodesRequest"),
+        ),
+        ("/api/tree/root", "Create the root if there is none", None),
+        ("/api/tree/prune-leaves", "Remove the current leaves", None),
+        ("/api/tree/compact", "Renumber the ids to 1..=n", None),
+        (
+            "/api/tree/reindex",
+            "Rebuild the derived indexes from the parent links",
+            None,
+        ),
+    ] {
+        add(path, "post", write(summary, body, vec![]));
+    }
+    let mut restore = write("Replace the tree with a snapshot", Some("Snapshot"), vec![]);
+    restore["responses"]["503"] = text_error("The request timed out, the tree is unchanged");
+    add("/api/tree/restore", "post", restore);
+    let mut import = write(
+        "Add a batch of nodes, all or nothing unless mode is best_effort",
+        Some("ImportRequest"),
+        vec![query_enum("mode", &["all_or_nothing", "best_effort"])],
+    );
+    import["responses"]["200"] = response(
+        "The id each keyed node got, as id_map",
+        json_content(json!({})),
+    );
+    import["responses"]["207"] = response(
+        "best_effort: the inserted ids, the failed entries by index and the id_map",
+        json_content(json!({})),
+    );
+    import["responses"]["503"] = text_error("The request timed out, nothing was imported");
+    add("/api/tree/import", "post", import);
+    add(
+        "/api/tree/diff",
+        "post",
+        json!({
+            "summary": "Compare two snapshots",
+            "requestBody": {
+                "required": true,
+                "content": json_content(json!({
+                    "type": "object",
+                    "required": ["before", "after"],
+                    "properties": { "before": schema("Snapshot"), "after": schema("Snapshot") },
+                })),
+            },
+            "responses": {
+                "200": response(
+                    "Ids added, removed, relabeled, moved and otherwise changed",
+                    json_content(json!({})),
+                ),
+                "400": text_error("A snapshot is inconsistent"),
+            },
+        }),
+    );
+    add(
+        "/api/tree/labels",
+        "patch",
+        write(
+            "Relabel several nodes at once",
+            Some("RenameNodesRequest"),
+            vec![],
+        ),
+    );
+
+    add(
+        "/api/tree/{id}",
+        "head",
+        json!({
+            "summary": "Whether the node exists",
+            "parameters": [id()],
+            "responses": {
+                "200": { "description": "It exists" },
+                "404": { "description": "It does not" },
+            },
+        }),
+    );
+    let mut move_node = write(
+        "Move a node under a parent",
+        Some("MoveToPositionRequest"),
+        vec![id()],
+    );
+    move_node["responses"]["200"] = response(
+        "The branches that changed",
+        json_content(schema("MoveResponse")),
+    );
+    add("/api/tree/{id}/position", "put", move_node);
+    add(
+        "/api/tree/{id}/reorder",
+        "post",
+        write(
+            "Move a node to the front or back of its siblings",
+            Some("ReorderRequest"),
+            vec![id()],
+        ),
+    );
+    add(
+        "/api/tree/{id}/nudge",
+        "post",
+        write(
+            "Swap a node with its previous or next sibling",
+            Some("NudgeRequest"),
+            vec![id()],
+        ),
+    );
+    add(
+        "/api/tree/{id}/metadata",
+        "patch",
+        write(
+            "Set one metadata entry",
+            Some("SetMetadataRequest"),
+            vec![id()],
+        ),
+    );
+    add(
+        "/api/tree/{id}/label",
+        "patch",
+        write("Relabel one node", Some("RenameNodeRequest"), vec![id()]),
+    );
+    add(
+        "/api/tree/{id}/clone",
+        "post",
+        write(
+            "Copy a subtree under a parent",
+            Some("CloneSubtreeRequest"),
+            vec![id()],
+        ),
+    );
+    let mut collapse_chain = write(
+        "Merge the run of single-child nodes below a node into it",
+        None,
+        vec![
+            id(),
+            query_parameter("separator", "string", "Joins the labels, / by default"),
+        ],
+    );
+    collapse_chain["responses"]["200"] =
+        response("The collapsed node", json_content(schema("Node")));
+    add("/api/tree/{id}/collapse-chain", "post", collapse_chain);
+    add(
+        "/api/tree/{id}/flatten",
+        "post",
+        write(
+            "Remove a node, its children take its place",
+            None,
+            vec![id()],
+        ),
+    );
+    add(
+        "/api/tree/{id}/touch",
+        "post",
+        write("Bump the node's updated_at", None, vec![id()]),
+    );
+    for (path, summary) in [
+        ("/api/tree/{id}/root", "The root above a node"),
+        ("/api/tree/{id}/children", "Direct children of a node"),
+        (
+            "/api/tree/{id}/siblings",
+            "Other children of the node's parent",
+        ),
+        ("/api/tree/{id}/size", "Number of nodes in the subtree"),
+        (
+            "/api/tree/{id}/locate",
+            "The node's parent and index among its siblings",
+        ),
+        (
+            "/api/tree/{id}/leaf-paths",
+            "Id paths from the node to each leaf",
+        ),
+    ] {
+        add(
+            path,
+            "get",
+            read(summary, vec![id()], json_content(json!({}))),
+        );
+    }
+    add(
+        "/api/tree/{a}/is-ancestor-of/{b}",
+        "get",
+        read(
+            "Whether a is an ancestor of b",
+            vec![path_parameter("a"), path_parameter("b")],
+            json_content(json!({})),
+        ),
+    );
+    add(
+        "/api/openapi.json",
+        "get",
+        read("This document", vec![], json_content(json!({}))),
+    );
+
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": "Tree API", "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "components": { "schemas": schemas() },
+    })
+}
+
+fn schemas() -> Value {
+    let id = json!({ "type": "integer", "format": "int32" });
+    let nullable_id = json!({ "type": "integer", "format": "int32", "nullable": true });
+    let metadata = json!({ "type": "object", "additionalProperties": { "type": "string" } });
+    json!({
+        "Node": {
+            "type": "object",
+            "properties": {
+                "id": id,
+                "label": { "type": "string" },
+                "metadata": metadata,
+                "data": {},
+                "sort_key": { "type": "integer", "format": "int64" },
+                "children": { "type": "array", "items": schema("Node") },
+            },
+        },
+        "Forest": { "type": "array", "items": schema("Node") },
+        "AddNodeRequest": {
+            "type": "object",
+            "description": "label may be left out when the server has TREE_DEFAULT_LABEL set",
+            "additionalProperties": false,
+            "properties": {
+                "label": { "type": "string" },
+                "parent_id": nullable_id,
+                "metadata": metadata,
+                "sort_key": { "type": "integer", "format": "int64", "nullable": true },
+            },
+        },
+        "MoveToPositionRequest": {
+            "type": "object",
+            "required": ["parent_id"],
+            "description": "index and after_sibling_id are exclusive, with neither the node goes last",
+            "properties": {
+                "parent_id": id,
+                "index": { "type": "integer", "minimum": 0 },
+                "after_sibling_id": nullable_id,
+            },
+        },
+        "ImportRequest": {
+            "type": "object",
+            "required": ["nodes"],
+            "properties": {
+                "nodes": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["label"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "key": { "type": "string" },
+                            "label": { "type": "string" },
+                            "parent_id": id,
+                            "parent_key": { "type": "string" },
+                            "metadata": metadata,
+                        },
+                    },
+                },
+            },
+        },
+        "RemoveNodesRequest": {
+            "type": "object",
+            "required": ["ids"],
+            "properties": { "ids": { "type": "array", "items": id } },
+        },
+        "MoveResponse": {
+            "type": "object",
+            "properties": {
+                "moved_id": id,
+                "old_parent_id": nullable_id,
+                "new_parent_id": id,
+            },
+        },
+        "ReorderRequest": {
+            "type": "object",
+            "required": ["to"],
+            "properties": { "to": { "type": "string", "enum": ["front", "back"] } },
+        },
+        "NudgeRequest": {
+            "type": "object",
+            "required": ["direction"],
+            "properties": { "direction": { "type": "string", "enum": ["up", "down"] } },
+        },
+        "SetMetadataRequest": {
+            "type": "object",
+            "required": ["key", "value"],
+            "properties": { "key": { "type": "string" }, "value": { "type": "string" } },
+        },
+        "RenameNodesRequest": {
+            "type": "object",
+            "required": ["updates"],
+            "properties": {
+                "updates": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["id", "label"],
+                        "properties": { "id": id, "label": { "type": "string" } },
+                    },
+                },
+            },
+        },
+        "RenameNodeRequest": {
+            "type": "object",
+            "required": ["label"],
+            "properties": { "label": { "type": "string" } },
+        },
+        "CloneSubtreeRequest": {
+            "type": "object",
+            "properties": { "parent_id": nullable_id },
+        },
+        "SwapNodesRequest": {
+            "type": "object",
+            "required": ["a", "b"],
+            "properties": { "a": id, "b": id },
+        },
+        "Snapshot": {
+            "type": "object",
+            "required": ["nodes"],
+            "properties": {
+                "version": { "type": "integer" },
+                "next_id": id,
+                "nodes": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["id", "label"],
+                        "properties": {
+                            "id": id,
+                            "label": { "type": "string" },
+                            "parent_id": nullable_id,
+                            "metadata": metadata,
+                            "sort_key": { "type": "integer", "format": "int64" },
+                            "data": {},
+                            "updated_at": { "type": "integer", "format": "int64" },
+                        },
+                    },
+                },
+            },
+        },
+        "DuplicateGroups": {
+            "type": "array",
+            "items": { "type": "array", "items": id, "minItems": 2 },
+        },
+        "Error": {
+            "type": "object",
+            "required": ["error"],
+            "properties": { "error": { "type": "string" } },
+        },
+    })
+}
+
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn text_content() -> Value {
+    json!({ "text/plain": { "schema": { "type": "string" } } })
+}
+
+fn response(description: &str, content: Value) -> Value {
+    json!({ "description": description, "content": content })
+}
+
+fn json_error(description: &str) -> Value {
+    response(description, json_content(schema("Error")))
+}
+
+fn request_body(name: &str) -> Value {
+    json!({ "required": true, "content": json_content(schema(name)) })
+}
+
+fn path_parameter(name: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": { "type": "integer", "format": "int32" },
+    })
+}
+
+fn query_parameter(name: &str, kind: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "query", "description": description, "schema": { "type": kind } })
+}
+
+fn query_enum(name: &str, values: &[&str]) -> Value {
+    json!({ "name": name, "in": "query", "schema": { "type": "string", "enum": values } })
+}
+
+// A GET answering 200 with content, 404 when a path id is missing.
+fn read(summary: &str, parameters: Vec<Value>, content: Value) -> Value {
+    let mut operation = json!({
+        "summary": summary,
+        "parameters": parameters,
+        "responses": {
+            "200": response("OK", content),
+            "400": response("Invalid query", text_content()),
+        },
+    });
+    if !parameters.is_empty() {
+        operation["responses"]["404"] = response("The node does not exist", text_content());
+    }
+    operation
+}
+
+// A mutation, conditional on If-Match, answering with the tree or the changed node.
+fn write(summary: &str, body: Option<&str>, parameters: Vec<Value>) -> Value {
+    let mut operation = json!({
+        "summary": summary,
+        "parameters": parameters,
+        "responses": {
+            "200": response("The changed tree or node", json_content(json!({}))),
+            "400": response("The change was rejected", text_content()),
+            "404": response("A referenced node does not exist", text_content()),
+            "412": response("If-Match names an older version", text_content()),
+        },
+    });
+    if let Some(body) = body {
+        operation["requestBody"] = request_body(body);
+    }
+    operation
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn references_resolve() {
+        let document = document();
+        let text = document.to_string();
+        for (index, _) in text.match_indices("#/components/schemas/") {
+            let name: String = text[index + "#/components/schemas/".len()..]
+                .chars()
+                .take_while(|c| c.is_alphanumeric())
+                .collect();
+            assert!(
+                document["components"]["schemas"].get(&name).is_some(),
+                "missing schema {}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/*
+ * RateLimiter is a per client ip token bucket.
+ * Each bucket holds up to one second worth of requests and refills at `rate` tokens a second.
+ * It is shared by every worker, like the TreeStore it has to be created outside of the
+ * HttpServer closure.
+ */
+pub struct RateLimiter {
+    // requests per second, None disables limiting.
+    rate: Option<f64>,
+    buckets: Mutex<HashMap<Option<IpAddr>, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Past this many tracked clients, buckets that have refilled completely are dropped.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+impl RateLimiter {
+    pub fn new(rate: Option<f64>) -> Self {
+        RateLimiter {
+            rate: rate.filter(|rate| *rate > 0.0),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a token for the client, or returns how long until one is available.
+    pub fn check(&self, client: Option<IpAddr>) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        let Some(rate) = self.rate else {
+            return Ok(());
+        };
+        let capacity = rate.max(1.0);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < capacity
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    #[test]
+    fn unlimited_without_rate() {
+        let limiter = RateLimiter::new(None);
+        for _ in 0..100 {
+            assert!(limiter.check(CLIENT).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_past_burst_then_refills() {
+        let limiter = RateLimiter::new(Some(2.0));
+        let now = Instant::now();
+
+        assert!(limiter.check_at(CLIENT, now).is_ok());
+        assert!(limiter.check_at(CLIENT, now).is_ok());
+        let retry_after = limiter.check_at(CLIENT, now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+
+        assert!(limiter
+            .check_at(CLIENT, now + Duration::from_millis(500))
+            .is_ok());
+    }
+
+    #[test]
+    fn tracks_clients_separately() {
+        let limiter = RateLimiter::new(Some(1.0));
+        let other = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let now = Instant::now();
+
+        assert!(limiter.check_at(CLIENT, now).is_ok());
+        assert!(limiter.check_at(CLIENT, now).is_err());
+        assert!(limiter.check_at(other, now).is_ok());
+    }
+}
@@ -0,0 +1,133 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error,
+};
+use futures_util::future::LocalBoxFuture;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer incoming ids are replaced rather than copied into every log line.
+const MAX_LEN: usize = 128;
+
+// A random (version 4) uuid such as "7f1c0c9e-3d6b-4c1a-9f0e-2b8d5a6e4c21".
+fn new_uuid() -> String {
+    let bits = rand::random::<u128>() & !(0xf << 76) & !(0x3 << 62) | (0x4 << 76) | (0x2 << 62);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// The id of the request being handled, None outside a request. The logger adds it to each line.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+/*
+ * RequestId takes the request's X-Request-Id, or a new uuid when it has none (or an unusable
+ * one), and echoes it on the response. The id is task local while the request is handled so log
+ * lines written on its behalf carry it, requests interleaved on the same worker thread each see
+ * their own.
+ */
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // to_str only accepts visible ascii, so the id is always a valid header value back.
+        let id = req
+            .headers()
+            .get(&X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|id| !id.is_empty() && id.len() <= MAX_LEN)
+            .map_or_else(new_uuid, str::to_string);
+        // the inner services start work in call as well as in the future it returns.
+        let response = REQUEST_ID.sync_scope(id.clone(), || self.service.call(req));
+        Box::pin(async move {
+            let mut response = REQUEST_ID.scope(id.clone(), response).await?;
+            response
+                .headers_mut()
+                .insert(X_REQUEST_ID, HeaderValue::from_str(&id).unwrap());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn echo_current() -> HttpResponse {
+        HttpResponse::Ok().body(current().unwrap_or_default())
+    }
+
+    #[actix_rt::test]
+    async fn handlers_see_the_request_id() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestId)
+                .route("/", web::get().to(echo_current)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((X_REQUEST_ID, "abc-123"))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "abc-123");
+        assert!(current().is_none());
+
+        // too long to be copied, a new one is made.
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((X_REQUEST_ID, "x".repeat(MAX_LEN + 1)))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        let id = response.headers().get(&X_REQUEST_ID).unwrap().clone();
+        assert_eq!(id.len(), 36);
+        assert_eq!(test::read_body(response).await, id.as_bytes());
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    future::{ready, Ready},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error::ErrorServiceUnavailable,
+    web, Error, HttpMessage,
+};
+use futures_util::future::LocalBoxFuture;
+
+/*
+ * Timeout answers 503 when the wrapped service hasn't produced a response within the duration,
+ * dropping the handler's future. That abandons work waiting at an await point, a handler that is
+ * busy without yielding (holding the tree lock for instance) can't be cut off from outside, so
+ * the request also carries its Deadline for the bulk writes to check as they go.
+ * Streaming bodies such as /events are not limited, only the time to the response head is.
+ */
+// When the request's time is up, in the request extensions whenever a timeout is configured.
+#[derive(Clone, Copy)]
+pub struct Deadline(pub Instant);
+
+impl Deadline {
+    // The handler's Option<web::ReqData<Deadline>> as the instant the tree's bulk writes take.
+    pub fn of(deadline: Option<web::ReqData<Deadline>>) -> Option<Instant> {
+        deadline.map(|deadline| deadline.0)
+    }
+}
+
+pub struct Timeout {
+    // None disables the timeout.
+    duration: Option<Duration>,
+}
+
+impl Timeout {
+    pub fn new(duration: Option<Duration>) -> Self {
+        Timeout { duration }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Timeout
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = TimeoutMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TimeoutMiddleware {
+            service,
+            duration: self.duration,
+        }))
+    }
+}
+
+pub struct TimeoutMiddleware<S> {
+    service: S,
+    duration: Option<Duration>,
+}
+
+impl<S, B> Service<ServiceRequest> for TimeoutMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let duration = self.duration;
+        if let Some(duration) = duration {
+            req.extensions_mut()
+                .insert(Deadline(Instant::now() + duration));
+        }
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let Some(duration) = duration else {
+                return response.await;
+            };
+            // the request went to the service so there is none to build a response from, actix
+            // renders the error as the 503.
+            actix_rt::time::timeout(duration, response)
+                .await
+                .unwrap_or_else(|_| Err(ErrorServiceUnavailable("Request timed out")))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn slow() -> HttpResponse {
+        actix_rt::time::sleep(Duration::from_millis(200)).await;
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn returns_503_past_the_timeout() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Timeout::new(Some(Duration::from_millis(20))))
+                .route("/slow", web::get().to(slow))
+                .route("/fast", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/slow").to_request();
+        let error = test::try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), 503);
+        let req = test::TestRequest::get().uri("/fast").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_rt::test]
+    async fn waits_without_a_duration() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Timeout::new(None))
+                .route("/slow", web::get().to(slow)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/slow").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+}
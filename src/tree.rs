@@ -1,223 +1,4596 @@
-use crate::node::{as_rc_ref, Node, RcNodeRef};
+use crate::{
+    config::{AutoRoot, TreeConfig},
+    node::{as_rc_ref, lock_two, now_millis, Node, Payload, RcNodeRef},
+};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+pub struct Tree<T = ()> {
+    next_id: i32,
+    // For now this will double as a in-memory store, where the node id is 1 + the node's index.
+    lookup: HashMap<i32, RcNodeRef<T>>,
+    // enforcing that child can only have one parent, to prevent the tree from becoming a graph.
+    // If this was a database we would have a unique constraint on the child_id column.
+    // Its faster to track at insertion time than to check on every query.
+    child_to_parent: HashMap<i32, i32>,
+    parent_to_child: HashMap<i32, Vec<i32>>,
+    // label -> ids of every node carrying it, for exact label lookups without a scan. Behind its
+    // own mutex because rename_node relabels under the store's read lock.
+    by_label: Mutex<HashMap<String, HashSet<i32>>>,
+    config: TreeConfig,
+}
+
+impl Default for Tree {
+    fn default() -> Self {
+        Tree::with_config(TreeConfig::default())
+    }
+}
+
+#[derive(Debug)]
+pub enum AddNodeError {
+    ParentNotFound(i32),
+    AlreadyHasParent(i32),
+    SelfLoop(i32),
+    EmptyLabel,
+    InvalidLabel(String),
+    TreeFull { max_nodes: usize },
+    TooManyRoots { max_roots: usize },
+    TooDeep { parent_id: i32, max_depth: usize },
+    ParentFull { parent_id: i32, max_children: usize },
+    LabelTaken { parent_id: i32, label: String },
+    RootExists(i32),
+    IdsExhausted,
+    LockPoisoned,
+}
+
+impl fmt::Display for AddNodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddNodeError::ParentNotFound(parent_id) => write!(
+                f,
+                "Cannot add connection, parent {} does not exist",
+                parent_id
+            ),
+            AddNodeError::AlreadyHasParent(id) => {
+                write!(
+                    f,
+                    "Cannot add connection, child {} already has a parent",
+                    id
+                )
+            }
+            AddNodeError::SelfLoop(id) => write!(
+                f,
+                "Cannot add connection, parent and child are the same node: {}",
+                id
+            ),
+            AddNodeError::EmptyLabel => write!(f, "Cannot add node, label must not be empty"),
+            AddNodeError::InvalidLabel(label) => write!(
+                f,
+                "Cannot add node, label {:?} contains characters that are not allowed",
+                label
+            ),
+            AddNodeError::TreeFull { max_nodes } => write!(
+                f,
+                "Cannot add node, tree has reached its maximum size of {}",
+                max_nodes
+            ),
+            AddNodeError::TooManyRoots { max_roots } => write!(
+                f,
+                "Cannot add root, tree already has the maximum of {} roots",
+                max_roots
+            ),
+            AddNodeError::TooDeep {
+                parent_id,
+                max_depth,
+            } => write!(
+                f,
+                "Cannot add node under {}, tree would exceed its maximum depth of {}",
+                parent_id, max_depth
+            ),
+            AddNodeError::ParentFull {
+                parent_id,
+                max_children,
+            } => write!(
+                f,
+                "Cannot add node under {}, it already has the maximum of {} children",
+                parent_id, max_children
+            ),
+            AddNodeError::LabelTaken { parent_id, label } => write!(
+                f,
+                "Cannot add node, parent {} already has a child labelled {:?}",
+                parent_id, label
+            ),
+            AddNodeError::RootExists(root_id) => write!(
+                f,
+                "Cannot add another root, the tree has root {}, give a parent_id",
+                root_id
+            ),
+            AddNodeError::IdsExhausted => {
+                write!(f, "Cannot add node, the tree has run out of node ids")
+            }
+            AddNodeError::LockPoisoned => write!(f, "failed to get lock"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MoveError {
+    NodeNotFound(i32),
+    ParentNotFound(i32),
+    Cycle { id: i32, parent_id: i32 },
+    ParentFull { parent_id: i32, max_children: usize },
+    IsRoot(i32),
+    NotASibling { parent_id: i32, sibling_id: i32 },
+    LabelTaken { parent_id: i32, label: String },
+    TooDeep { parent_id: i32, max_depth: usize },
+    LockPoisoned,
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoveError::NodeNotFound(id) => {
+                write!(f, "Cannot move node, node {} does not exist", id)
+            }
+            MoveError::ParentNotFound(parent_id) => {
+                write!(f, "Cannot move node, parent {} does not exist", parent_id)
+            }
+            MoveError::Cycle { id, parent_id } => write!(
+                f,
+                "Cannot move node {} under {}, it would create a cycle",
+                id, parent_id
+            ),
+            MoveError::ParentFull {
+                parent_id,
+                max_children,
+            } => write!(
+                f,
+                "Cannot move node, parent {} already has the maximum of {} children",
+                parent_id, max_children
+            ),
+            MoveError::IsRoot(id) => {
+                write!(f, "Cannot reorder node {}, it is a root", id)
+            }
+            MoveError::NotASibling {
+                parent_id,
+                sibling_id,
+            } => write!(
+                f,
+                "Cannot move node after {}, it is not a child of {}",
+                sibling_id, parent_id
+            ),
+            MoveError::LabelTaken { parent_id, label } => write!(
+                f,
+                "Cannot move node, parent {} already has a child labelled {:?}",
+                parent_id, label
+            ),
+            MoveError::TooDeep {
+                parent_id,
+                max_depth,
+            } => write!(
+                f,
+                "Cannot move node under {}, tree would exceed its maximum depth of {}",
+                parent_id, max_depth
+            ),
+            MoveError::LockPoisoned => write!(f, "failed to get lock"),
+        }
+    }
+}
+
+// Which end of its siblings a node is moved to by Tree::reorder.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SiblingEnd {
+    Front,
+    Back,
+}
+
+// Which neighbour a node trades places with in Tree::nudge, up is towards the front.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+// index is the position of the rejected entry in the import.
+#[derive(Debug)]
+pub enum ImportError {
+    DuplicateKey { index: usize, key: String },
+    UnknownKey { index: usize, key: String },
+    TwoParents(usize),
+    TooDeep { index: usize, max_depth: usize },
+    TimedOut(usize),
+    Add { index: usize, error: AddNodeError },
+}
+
+impl ImportError {
+    pub fn index(&self) -> usize {
+        match self {
+            ImportError::DuplicateKey { index, .. }
+            | ImportError::UnknownKey { index, .. }
+            | ImportError::TwoParents(index)
+            | ImportError::TooDeep { index, .. }
+            | ImportError::TimedOut(index)
+            | ImportError::Add { index, .. } => *index,
+        }
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::DuplicateKey { index, key } => write!(
+                f,
+                "Cannot import node {}, key {:?} is already used",
+                index, key
+            ),
+            ImportError::UnknownKey { index, key } => write!(
+                f,
+                "Cannot import node {}, parent_key {:?} does not name an earlier node",
+                index, key
+            ),
+            ImportError::TwoParents(index) => write!(
+                f,
+                "Cannot import node {}, give parent_id or parent_key, not both",
+                index
+            ),
+            ImportError::TooDeep { index, max_depth } => write!(
+                f,
+                "Cannot import node {}, tree would exceed its maximum depth of {}",
+                index, max_depth
+            ),
+            ImportError::TimedOut(index) => {
+                write!(f, "Cannot import node {}, the request timed out", index)
+            }
+            ImportError::Add { index, error } => {
+                write!(f, "Cannot import node {}: {}", index, error)
+            }
+        }
+    }
+}
+
+// How many entries name each key as their parent_key, so the keyed node's children can be
+// allocated once.
+fn import_child_counts(nodes: &[ImportNode]) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    for parent_key in nodes.iter().filter_map(|node| node.parent_key.clone()) {
+        *counts.entry(parent_key).or_default() += 1;
+    }
+    counts
+}
+
+#[derive(Debug)]
+pub enum FlattenError {
+    NodeNotFound(i32),
+    IsRoot(i32),
+    ParentFull { parent_id: i32, max_children: usize },
+    LabelTaken { parent_id: i32, label: String },
+}
+
+impl fmt::Display for FlattenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlattenError::NodeNotFound(id) => {
+                write!(f, "Cannot flatten node, node {} does not exist", id)
+            }
+            FlattenError::IsRoot(id) => {
+                write!(f, "Cannot flatten node {}, it is a root", id)
+            }
+            FlattenError::ParentFull {
+                parent_id,
+                max_children,
+            } => write!(
+                f,
+                "Cannot flatten node, parent {} would exceed its maximum of {} children",
+                parent_id, max_children
+            ),
+            FlattenError::LabelTaken { parent_id, label } => write!(
+                f,
+                "Cannot flatten node, parent {} already has a child labelled {:?}",
+                parent_id, label
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TouchError {
+    NodeNotFound(i32),
+}
+
+impl fmt::Display for TouchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TouchError::NodeNotFound(id) => {
+                write!(f, "Cannot touch node, node {} does not exist", id)
+            }
+        }
+    }
+}
+
+// The segment is the label that matched no node, or more than one sibling.
+#[derive(Debug)]
+pub enum PathError {
+    Empty,
+    NotFound(String),
+    Ambiguous(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::Empty => write!(f, "Cannot resolve path, it has no segments"),
+            PathError::NotFound(segment) => {
+                write!(f, "Cannot resolve path, no node labelled {:?}", segment)
+            }
+            PathError::Ambiguous(segment) => write!(
+                f,
+                "Cannot resolve path, more than one node labelled {:?}",
+                segment
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CollapseError {
+    NodeNotFound(i32),
+    InvalidLabel(i32),
+    LabelTaken { parent_id: i32, label: String },
+}
+
+impl fmt::Display for CollapseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CollapseError::NodeNotFound(id) => {
+                write!(f, "Cannot collapse chain, node {} does not exist", id)
+            }
+            CollapseError::InvalidLabel(id) => write!(
+                f,
+                "Cannot collapse chain at {}, the joined label is not allowed",
+                id
+            ),
+            CollapseError::LabelTaken { parent_id, label } => write!(
+                f,
+                "Cannot collapse chain, parent {} already has a child labelled {:?}",
+                parent_id, label
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CloneError {
+    NodeNotFound(i32),
+    ParentNotFound(i32),
+    LimitExceeded(String),
+    LabelTaken { parent_id: i32, label: String },
+    RootExists(i32),
+    LockPoisoned,
+}
+
+impl fmt::Display for CloneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloneError::NodeNotFound(id) => {
+                write!(f, "Cannot clone subtree, node {} does not exist", id)
+            }
+            CloneError::ParentNotFound(parent_id) => write!(
+                f,
+                "Cannot clone subtree, parent {} does not exist",
+                parent_id
+            ),
+            CloneError::LimitExceeded(message) => write!(f, "Cannot clone subtree, {}", message),
+            CloneError::LabelTaken { parent_id, label } => write!(
+                f,
+                "Cannot clone subtree, parent {} already has a child labelled {:?}",
+                parent_id, label
+            ),
+            CloneError::RootExists(root_id) => write!(
+                f,
+                "Cannot clone subtree as another root, the tree has root {}, give a parent_id",
+                root_id
+            ),
+            CloneError::LockPoisoned => write!(f, "failed to get lock"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SwapError {
+    NodeNotFound(i32),
+    IsAncestor { ancestor: i32, descendant: i32 },
+    LabelTaken { parent_id: i32, label: String },
+    TooDeep { id: i32, max_depth: usize },
+    LockPoisoned,
+}
+
+impl fmt::Display for SwapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwapError::NodeNotFound(id) => {
+                write!(f, "Cannot swap nodes, node {} does not exist", id)
+            }
+            SwapError::IsAncestor {
+                ancestor,
+                descendant,
+            } => write!(
+                f,
+                "Cannot swap nodes, {} is an ancestor of {}",
+                ancestor, descendant
+            ),
+            SwapError::LabelTaken { parent_id, label } => write!(
+                f,
+                "Cannot swap nodes, parent {} already has a child labelled {:?}",
+                parent_id, label
+            ),
+            SwapError::TooDeep { id, max_depth } => write!(
+                f,
+                "Cannot swap nodes, node {} would end up past the maximum depth of {}",
+                id, max_depth
+            ),
+            SwapError::LockPoisoned => write!(f, "failed to get lock"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EnsureRootError {
+    MultipleRoots(Vec<i32>),
+    AddNode(AddNodeError),
+    LockPoisoned,
+}
+
+impl fmt::Display for EnsureRootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnsureRootError::MultipleRoots(root_ids) => write!(
+                f,
+                "Cannot pick a root, the tree has multiple roots: {:?}",
+                root_ids
+            ),
+            EnsureRootError::AddNode(error) => write!(f, "{}", error),
+            EnsureRootError::LockPoisoned => write!(f, "failed to get lock"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RestoreError {
+    DuplicateId(i32),
+    // the parent must appear earlier in the snapshot than its children.
+    ParentNotFound { id: i32, parent_id: i32 },
+    LimitExceeded(String),
+    // ids at i32::MAX leave no room for the next one.
+    IdOutOfRange(i32),
+    TimedOut,
+    LockPoisoned,
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreError::DuplicateId(id) => {
+                write!(f, "Cannot restore snapshot, node {} appears twice", id)
+            }
+            RestoreError::ParentNotFound { id, parent_id } => write!(
+                f,
+                "Cannot restore snapshot, parent {} of node {} does not precede it",
+                parent_id, id
+            ),
+            RestoreError::LimitExceeded(message) => {
+                write!(f, "Cannot restore snapshot, {}", message)
+            }
+            RestoreError::IdOutOfRange(id) => write!(
+                f,
+                "Cannot restore snapshot, id {} leaves no room for another node",
+                id
+            ),
+            RestoreError::TimedOut => write!(f, "Cannot restore snapshot, the request timed out"),
+            RestoreError::LockPoisoned => write!(f, "failed to get lock"),
+        }
+    }
+}
+
+// A bulk write gave up because the request's deadline passed, the tree is left as it was.
+#[derive(Debug)]
+pub struct DeadlinePassed;
+
+impl fmt::Display for DeadlinePassed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cannot finish the change, the request timed out")
+    }
+}
+
+fn passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+#[derive(Debug)]
+pub enum RenameError {
+    NodeNotFound(i32),
+    EmptyLabel(i32),
+    InvalidLabel(i32),
+    LabelTaken { parent_id: i32, label: String },
+    LockPoisoned,
+}
+
+impl fmt::Display for RenameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenameError::NodeNotFound(id) => {
+                write!(f, "Cannot rename node, node {} does not exist", id)
+            }
+            RenameError::EmptyLabel(id) => {
+                write!(f, "Cannot rename node {}, label must not be empty", id)
+            }
+            RenameError::InvalidLabel(id) => write!(
+                f,
+                "Cannot rename node {}, label contains characters that are not allowed",
+                id
+            ),
+            RenameError::LabelTaken { parent_id, label } => write!(
+                f,
+                "Cannot rename node, parent {} would have two children labelled {:?}",
+                parent_id, label
+            ),
+            RenameError::LockPoisoned => write!(f, "failed to get lock"),
+        }
+    }
+}
+
+// One entry of a bulk rename.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct LabelUpdate {
+    pub id: i32,
+    pub label: String,
+}
+
+// Backslash escapes every character CommonMark lets a backslash escape.
+fn escape_markdown(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        if c.is_ascii_punctuation() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Fill colors for DOT nodes by depth, roots take the first and deeper levels cycle through.
+const DEPTH_PALETTE: [&str; 5] = ["lightblue", "palegreen", "khaki", "lightsalmon", "plum"];
+
+/*
+ * Snapshot is a point-in-time copy of the whole forest.
+ * Nodes are flat and in pre-order (roots by id, children in order) so restoring a deep tree
+ * doesn't recurse, next_id is kept so ids handed out before the snapshot are never reused.
+ * Each node carries all of its state, restoring gives back the same payloads and timestamps.
+ */
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Snapshot<T = ()> {
+    pub next_id: i32,
+    pub nodes: Vec<SnapshotNode<T>>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SnapshotNode<T = ()> {
+    pub id: i32,
+    pub label: String,
+    pub parent_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort_key: Option<i64>,
+    // a missing Option is read as None without serde(default), which would want T: Default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+    // missing in a hand written snapshot, the restored node is then stamped with the restore time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
+}
+
+// The forest as flat lists for graph libraries, nodes in id order and edges as [parent, child]
+// grouped by parent id with each parent's children in order.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Adjacency {
+    pub nodes: Vec<AdjacencyNode>,
+    pub edges: Vec<(i32, i32)>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AdjacencyNode {
+    pub id: i32,
+    pub label: String,
+}
+
+// One node of a bulk import. key names it for later entries' parent_key and for the id_map,
+// parent_id points at a node already in the tree. With neither parent the node is a root.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportNode {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_key: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+// What an import added, ids in insertion order and the server id for every keyed node.
+#[derive(Debug, Default, PartialEq)]
+pub struct Imported {
+    pub ids: Vec<i32>,
+    pub id_map: HashMap<String, i32>,
+}
+
+// One row of the flat node listing, a root has a null parent_id.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FlatNode {
+    pub id: i32,
+    pub label: String,
+    pub parent_id: Option<i32>,
+}
+
+// Node ids that differ between two trees, each list sorted.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct TreeDiff {
+    pub added: Vec<i32>,
+    pub removed: Vec<i32>,
+    pub relabeled: Vec<i32>,
+    // ids in both trees whose parent changed.
+    pub moved: Vec<i32>,
+    // ids in both trees whose payload, metadata or sort key changed.
+    pub changed: Vec<i32>,
+}
+
+impl Tree {
+    pub fn with_config(config: TreeConfig) -> Self {
+        Tree::typed(config)
+    }
+}
+
+// Trees carrying a payload type are built with Tree::<T>::typed, the plain constructors above
+// are for the unit payload so callers don't have to name a type.
+impl<T: Payload> Tree<T> {
+    pub fn typed(config: TreeConfig) -> Self {
+        Tree {
+            child_to_parent: HashMap::new(),
+            parent_to_child: HashMap::new(),
+            lookup: HashMap::new(),
+            by_label: Mutex::new(HashMap::new()),
+            next_id: 1,
+            config,
+        }
+    }
+
+    pub fn add_node(
+        &mut self,
+        label: String,
+        parent_id: Option<i32>,
+    ) -> Result<RcNodeRef<T>, AddNodeError> {
+        self.add_node_with_metadata(label, parent_id, HashMap::new())
+    }
+
+    pub fn add_node_with_metadata(
+        &mut self,
+        label: String,
+        parent_id: Option<i32>,
+        metadata: HashMap<String, String>,
+    ) -> Result<RcNodeRef<T>, AddNodeError> {
+        self.insert_node(label, parent_id, metadata, None, 0, false)
+    }
+
+    // add_node_with_metadata that also sets the node's sort_key.
+    pub fn add_sorted_node(
+        &mut self,
+        label: String,
+        parent_id: Option<i32>,
+        metadata: HashMap<String, String>,
+        sort_key: Option<i64>,
+    ) -> Result<RcNodeRef<T>, AddNodeError> {
+        let node = self.insert_node(label, parent_id, metadata, None, 0, false)?;
+        node.lock().unwrap().sort_key = sort_key;
+        Ok(node)
+    }
+
+    // depth_checked skips the max_depth walk for bulk callers that worked out every depth in one
+    // pass beforehand.
+    fn insert_node(
+        &mut self,
+        label: String,
+        parent_id: Option<i32>,
+        metadata: HashMap<String, String>,
+        data: Option<T>,
+        capacity: usize,
+        depth_checked: bool,
+    ) -> Result<RcNodeRef<T>, AddNodeError> {
+        let label = self.check_add(&label, parent_id, !depth_checked)?;
+        let parent_id = self.parent_for_add(parent_id)?;
+
+        // staged: everything that can fail or panic happens before the first map is touched, so
+        // neither an error nor a panic leaves the tree half changed. add_edge locks the parent
+        // before it changes anything and the steps after it can't fail, see labels().
+        let id = self.next_id;
+        let next_id = id.checked_add(1).ok_or(AddNodeError::IdsExhausted)?;
+        let mut node = Node::with_data_and_capacity(id, label, data, capacity);
+        node.metadata = metadata;
+        let label = node.label.clone();
+        let node = as_rc_ref(node);
+        if let Some(parent_id) = parent_id {
+            self.add_edge(parent_id, node.clone())?;
+        }
+        self.index_label(id, label);
+        self.lookup.insert(id, node.clone());
+        self.next_id = next_id;
+        Ok(node)
+    }
+
+    // The label index. Its critical sections are single map operations that can't panic part
+    // way, so a poisoned lock still guards a consistent index and the poison is ignored, which
+    // keeps the steps that touch it from panicking.
+    fn labels(&self) -> MutexGuard<'_, HashMap<String, HashSet<i32>>> {
+        self.by_label.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn index_label(&self, id: i32, label: String) {
+        self.labels().entry(label).or_default().insert(id);
+    }
+
+    fn unindex_label(&self, id: i32, label: &str) {
+        let mut by_label = self.labels();
+        if let Some(ids) = by_label.get_mut(label) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                by_label.remove(label);
+            }
+        }
+    }
+
+    // Every label change goes through here to keep by_label in step. The node stays locked until
+    // the index is updated, so two renames of the same node can't leave it under both labels.
+    fn relabel(&self, node: &RcNodeRef<T>, label: String) {
+        let mut node = node.lock().unwrap();
+        self.unindex_label(node.id, &node.label);
+        self.index_label(node.id, label.clone());
+        node.label = label;
+    }
+
+    // Drops the node from lookup and the label index, its edges are the caller's job.
+    fn forget(&mut self, id: i32) {
+        if let Some(node) = self.lookup.remove(&id) {
+            let node = node.lock().unwrap();
+            self.unindex_label(id, &node.label);
+        }
+    }
+
+    // Every check add_node makes, without touching the tree. Returns the label as it would be
+    // stored.
+    pub fn validate_add(
+        &self,
+        label: &str,
+        parent_id: Option<i32>,
+    ) -> Result<String, AddNodeError> {
+        self.check_add(label, parent_id, true)
+    }
+
+    fn check_add(
+        &self,
+        label: &str,
+        parent_id: Option<i32>,
+        check_depth: bool,
+    ) -> Result<String, AddNodeError> {
+        // normalized first so a whitespace only label counts as empty.
+        let label = self.normalize_label(label);
+        if label.is_empty() {
+            return Err(AddNodeError::EmptyLabel);
+        }
+        if !self.is_allowed_label(&label) {
+            return Err(AddNodeError::InvalidLabel(label));
+        }
+        let parent_id = self.parent_for_add(parent_id)?;
+
+        // checked before the id is allocated so a rejected insert doesn't burn an id.
+        if let Some(max_nodes) = self.config.max_nodes {
+            if self.lookup.len() >= max_nodes {
+                return Err(AddNodeError::TreeFull { max_nodes });
+            }
+        }
+
+        if let (Some(max_roots), None) = (self.config.max_roots, parent_id) {
+            // every node with a parent has an entry in child_to_parent, the rest are roots.
+            if self.lookup.len() - self.child_to_parent.len() >= max_roots {
+                return Err(AddNodeError::TooManyRoots { max_roots });
+            }
+        }
+
+        if let (Some(max_depth), Some(parent_id), true) =
+            (self.config.max_depth, parent_id, check_depth)
+        {
+            if self.lookup.contains_key(&parent_id) && self.depth(parent_id) + 1 > max_depth {
+                return Err(AddNodeError::TooDeep {
+                    parent_id,
+                    max_depth,
+                });
+            }
+        }
+
+        if let Some(parent_id) = parent_id {
+            if let Some(max_children) = self.is_full(parent_id) {
+                return Err(AddNodeError::ParentFull {
+                    parent_id,
+                    max_children,
+                });
+            }
+            if self.sibling_label_taken(parent_id, &label) {
+                return Err(AddNodeError::LabelTaken { parent_id, label });
+            }
+            if !self.lookup.contains_key(&parent_id) {
+                return Err(AddNodeError::ParentNotFound(parent_id));
+            }
+        }
+
+        Ok(label)
+    }
+
+    // Where a node asked to go under parent_id goes, only differs under auto_root once the tree
+    // has a root. An emptied tree (restored from an empty snapshot) can take a new root.
+    fn parent_for_add(&self, parent_id: Option<i32>) -> Result<Option<i32>, AddNodeError> {
+        let (None, Some(auto_root)) = (parent_id, self.config.auto_root) else {
+            return Ok(parent_id);
+        };
+        match (self.first_root(), auto_root) {
+            (None, _) => Ok(None),
+            (Some(root_id), AutoRoot::Reject) => Err(AddNodeError::RootExists(root_id)),
+            (Some(root_id), AutoRoot::Attach) => Ok(Some(root_id)),
+        }
+    }
+
+    // The lowest root id. auto_root keeps a single root, so this walks up from any node instead
+    // of scanning for every root. Only a restored snapshot with several roots takes the scan.
+    fn first_root(&self) -> Option<i32> {
+        if self.lookup.len() - self.child_to_parent.len() > 1 {
+            return self.root_ids().first().copied();
+        }
+        let mut current = *self.lookup.keys().next()?;
+        while let Some(&parent_id) = self.child_to_parent.get(&current) {
+            current = parent_id;
+        }
+        Some(current)
+    }
+
+    pub fn config(&self) -> &TreeConfig {
+        &self.config
+    }
+
+    // The id the next added node will get.
+    pub fn next_id(&self) -> i32 {
+        self.next_id
+    }
+
+    fn normalize_label(&self, label: &str) -> String {
+        if self.config.normalize_labels {
+            label.split_whitespace().collect::<Vec<_>>().join(" ")
+        } else {
+            label.trim().to_string()
+        }
+    }
+
+    // Always true unless label_pattern is set.
+    fn is_allowed_label(&self, label: &str) -> bool {
+        self.config
+            .label_pattern
+            .as_ref()
+            .is_none_or(|pattern| !label.chars().any(char::is_control) && pattern.is_match(label))
+    }
+
+    // The max_children limit when the node has reached it, None while it can take another child.
+    fn is_full(&self, id: i32) -> Option<usize> {
+        let max_children = self.config.max_children?;
+        let children = self.parent_to_child.get(&id).map_or(0, Vec::len);
+        (children >= max_children).then_some(max_children)
+    }
+
+    // Always false unless unique_sibling_labels is on.
+    fn sibling_label_taken(&self, parent_id: i32, label: &str) -> bool {
+        self.config.unique_sibling_labels
+            && self
+                .parent_to_child
+                .get(&parent_id)
+                .is_some_and(|children| {
+                    children
+                        .iter()
+                        .any(|child_id| self.lookup[child_id].lock().unwrap().label == label)
+                })
+    }
+
+    fn add_edge(&mut self, parent_id: i32, child_ref: RcNodeRef<T>) -> Result<(), AddNodeError> {
+        let child = child_ref.lock().unwrap();
+        if parent_id == child.id {
+            return Err(AddNodeError::SelfLoop(parent_id));
+        }
+
+        if self.child_to_parent.contains_key(&child.id) {
+            return Err(AddNodeError::AlreadyHasParent(child.id));
+        }
+
+        // we could turn this into a map lookup by changing the way we store nodes from a vec to a
+        // hashmap
+        if !self.lookup.contains_key(&parent_id) {
+            return Err(AddNodeError::ParentNotFound(parent_id));
+        };
+
+        // locked before any map changes, it panics if the parent's mutex is poisoned.
+        let mut parent = self.lookup[&parent_id].lock().unwrap();
+        self.child_to_parent.insert(child.id, parent_id);
+        parent.add_child(child_ref.clone());
+        self.parent_to_child
+            .entry(parent_id)
+            .or_insert(vec![])
+            .push(child.id);
+        Ok(())
+    }
+
+    // Moves the node (and with it its subtree) under parent_id at the given index among the new
+    // siblings. The index is clamped to the end of the sibling list.
+    // Only the old and new parent's child lists and the node's two map entries are rewritten,
+    // the subtree itself is untouched. Returns the previous parent, None when the node was a root.
+    pub fn move_node(
+        &mut self,
+        id: i32,
+        parent_id: i32,
+        index: usize,
+    ) -> Result<Option<i32>, MoveError> {
+        self.validate_move(id, parent_id)?;
+
+        let old_parent_id = self.child_to_parent.get(&id).copied();
+        match old_parent_id {
+            Some(old_parent_id) if old_parent_id != parent_id => {
+                // both child lists change under one pair of locks.
+                let (_, old_index) = self.unlink(id).unwrap();
+                let index = self.link(id, parent_id, index);
+                let (mut old_parent, mut parent) = self.lock_pair(old_parent_id, parent_id);
+                let node = old_parent.remove_child_at(old_index);
+                parent.insert_child(index, node);
+            }
+            _ => {
+                let node = self.detach(id);
+                self.attach(parent_id, node, index);
+            }
+        }
+        Ok(old_parent_id)
+    }
+
+    // Moves the node under parent_id right after the sibling after_sibling_id, or first among the
+    // children when that is None, for "drop between X and Y".
+    pub fn move_after(
+        &mut self,
+        id: i32,
+        parent_id: i32,
+        after_sibling_id: Option<i32>,
+    ) -> Result<Option<i32>, MoveError> {
+        let index = match after_sibling_id {
+            None => 0,
+            Some(sibling_id) => {
+                let not_a_sibling = MoveError::NotASibling {
+                    parent_id,
+                    sibling_id,
+                };
+                if sibling_id == id {
+                    return Err(not_a_sibling);
+                }
+                // move_node inserts after detaching, so the node itself doesn't count.
+                self.parent_to_child
+                    .get(&parent_id)
+                    .and_then(|children| {
+                        children
+                            .iter()
+                            .filter(|child_id| **child_id != id)
+                            .position(|child_id| *child_id == sibling_id)
+                    })
+                    .ok_or(not_a_sibling)?
+                    + 1
+            }
+        };
+        self.move_node(id, parent_id, index)
+    }
+
+    // Moves the node to the front or back of its current siblings, the parent doesn't change.
+    pub fn reorder(&mut self, id: i32, to: SiblingEnd) -> Result<(), MoveError> {
+        if !self.lookup.contains_key(&id) {
+            return Err(MoveError::NodeNotFound(id));
+        }
+        let parent_id = *self.child_to_parent.get(&id).ok_or(MoveError::IsRoot(id))?;
+        let index = match to {
+            SiblingEnd::Front => 0,
+            SiblingEnd::Back => usize::MAX,
+        };
+        self.move_node(id, parent_id, index)?;
+        Ok(())
+    }
+
+    // Swaps the node with the sibling before (up) or after (down) it. Returns whether it moved,
+    // the first child can't go up and the last can't go down.
+    pub fn nudge(&mut self, id: i32, direction: Direction) -> Result<bool, MoveError> {
+        if !self.lookup.contains_key(&id) {
+            return Err(MoveError::NodeNotFound(id));
+        }
+        let parent_id = *self.child_to_parent.get(&id).ok_or(MoveError::IsRoot(id))?;
+        let siblings = self.parent_to_child.get_mut(&parent_id).unwrap();
+        let index = siblings
+            .iter()
+            .position(|child_id| *child_id == id)
+            .unwrap();
+        let target = match direction {
+            Direction::Up => index.checked_sub(1),
+            Direction::Down => Some(index + 1).filter(|target| *target < siblings.len()),
+        };
+        let Some(target) = target else {
+            return Ok(false);
+        };
+        siblings.swap(index, target);
+        let mut parent = self.lookup[&parent_id].lock().unwrap();
+        let node = parent.remove_child(id).unwrap();
+        parent.insert_child(target, node);
+        Ok(true)
+    }
+
+    // Every check move_node makes, without touching the tree, GET /can-move runs it under the
+    // read lock.
+    pub fn validate_move(&self, id: i32, parent_id: i32) -> Result<(), MoveError> {
+        self.check_move_targets(id, parent_id)?;
+        // cheaper than collecting the descendants, it only walks from the new parent to its root.
+        if self.is_self_or_ancestor(id, parent_id) {
+            return Err(MoveError::Cycle { id, parent_id });
+        }
+        Ok(())
+    }
+
+    fn check_move_targets(&self, id: i32, parent_id: i32) -> Result<(), MoveError> {
+        if !self.lookup.contains_key(&id) {
+            return Err(MoveError::NodeNotFound(id));
+        }
+        if !self.lookup.contains_key(&parent_id) {
+            return Err(MoveError::ParentNotFound(parent_id));
+        }
+        // reordering under the current parent doesn't change its child count or its labels.
+        if self.child_to_parent.get(&id) != Some(&parent_id) {
+            if let Some(max_children) = self.is_full(parent_id) {
+                return Err(MoveError::ParentFull {
+                    parent_id,
+                    max_children,
+                });
+            }
+            let label = self.lookup[&id].lock().unwrap().label.clone();
+            if self.sibling_label_taken(parent_id, &label) {
+                return Err(MoveError::LabelTaken { parent_id, label });
+            }
+            if let Some(max_depth) = self.config.max_depth {
+                if self.depth(parent_id) + self.height(id) > max_depth {
+                    return Err(MoveError::TooDeep {
+                        parent_id,
+                        max_depth,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // All ids below the node, not including the node itself.
+    pub fn descendants(&self, id: i32) -> HashSet<i32> {
+        let mut descendants = HashSet::new();
+        let mut stack = vec![id];
+        while let Some(current) = stack.pop() {
+            if let Some(children) = self.parent_to_child.get(&current) {
+                for child_id in children {
+                    descendants.insert(*child_id);
+                    stack.push(*child_id);
+                }
+            }
+        }
+        descendants
+    }
+
+    // Number of nodes in the subtree rooted at id, counting id itself.
+    pub fn subtree_size(&self, id: i32) -> Option<usize> {
+        if !self.lookup.contains_key(&id) {
+            return None;
+        }
+        Some(self.descendants(id).len() + 1)
+    }
+
+    // Number of nodes on the path from id up to its root, a root has a depth of 1.
+    fn depth(&self, id: i32) -> usize {
+        let mut depth = 1;
+        let mut current = id;
+        while let Some(parent_id) = self.child_to_parent.get(&current) {
+            depth += 1;
+            current = *parent_id;
+        }
+        depth
+    }
+
+    // Number of levels in the subtree rooted at id, a leaf has a height of 1.
+    fn height(&self, id: i32) -> usize {
+        let mut height = 0;
+        let mut stack = vec![(id, 1)];
+        while let Some((current, depth)) = stack.pop() {
+            height = height.max(depth);
+            if let Some(children) = self.parent_to_child.get(&current) {
+                stack.extend(children.iter().map(|child_id| (*child_id, depth + 1)));
+            }
+        }
+        height
+    }
+
+    // Strict ancestry, a node is not its own ancestor.
+    pub fn is_ancestor(&self, a: i32, b: i32) -> bool {
+        a != b && self.is_self_or_ancestor(a, b)
+    }
+
+    // walks up from descendant, the tree is acyclic so this always terminates at a root.
+    fn is_self_or_ancestor(&self, id: i32, descendant: i32) -> bool {
+        let mut current = Some(descendant);
+        while let Some(current_id) = current {
+            if current_id == id {
+                return true;
+            }
+            current = self.child_to_parent.get(&current_id).copied();
+        }
+        false
+    }
+
+    // Removes the edge between the node and its parent (if any), leaving the node as a root.
+    fn detach(&mut self, id: i32) -> RcNodeRef<T> {
+        if let Some((parent_id, index)) = self.unlink(id) {
+            self.lookup[&parent_id]
+                .lock()
+                .unwrap()
+                .remove_child_at(index);
+        }
+        self.lookup[&id].clone()
+    }
+
+    fn attach(&mut self, parent_id: i32, node: RcNodeRef<T>, index: usize) {
+        let id = node.lock().unwrap().id;
+        let index = self.link(id, parent_id, index);
+        self.lookup[&parent_id]
+            .lock()
+            .unwrap()
+            .insert_child(index, node);
+    }
+
+    // The map half of detach, returns the parent the node had and its slot among the children.
+    fn unlink(&mut self, id: i32) -> Option<(i32, usize)> {
+        let (parent_id, index) = self.position(id)?;
+        self.child_to_parent.remove(&id);
+        let siblings = self.parent_to_child.get_mut(&parent_id).unwrap();
+        siblings.remove(index);
+        if siblings.is_empty() {
+            self.parent_to_child.remove(&parent_id);
+        }
+        Some((parent_id, index))
+    }
+
+    // The map half of attach, index is clamped to the end of the children. Returns the slot used.
+    fn link(&mut self, id: i32, parent_id: i32, index: usize) -> usize {
+        let siblings = self.parent_to_child.entry(parent_id).or_default();
+        let index = index.min(siblings.len());
+        siblings.insert(index, id);
+        self.child_to_parent.insert(id, parent_id);
+        index
+    }
+
+    // Locks two different nodes through node::lock_two, for writes that change both at once.
+    fn lock_pair(&self, a: i32, b: i32) -> (MutexGuard<'_, Node<T>>, MutexGuard<'_, Node<T>>) {
+        lock_two((a, &self.lookup[&a]), (b, &self.lookup[&b]))
+    }
+
+    // Deep copies the subtree rooted at id under new_parent (or as a new root), every copy gets a
+    // fresh id. Limits are checked up front so a clone is either applied fully or not at all.
+    pub fn clone_subtree(
+        &mut self,
+        id: i32,
+        new_parent: Option<i32>,
+    ) -> Result<RcNodeRef<T>, CloneError> {
+        if !self.lookup.contains_key(&id) {
+            return Err(CloneError::NodeNotFound(id));
+        }
+        if let Some(parent_id) = new_parent {
+            if !self.lookup.contains_key(&parent_id) {
+                return Err(CloneError::ParentNotFound(parent_id));
+            }
+        }
+        // a copy without a parent is a new root, auto_root treats it like a parentless add.
+        let new_parent = match self.parent_for_add(new_parent) {
+            Ok(new_parent) => new_parent,
+            Err(AddNodeError::RootExists(root_id)) => return Err(CloneError::RootExists(root_id)),
+            Err(error) => unreachable!("parent_for_add only rejects a second root: {}", error),
+        };
+
+        // pre-order snapshot taken before inserting, so cloning a node under its own subtree
+        // doesn't pick up the copies. Each entry is (id, parent id, depth below id).
+        let mut snapshot = vec![];
+        let mut stack = vec![(id, None, 1)];
+        while let Some((current, parent, depth)) = stack.pop() {
+            snapshot.push((current, parent, depth));
+            if let Some(children) = self.parent_to_child.get(&current) {
+                for child_id in children.iter().rev() {
+                    stack.push((*child_id, Some(current), depth + 1));
+                }
+            }
+        }
+
+        if let Some(max_nodes) = self.config.max_nodes {
+            if self.lookup.len() + snapshot.len() > max_nodes {
+                return Err(CloneError::LimitExceeded(format!(
+                    "tree would exceed its maximum size of {}",
+                    max_nodes
+                )));
+            }
+        }
+        if let (Some(max_roots), None) = (self.config.max_roots, new_parent) {
+            if self.lookup.len() - self.child_to_parent.len() >= max_roots {
+                return Err(CloneError::LimitExceeded(format!(
+                    "tree would exceed its maximum of {} roots",
+                    max_roots
+                )));
+            }
+        }
+        if let Some(max_depth) = self.config.max_depth {
+            let height = snapshot.iter().map(|(_, _, depth)| *depth).max().unwrap();
+            let base = new_parent.map_or(0, |parent_id| self.depth(parent_id));
+            if base + height > max_depth {
+                return Err(CloneError::LimitExceeded(format!(
+                    "tree would exceed its maximum depth of {}",
+                    max_depth
+                )));
+            }
+        }
+
+        // every other copy lands under a fresh parent, only the top one can collide or overfill.
+        if let Some(parent_id) = new_parent {
+            if let Some(max_children) = self.is_full(parent_id) {
+                return Err(CloneError::LimitExceeded(format!(
+                    "parent {} already has the maximum of {} children",
+                    parent_id, max_children
+                )));
+            }
+            let label = self.lookup[&id].lock().unwrap().label.clone();
+            if self.sibling_label_taken(parent_id, &label) {
+                return Err(CloneError::LabelTaken { parent_id, label });
+            }
+        }
+
+        let mut new_ids = HashMap::new();
+        let mut root = None;
+        for (old_id, old_parent, _) in snapshot {
+            let (label, metadata, data, sort_key) = {
+                let node = self.lookup[&old_id].lock().unwrap();
+                let data = node.data.clone();
+                (
+                    node.label.clone(),
+                    node.metadata.clone(),
+                    data,
+                    node.sort_key,
+                )
+            };
+            let parent_id = match old_parent {
+                Some(old_parent) => Some(new_ids[&old_parent]),
+                None => new_parent,
+            };
+            let copy = self
+                .insert_node(label, parent_id, metadata, data, 0, true)
+                .expect("clone inserts are validated up front");
+            copy.lock().unwrap().sort_key = sort_key;
+            new_ids.insert(old_id, copy.lock().unwrap().id);
+            root.get_or_insert(copy);
+        }
+
+        Ok(root.unwrap())
+    }
+
+    // Swaps the positions of two nodes, each takes over the other's parent and sibling index and
+    // brings its subtree along.
+    pub fn swap_nodes(&mut self, a: i32, b: i32) -> Result<(), SwapError> {
+        for id in [a, b] {
+            if !self.lookup.contains_key(&id) {
+                return Err(SwapError::NodeNotFound(id));
+            }
+        }
+        if a == b {
+            return Ok(());
+        }
+        for (ancestor, descendant) in [(a, b), (b, a)] {
+            if self.is_self_or_ancestor(ancestor, descendant) {
+                return Err(SwapError::IsAncestor {
+                    ancestor,
+                    descendant,
+                });
+            }
+        }
+
+        // neither is above the other, so each lands at the depth the other has now.
+        if let Some(max_depth) = self.config.max_depth {
+            for (id, other) in [(a, b), (b, a)] {
+                if self.depth(other) - 1 + self.height(id) > max_depth {
+                    return Err(SwapError::TooDeep { id, max_depth });
+                }
+            }
+        }
+        let position_a = self.position(a);
+        let position_b = self.position(b);
+        self.check_swap_labels(a, b, position_a, position_b)?;
+        let node_a = self.lookup[&a].clone();
+        let node_b = self.lookup[&b].clone();
+        self.place(position_a, b);
+        self.place(position_b, a);
+        match (position_a, position_b) {
+            (Some((parent_a, index_a)), Some((parent_b, index_b))) if parent_a == parent_b => {
+                let mut parent = self.lookup[&parent_a].lock().unwrap();
+                parent.replace_child(index_a, node_b);
+                parent.replace_child(index_b, node_a);
+            }
+            (Some((parent_a, index_a)), Some((parent_b, index_b))) => {
+                let (mut parent_a, mut parent_b) = self.lock_pair(parent_a, parent_b);
+                parent_a.replace_child(index_a, node_b);
+                parent_b.replace_child(index_b, node_a);
+            }
+            (Some((parent_a, index_a)), None) => {
+                self.lookup[&parent_a]
+                    .lock()
+                    .unwrap()
+                    .replace_child(index_a, node_b);
+            }
+            (None, Some((parent_b, index_b))) => {
+                self.lookup[&parent_b]
+                    .lock()
+                    .unwrap()
+                    .replace_child(index_b, node_a);
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+
+    // Under unique_sibling_labels each node must fit among the other's siblings. Swapping within
+    // one parent, or two nodes with the same label, can't introduce a clash.
+    fn check_swap_labels(
+        &self,
+        a: i32,
+        b: i32,
+        position_a: Option<(i32, usize)>,
+        position_b: Option<(i32, usize)>,
+    ) -> Result<(), SwapError> {
+        let parent_a = position_a.map(|(parent_id, _)| parent_id);
+        let parent_b = position_b.map(|(parent_id, _)| parent_id);
+        if !self.config.unique_sibling_labels || parent_a == parent_b {
+            return Ok(());
+        }
+        let label_a = self.lookup[&a].lock().unwrap().label.clone();
+        let label_b = self.lookup[&b].lock().unwrap().label.clone();
+        if label_a == label_b {
+            return Ok(());
+        }
+        for (parent_id, label) in [(parent_b, label_a), (parent_a, label_b)] {
+            if let Some(parent_id) = parent_id {
+                if self.sibling_label_taken(parent_id, &label) {
+                    return Err(SwapError::LabelTaken { parent_id, label });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Where the node sits in the nested output: its parent (None for a root) and its index among
+    // its siblings, roots counted in id order. None when the node doesn't exist.
+    pub fn locate(&self, id: i32) -> Option<(Option<i32>, usize)> {
+        if !self.lookup.contains_key(&id) {
+            return None;
+        }
+        match self.position(id) {
+            Some((parent_id, index)) => Some((Some(parent_id), index)),
+            None => {
+                let index = self.root_ids().iter().position(|root_id| *root_id == id)?;
+                Some((None, index))
+            }
+        }
+    }
+
+    // The parent and index among its siblings of a node, None for roots.
+    fn position(&self, id: i32) -> Option<(i32, usize)> {
+        let parent_id = *self.child_to_parent.get(&id)?;
+        let index = self.parent_to_child[&parent_id]
+            .iter()
+            .position(|child_id| *child_id == id)?;
+        Some((parent_id, index))
+    }
+
+    // Records the node in a position taken from position(), overwriting whatever was in that
+    // slot. Only the maps, swap_nodes moves the node refs themselves.
+    fn place(&mut self, position: Option<(i32, usize)>, id: i32) {
+        match position {
+            Some((parent_id, index)) => {
+                self.parent_to_child.get_mut(&parent_id).unwrap()[index] = id;
+                self.child_to_parent.insert(id, parent_id);
+            }
+            None => {
+                self.child_to_parent.remove(&id);
+            }
+        }
+    }
+
+    // Returns the single root, creating it with default_label when the tree is empty.
+    pub fn ensure_root(&mut self, default_label: &str) -> Result<RcNodeRef<T>, EnsureRootError> {
+        let root_ids = self.root_ids();
+        match root_ids.as_slice() {
+            [] => self
+                .add_node(default_label.to_string(), None)
+                .map_err(EnsureRootError::AddNode),
+            [root_id] => Ok(self.lookup[root_id].clone()),
+            _ => Err(EnsureRootError::MultipleRoots(root_ids)),
+        }
+    }
+
+    // The ids from id up to its root, starting with id itself.
+    fn path_to_root(&self, id: i32) -> Vec<i32> {
+        let mut path = vec![id];
+        while let Some(parent_id) = self.child_to_parent.get(path.last().unwrap()) {
+            path.push(*parent_id);
+        }
+        path
+    }
+
+    // The other children of id's parent in order, for a root the other roots by id. None when id
+    // doesn't exist.
+    pub fn siblings(&self, id: i32) -> Option<Vec<RcNodeRef<T>>> {
+        if !self.lookup.contains_key(&id) {
+            return None;
+        }
+        let sibling_ids = match self.child_to_parent.get(&id) {
+            Some(parent_id) => self.parent_to_child[parent_id].clone(),
+            None => self.root_ids(),
+        };
+        Some(
+            sibling_ids
+                .iter()
+                .filter(|sibling_id| **sibling_id != id)
+                .map(|sibling_id| self.lookup[sibling_id].clone())
+                .collect(),
+        )
+    }
+
+    // The path from id down to every leaf below it, leaves in pre-order. A leaf id gives the
+    // single path [id]. None when id doesn't exist.
+    pub fn leaf_paths(&self, id: i32) -> Option<Vec<Vec<i32>>> {
+        if !self.lookup.contains_key(&id) {
+            return None;
+        }
+        let mut paths = vec![];
+        // each entry carries its own path, popping a branch drops it.
+        let mut stack = vec![vec![id]];
+        while let Some(path) = stack.pop() {
+            match self.parent_to_child.get(path.last().unwrap()) {
+                Some(children) => {
+                    for child_id in children.iter().rev() {
+                        let mut child_path = path.clone();
+                        child_path.push(*child_id);
+                        stack.push(child_path);
+                    }
+                }
+                None => paths.push(path),
+            }
+        }
+        Some(paths)
+    }
+
+    // Direct children of id in order, None when id doesn't exist.
+    pub fn children_of(&self, id: i32) -> Option<Vec<RcNodeRef<T>>> {
+        if !self.lookup.contains_key(&id) {
+            return None;
+        }
+        Some(
+            self.parent_to_child
+                .get(&id)
+                .map(|children| {
+                    children
+                        .iter()
+                        .map(|child_id| self.lookup[child_id].clone())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        )
+    }
+
+    // The root of the tree containing id, id's own node when it is a root.
+    pub fn root_of(&self, id: i32) -> Option<RcNodeRef<T>> {
+        if !self.lookup.contains_key(&id) {
+            return None;
+        }
+        let root_id = self.path_to_root(id).pop()?;
+        self.get_node(&root_id)
+    }
+
+    // Deepest node that is an ancestor of (or equal to) both a and b, None when they live in
+    // different trees or either doesn't exist.
+    pub fn lca(&self, a: i32, b: i32) -> Option<i32> {
+        if !self.lookup.contains_key(&a) || !self.lookup.contains_key(&b) {
+            return None;
+        }
+        let ancestors_of_a: HashSet<i32> = self.path_to_root(a).into_iter().collect();
+        self.path_to_root(b)
+            .into_iter()
+            .find(|id| ancestors_of_a.contains(id))
+    }
+
+    // Number of edges between a and b through their lowest common ancestor, None when they are
+    // in different trees or either doesn't exist.
+    pub fn distance(&self, a: i32, b: i32) -> Option<usize> {
+        let lca = self.lca(a, b)?;
+        let up = self.path_to_root(a).iter().position(|id| *id == lca)?;
+        let down = self.path_to_root(b).iter().position(|id| *id == lca)?;
+        Some(up + down)
+    }
+
+    // Follows labels down from the roots, segments[0] picks a root and each later one a child of
+    // the node before it.
+    pub fn resolve_path(&self, segments: &[&str]) -> Result<RcNodeRef<T>, PathError> {
+        let mut candidates = self.root_ids();
+        let mut found = None;
+        for segment in segments {
+            let mut matches = candidates
+                .iter()
+                .filter(|id| self.lookup[id].lock().unwrap().label == *segment);
+            let id = match (matches.next(), matches.next()) {
+                (Some(id), None) => *id,
+                (None, _) => return Err(PathError::NotFound(segment.to_string())),
+                (Some(_), Some(_)) => return Err(PathError::Ambiguous(segment.to_string())),
+            };
+            candidates = self.parent_to_child.get(&id).cloned().unwrap_or_default();
+            found = Some(id);
+        }
+        found
+            .map(|id| self.lookup[&id].clone())
+            .ok_or(PathError::Empty)
+    }
+
+    // Every root in id order.
+    pub fn roots(&self) -> Vec<RcNodeRef<T>> {
+        self.root_ids()
+            .into_iter()
+            .map(|id| self.lookup[&id].clone())
+            .collect()
+    }
+
+    // Ids of every node without a parent, sorted so responses are stable.
+    fn root_ids(&self) -> Vec<i32> {
+        let mut root_ids: Vec<i32> = self
+            .lookup
+            .keys()
+            .filter(|key| !self.child_to_parent.contains_key(key))
+            .copied()
+            .collect();
+        root_ids.sort_unstable();
+        root_ids
+    }
+
+    pub fn adjacency(&self) -> Adjacency {
+        let ids = self.ids();
+        let nodes = ids
+            .iter()
+            .map(|id| AdjacencyNode {
+                id: *id,
+                label: self.lookup[id].lock().unwrap().label.clone(),
+            })
+            .collect();
+        let edges = ids
+            .iter()
+            .filter_map(|id| Some((*id, self.parent_to_child.get(id)?)))
+            .flat_map(|(parent_id, children)| {
+                children.iter().map(move |child_id| (parent_id, *child_id))
+            })
+            .collect();
+        Adjacency { nodes, edges }
+    }
+
+    // Every node without its children, in id order.
+    pub fn flat_nodes(&self) -> Vec<FlatNode> {
+        self.ids()
+            .into_iter()
+            .map(|id| FlatNode {
+                id,
+                label: self.lookup[&id].lock().unwrap().label.clone(),
+                parent_id: self.child_to_parent.get(&id).copied(),
+            })
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> Snapshot<T> {
+        let mut nodes = vec![];
+        let mut stack: Vec<(i32, Option<i32>)> = self
+            .root_ids()
+            .into_iter()
+            .rev()
+            .map(|id| (id, None))
+            .collect();
+        while let Some((id, parent_id)) = stack.pop() {
+            let node = self.lookup[&id].lock().unwrap();
+            nodes.push(SnapshotNode {
+                id,
+                label: node.label.clone(),
+                parent_id,
+                metadata: node.metadata.clone(),
+                sort_key: node.sort_key,
+                data: node.data.clone(),
+                updated_at: Some(node.updated_at),
+            });
+            if let Some(children) = self.parent_to_child.get(&id) {
+                stack.extend(children.iter().rev().map(|child_id| (*child_id, Some(id))));
+            }
+        }
+        Snapshot {
+            next_id: self.next_id,
+            nodes,
+        }
+    }
+
+    // Replaces the whole tree with the snapshot, rebuilding every map. The snapshot is validated
+    // against this tree's config first, on error the tree is left as it was.
+    pub fn restore(
+        &mut self,
+        snapshot: Snapshot<T>,
+        deadline: Option<Instant>,
+    ) -> Result<(), RestoreError> {
+        if let Some(max_nodes) = self.config.max_nodes {
+            if snapshot.nodes.len() > max_nodes {
+                return Err(RestoreError::LimitExceeded(format!(
+                    "tree would exceed its maximum size of {}",
+                    max_nodes
+                )));
+            }
+        }
+
+        if let Some(max_roots) = self.config.max_roots {
+            let roots = snapshot
+                .nodes
+                .iter()
+                .filter(|entry| entry.parent_id.is_none())
+                .count();
+            if roots > max_roots {
+                return Err(RestoreError::LimitExceeded(format!(
+                    "tree would exceed its maximum of {} roots",
+                    max_roots
+                )));
+            }
+        }
+
+        self.check_snapshot_shape(&snapshot)?;
+
+        let mut child_counts: HashMap<i32, usize> = HashMap::new();
+        for parent_id in snapshot.nodes.iter().filter_map(|entry| entry.parent_id) {
+            *child_counts.entry(parent_id).or_default() += 1;
+        }
+
+        // built on the side, the tree is only replaced once the whole snapshot is in.
+        let mut restored = Tree::typed(self.config.clone());
+        for entry in snapshot.nodes {
+            if passed(deadline) {
+                return Err(RestoreError::TimedOut);
+            }
+            let mut node = Node::with_data_and_capacity(
+                entry.id,
+                entry.label,
+                entry.data,
+                child_counts.get(&entry.id).copied().unwrap_or(0),
+            );
+            node.metadata = entry.metadata;
+            node.sort_key = entry.sort_key;
+            if let Some(updated_at) = entry.updated_at {
+                node.updated_at = updated_at;
+            }
+            restored.index_label(entry.id, node.label.clone());
+            let node = as_rc_ref(node);
+            if let Some(parent_id) = entry.parent_id {
+                restored.attach(parent_id, node.clone(), usize::MAX);
+            }
+            restored.lookup.insert(entry.id, node);
+            restored.next_id = restored.next_id.max(entry.id + 1);
+        }
+        restored.next_id = restored.next_id.max(snapshot.next_id);
+
+        *self = restored;
+        Ok(())
+    }
+
+    // Validates a snapshot before restore builds any of it: ids are unique, every parent precedes
+    // its children and no node is deeper than max_depth. Each depth comes from the parent's in
+    // one pass, so a pathologically deep snapshot costs a map entry per node and no recursion.
+    fn check_snapshot_shape(&self, snapshot: &Snapshot<T>) -> Result<(), RestoreError> {
+        if snapshot.next_id == i32::MAX {
+            return Err(RestoreError::IdOutOfRange(snapshot.next_id));
+        }
+        let mut depths = HashMap::with_capacity(snapshot.nodes.len());
+        for entry in &snapshot.nodes {
+            if entry.id == i32::MAX {
+                return Err(RestoreError::IdOutOfRange(entry.id));
+            }
+            if depths.contains_key(&entry.id) {
+                return Err(RestoreError::DuplicateId(entry.id));
+            }
+            let depth = match entry.parent_id {
+                Some(parent_id) => match depths.get(&parent_id) {
+                    Some(parent_depth) => parent_depth + 1,
+                    None => {
+                        return Err(RestoreError::ParentNotFound {
+                            id: entry.id,
+                            parent_id,
+                        })
+                    }
+                },
+                None => 1,
+            };
+            if let Some(max_depth) = self.config.max_depth {
+                if depth > max_depth {
+                    return Err(RestoreError::LimitExceeded(format!(
+                        "tree would exceed its maximum depth of {}",
+                        max_depth
+                    )));
+                }
+            }
+            depths.insert(entry.id, depth);
+        }
+        Ok(())
+    }
+
+    pub fn diff(before: &Tree<T>, after: &Tree<T>) -> TreeDiff
+    where
+        T: PartialEq,
+    {
+        let mut diff = TreeDiff::default();
+        for (id, node) in &after.lookup {
+            match before.lookup.get(id) {
+                None => diff.added.push(*id),
+                Some(old) => {
+                    // separate statements, both sides are the same mutex when diffing a tree
+                    // against itself.
+                    let (old_label, old_state) = {
+                        let old = old.lock().unwrap();
+                        let state = (old.data.clone(), old.metadata.clone(), old.sort_key);
+                        (old.label.clone(), state)
+                    };
+                    let node = node.lock().unwrap();
+                    if old_label != node.label {
+                        diff.relabeled.push(*id);
+                    }
+                    if old_state != (node.data.clone(), node.metadata.clone(), node.sort_key) {
+                        diff.changed.push(*id);
+                    }
+                    if before.child_to_parent.get(id) != after.child_to_parent.get(id) {
+                        diff.moved.push(*id);
+                    }
+                }
+            }
+        }
+        diff.removed = before
+            .lookup
+            .keys()
+            .filter(|id| !after.lookup.contains_key(id))
+            .copied()
+            .collect();
+
+        for ids in [
+            &mut diff.added,
+            &mut diff.removed,
+            &mut diff.relabeled,
+            &mut diff.moved,
+            &mut diff.changed,
+        ] {
+            ids.sort_unstable();
+        }
+        diff
+    }
+
+    // Same shape and labels, ids aside: roots pair up in id order and children in their order, so
+    // a compacted or restored copy still compares equal to the original.
+    pub fn structurally_equals(&self, other: &Tree<T>) -> bool {
+        let mut stack: Vec<(Vec<i32>, Vec<i32>)> = vec![(self.root_ids(), other.root_ids())];
+        while let Some((ours, theirs)) = stack.pop() {
+            if ours.len() != theirs.len() {
+                return false;
+            }
+            for (a, b) in ours.into_iter().zip(theirs) {
+                // separate statements, both sides are the same mutex when comparing a tree with
+                // itself.
+                let label = self.lookup[&a].lock().unwrap().label.clone();
+                if label != other.lookup[&b].lock().unwrap().label {
+                    return false;
+                }
+                let children =
+                    |tree: &Tree<T>, id| tree.parent_to_child.get(&id).cloned().unwrap_or_default();
+                stack.push((children(self, a), children(other, b)));
+            }
+        }
+        true
+    }
+
+    // Cross checks the maps against each other and the nodes' children, every mismatch found is
+    // described in the error. Only meant for debugging, it walks the whole forest.
+    pub fn verify(&self) -> Result<(), Vec<String>> {
+        let mut discrepancies = vec![];
+        let ids = self.ids();
+
+        for id in &ids {
+            let node = self.lookup[id].lock().unwrap();
+            if node.id != *id {
+                discrepancies.push(format!("lookup key {} holds node {}", id, node.id));
+            }
+            let children: Vec<i32> = node
+                .children()
+                .iter()
+                .map(|child| child.lock().unwrap().id)
+                .collect();
+            let expected = self.parent_to_child.get(id).cloned().unwrap_or_default();
+            if children != expected {
+                discrepancies.push(format!(
+                    "node {} has children {:?} but parent_to_child has {:?}",
+                    id, children, expected
+                ));
+            }
+        }
+
+        let mut edges: Vec<(i32, i32)> = self
+            .child_to_parent
+            .iter()
+            .map(|(child_id, parent_id)| (*child_id, *parent_id))
+            .collect();
+        edges.sort_unstable();
+        for (child_id, parent_id) in edges {
+            for id in [child_id, parent_id] {
+                if !self.lookup.contains_key(&id) {
+                    discrepancies.push(format!(
+                        "child_to_parent {} -> {} references missing node {}",
+                        child_id, parent_id, id
+                    ));
+                }
+            }
+            let reciprocal = self
+                .parent_to_child
+                .get(&parent_id)
+                .is_some_and(|children| children.contains(&child_id));
+            if !reciprocal {
+                discrepancies.push(format!(
+                    "child_to_parent {} -> {} is missing from parent_to_child",
+                    child_id, parent_id
+                ));
+            }
+        }
+
+        let mut parents: Vec<&i32> = self.parent_to_child.keys().collect();
+        parents.sort_unstable();
+        for parent_id in parents {
+            for child_id in &self.parent_to_child[parent_id] {
+                if self.child_to_parent.get(child_id) != Some(parent_id) {
+                    discrepancies.push(format!(
+                        "parent_to_child {} -> {} is missing from child_to_parent",
+                        parent_id, child_id
+                    ));
+                }
+            }
+        }
+
+        let mut indexed: Vec<(String, i32)> = self
+            .labels()
+            .iter()
+            .flat_map(|(label, ids)| ids.iter().map(move |id| (label.clone(), *id)))
+            .collect();
+        indexed.sort_unstable();
+        let mut labels: Vec<(String, i32)> = ids
+            .iter()
+            .map(|id| (self.lookup[id].lock().unwrap().label.clone(), *id))
+            .collect();
+        labels.sort_unstable();
+        if indexed != labels {
+            discrepancies.push(format!(
+                "label index has {:?} but the nodes have {:?}",
+                indexed, labels
+            ));
+        }
+
+        // anything not reached from a root is orphaned or part of a cycle.
+        let mut reached = HashSet::new();
+        let mut stack = self.root_ids();
+        while let Some(id) = stack.pop() {
+            if reached.insert(id) {
+                if let Some(children) = self.parent_to_child.get(&id) {
+                    stack.extend(children);
+                }
+            }
+        }
+        for id in ids.iter().filter(|id| !reached.contains(id)) {
+            discrepancies.push(format!("node {} is not reachable from any root", id));
+        }
+
+        if discrepancies.is_empty() {
+            Ok(())
+        } else {
+            Err(discrepancies)
+        }
+    }
+
+    // Recomputes everything derived from lookup and child_to_parent: parent_to_child, each node's
+    // children and by_label. Children keep their order where parent_to_child still lists
+    // them under the right parent, the rest follow by id. Edges naming a missing node are dropped.
+    // Returns whether anything had drifted. A cycle in child_to_parent is left for verify to report.
+    pub fn rebuild_indexes(&mut self) -> bool {
+        let edges = self.child_to_parent.len();
+        let lookup = &self.lookup;
+        self.child_to_parent.retain(|child_id, parent_id| {
+            lookup.contains_key(child_id) && lookup.contains_key(parent_id)
+        });
+        let mut changed = self.child_to_parent.len() != edges;
+
+        let mut placed = HashSet::new();
+        let mut parent_to_child: HashMap<i32, Vec<i32>> = HashMap::new();
+        for (parent_id, children) in &self.parent_to_child {
+            for child_id in children {
+                if self.child_to_parent.get(child_id) == Some(parent_id) && placed.insert(*child_id)
+                {
+                    parent_to_child
+                        .entry(*parent_id)
+                        .or_default()
+                        .push(*child_id);
+                }
+            }
+        }
+        let mut unplaced: Vec<(i32, i32)> = self
+            .child_to_parent
+            .iter()
+            .filter(|(child_id, _)| !placed.contains(*child_id))
+            .map(|(child_id, parent_id)| (*child_id, *parent_id))
+            .collect();
+        unplaced.sort_unstable();
+        for (child_id, parent_id) in unplaced {
+            parent_to_child.entry(parent_id).or_default().push(child_id);
+        }
+        changed |= parent_to_child != self.parent_to_child;
+        self.parent_to_child = parent_to_child;
+
+        let mut by_label: HashMap<String, HashSet<i32>> = HashMap::new();
+        for (id, node_ref) in &self.lookup {
+            let expected: Vec<RcNodeRef<T>> = self
+                .parent_to_child
+                .get(id)
+                .map(|children| {
+                    children
+                        .iter()
+                        .map(|child_id| self.lookup[child_id].clone())
+                })
+                .into_iter()
+                .flatten()
+                .collect();
+            // compared by pointer, locking the children could deadlock on a self loop.
+            let mut node = node_ref.lock().unwrap();
+            let matches = node.children().len() == expected.len()
+                && node
+                    .children()
+                    .iter()
+                    .zip(&expected)
+                    .all(|(child, expected)| Arc::ptr_eq(child, expected));
+            if !matches {
+                node.retain_children(|_| false);
+                for child in expected {
+                    node.add_child(child);
+                }
+                changed = true;
+            }
+            by_label.entry(node.label.clone()).or_default().insert(*id);
+        }
+        let index = self
+            .by_label
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        changed |= by_label != *index;
+        *index = by_label;
+        changed
+    }
+
+    pub fn set_metadata(&mut self, id: i32, key: String, value: String) -> Option<RcNodeRef<T>> {
+        let node = self.get_node(&id)?;
+        node.lock().unwrap().metadata.insert(key, value);
+        Some(node)
+    }
+
+    // Relabels a single node through the node's own mutex, so it only needs &self and the store
+    // can run it under the read lock.
+    pub fn rename_node(&self, id: i32, label: &str) -> Result<(), RenameError> {
+        let node = self.get_node(&id).ok_or(RenameError::NodeNotFound(id))?;
+        let label = self.normalize_label(label);
+        if label.is_empty() {
+            return Err(RenameError::EmptyLabel(id));
+        }
+        if !self.is_allowed_label(&label) {
+            return Err(RenameError::InvalidLabel(id));
+        }
+        if let Some(&parent_id) = self.child_to_parent.get(&id) {
+            // the node keeping its own label is not a clash.
+            let taken = self.config.unique_sibling_labels
+                && self.parent_to_child[&parent_id].iter().any(|child_id| {
+                    *child_id != id && self.lookup[child_id].lock().unwrap().label == label
+                });
+            if taken {
+                return Err(RenameError::LabelTaken { parent_id, label });
+            }
+        }
+        self.relabel(&node, label);
+        Ok(())
+    }
+
+    // Relabels every node in updates or none of them. Everything is validated against the labels
+    // the tree would end up with before the first node is touched, so two siblings can swap
+    // labels under unique_sibling_labels. A later update for the same id wins.
+    pub fn rename_many(&mut self, updates: Vec<LabelUpdate>) -> Result<(), RenameError> {
+        let mut labels = HashMap::new();
+        for update in updates {
+            if !self.lookup.contains_key(&update.id) {
+                return Err(RenameError::NodeNotFound(update.id));
+            }
+            let label = self.normalize_label(&update.label);
+            if label.is_empty() {
+                return Err(RenameError::EmptyLabel(update.id));
+            }
+            if !self.is_allowed_label(&label) {
+                return Err(RenameError::InvalidLabel(update.id));
+            }
+            labels.insert(update.id, label);
+        }
+
+        if self.config.unique_sibling_labels {
+            let parents: HashSet<i32> = labels
+                .keys()
+                .filter_map(|id| self.child_to_parent.get(id))
+                .copied()
+                .collect();
+            for parent_id in parents {
+                let mut seen = HashSet::new();
+                for child_id in &self.parent_to_child[&parent_id] {
+                    let label = match labels.get(child_id) {
+                        Some(label) => label.clone(),
+                        None => self.lookup[child_id].lock().unwrap().label.clone(),
+                    };
+                    if !seen.insert(label.clone()) {
+                        return Err(RenameError::LabelTaken { parent_id, label });
+                    }
+                }
+            }
+        }
+
+        for (id, label) in labels {
+            self.relabel(&self.lookup[&id], label);
+        }
+        Ok(())
+    }
+
+    // Removes every node that currently has no children and returns their ids, sorted. Parents
+    // left childless are kept, each call prunes a single level.
+    pub fn prune_leaves(&mut self) -> Vec<i32> {
+        let leaves: Vec<i32> = self
+            .ids()
+            .into_iter()
+            .filter(|id| !self.parent_to_child.contains_key(id))
+            .collect();
+        let leaf_set: HashSet<i32> = leaves.iter().copied().collect();
+
+        // grouped by parent so a wide parent is only walked once.
+        let parents: HashSet<i32> = leaves
+            .iter()
+            .filter_map(|id| self.child_to_parent.remove(id))
+            .collect();
+        for parent_id in parents {
+            let children = self.parent_to_child.get_mut(&parent_id).unwrap();
+            children.retain(|child_id| !leaf_set.contains(child_id));
+            if children.is_empty() {
+                self.parent_to_child.remove(&parent_id);
+            }
+            self.lookup[&parent_id]
+                .lock()
+                .unwrap()
+                .retain_children(|child_id| !leaf_set.contains(&child_id));
+        }
+        for id in &leaves {
+            self.forget(*id);
+        }
+        leaves
+    }
+
+    // Removes the node and puts its children in its place under its parent, in their order.
+    // Returns the parent. Roots can't be flattened, their children would have nowhere to go.
+    pub fn flatten_node(&mut self, id: i32) -> Result<i32, FlattenError> {
+        if !self.lookup.contains_key(&id) {
+            return Err(FlattenError::NodeNotFound(id));
+        }
+        let parent_id = *self
+            .child_to_parent
+            .get(&id)
+            .ok_or(FlattenError::IsRoot(id))?;
+        let children = self.parent_to_child.remove(&id).unwrap_or_default();
+        let siblings = &self.parent_to_child[&parent_id];
+
+        let rejected = if let Some(max_children) = self
+            .config
+            .max_children
+            .filter(|max_children| siblings.len() - 1 + children.len() > *max_children)
+        {
+            Some(FlattenError::ParentFull {
+                parent_id,
+                max_children,
+            })
+        } else if self.config.unique_sibling_labels {
+            // the children are unique among themselves already, only the node's siblings can clash.
+            let taken: HashSet<String> = siblings
+                .iter()
+                .filter(|sibling_id| **sibling_id != id)
+                .map(|sibling_id| self.lookup[sibling_id].lock().unwrap().label.clone())
+                .collect();
+            children.iter().find_map(|child_id| {
+                let label = self.lookup[child_id].lock().unwrap().label.clone();
+                taken
+                    .contains(&label)
+                    .then_some(FlattenError::LabelTaken { parent_id, label })
+            })
+        } else {
+            None
+        };
+        if let Some(error) = rejected {
+            if !children.is_empty() {
+                self.parent_to_child.insert(id, children);
+            }
+            return Err(error);
+        }
+
+        let index = siblings
+            .iter()
+            .position(|sibling_id| *sibling_id == id)
+            .unwrap();
+        {
+            let (mut parent, mut node) = self.lock_pair(parent_id, id);
+            parent.remove_child_at(index);
+            for (offset, child) in node.take_children().into_iter().enumerate() {
+                parent.insert_child(index + offset, child);
+            }
+        }
+        for child_id in &children {
+            self.child_to_parent.insert(*child_id, parent_id);
+        }
+        self.parent_to_child
+            .get_mut(&parent_id)
+            .unwrap()
+            .splice(index..=index, children);
+        self.child_to_parent.remove(&id);
+        self.forget(id);
+        Ok(parent_id)
+    }
+
+    // Marks the node as just updated, nothing else about it changes. updated_at always moves
+    // forward, even when the clock hasn't ticked since the last touch.
+    pub fn touch(&mut self, id: i32) -> Result<(), TouchError> {
+        let node = self.lookup.get(&id).ok_or(TouchError::NodeNotFound(id))?;
+        let mut node = node.lock().unwrap();
+        node.updated_at = now_millis().max(node.updated_at + 1);
+        Ok(())
+    }
+
+    // The nodes collapse_chain would merge into id, top down. Each is the only child of the one
+    // above and has exactly one child itself, so the first node that branches (or a leaf) ends
+    // the run and is kept.
+    pub fn single_child_chain(&self, id: i32) -> Vec<i32> {
+        let mut chain = vec![];
+        let mut current = id;
+        while let Some([child_id]) = self.parent_to_child.get(&current).map(Vec::as_slice) {
+            if self.parent_to_child.get(child_id).map_or(0, Vec::len) != 1 {
+                break;
+            }
+            chain.push(*child_id);
+            current = *child_id;
+        }
+        chain
+    }
+
+    // Merges the single_child_chain below id into it, joining the labels with separator, and
+    // moves the children of the last merged node under it. The merged nodes are removed, their
+    // metadata and payload go with them.
+    pub fn collapse_chain(
+        &mut self,
+        id: i32,
+        separator: &str,
+    ) -> Result<RcNodeRef<T>, CollapseError> {
+        let node = self
+            .lookup
+            .get(&id)
+            .cloned()
+            .ok_or(CollapseError::NodeNotFound(id))?;
+        let chain = self.single_child_chain(id);
+        let Some(&last) = chain.last() else {
+            return Ok(node);
+        };
+
+        let mut label = node.lock().unwrap().label.clone();
+        for merged_id in &chain {
+            label.push_str(separator);
+            label.push_str(&self.lookup[merged_id].lock().unwrap().label);
+        }
+        if !self.is_allowed_label(&label) {
+            return Err(CollapseError::InvalidLabel(id));
+        }
+        if let Some(&parent_id) = self.child_to_parent.get(&id) {
+            if self.sibling_label_taken(parent_id, &label) {
+                return Err(CollapseError::LabelTaken { parent_id, label });
+            }
+        }
+
+        let children = self.parent_to_child.remove(&last).unwrap_or_default();
+        self.relabel(&node, label);
+        {
+            let (mut node, mut last) = self.lock_pair(id, last);
+            node.take_children();
+            for child in last.take_children() {
+                node.add_child(child);
+            }
+        }
+        for child_id in &children {
+            self.child_to_parent.insert(*child_id, id);
+        }
+        self.parent_to_child.insert(id, children);
+        for merged_id in &chain {
+            self.child_to_parent.remove(merged_id);
+            self.parent_to_child.remove(merged_id);
+            self.forget(*merged_id);
+        }
+        Ok(node)
+    }
+
+    // Adds the nodes in order, all of them or, when one is rejected, none: the ones already added
+    // are removed again and next_id is wound back, so a failed import leaves no trace.
+    pub fn import(
+        &mut self,
+        nodes: Vec<ImportNode>,
+        deadline: Option<Instant>,
+    ) -> Result<Imported, ImportError> {
+        let depths = self.import_depths(&nodes);
+        // the whole batch is rejected before anything is built.
+        if let Some(max_depth) = self.config.max_depth {
+            if let Some(index) = depths.iter().position(|depth| *depth > Some(max_depth)) {
+                return Err(ImportError::TooDeep { index, max_depth });
+            }
+        }
+        let next_id = self.next_id;
+        let mut imported = Imported::default();
+        let child_counts = import_child_counts(&nodes);
+        for (index, node) in nodes.into_iter().enumerate() {
+            let result = if passed(deadline) {
+                Err(ImportError::TimedOut(index))
+            } else {
+                let depth_checked = depths[index].is_some();
+                self.import_node(
+                    index,
+                    node,
+                    &child_counts,
+                    depth_checked,
+                    &mut imported.id_map,
+                )
+            };
+            match result {
+                Ok(id) => imported.ids.push(id),
+                Err(error) => {
+                    // the rollback has to finish, it runs without a deadline.
+                    let _ = self.remove_many(&imported.ids, None);
+                    self.next_id = next_id;
+                    return Err(error);
+                }
+            }
+        }
+        Ok(imported)
+    }
+
+    // Adds every node it can and reports the rest. A rejected keyed node is left out of id_map,
+    // so later entries naming it as parent_key are rejected too. Once the deadline passes every
+    // remaining entry is reported as timed out.
+    pub fn import_best_effort(
+        &mut self,
+        nodes: Vec<ImportNode>,
+        deadline: Option<Instant>,
+    ) -> (Imported, Vec<ImportError>) {
+        let depths = self.import_depths(&nodes);
+        let mut imported = Imported::default();
+        let mut failed = vec![];
+        let child_counts = import_child_counts(&nodes);
+        for (index, node) in nodes.into_iter().enumerate() {
+            let result = match (depths[index], self.config.max_depth) {
+                _ if passed(deadline) => Err(ImportError::TimedOut(index)),
+                (Some(depth), Some(max_depth)) if depth > max_depth => {
+                    Err(ImportError::TooDeep { index, max_depth })
+                }
+                (depth, _) => self.import_node(
+                    index,
+                    node,
+                    &child_counts,
+                    depth.is_some(),
+                    &mut imported.id_map,
+                ),
+            };
+            match result {
+                Ok(id) => imported.ids.push(id),
+                Err(error) => failed.push(error),
+            }
+        }
+        (imported, failed)
+    }
+
+    /*
+     * The depth each entry would land at, worked out in one pass with every keyed entry's depth
+     * carried over from its parent_key's, instead of walking up from the parent on every insert.
+     * None where the tree has no max_depth or the depth can't be known up front (an unresolved
+     * parent, a parent_id inside the batch), import_node checks those as it inserts them.
+     */
+    fn import_depths(&self, nodes: &[ImportNode]) -> Vec<Option<usize>> {
+        if self.config.max_depth.is_none() {
+            return vec![None; nodes.len()];
+        }
+        let mut key_depths: HashMap<&str, Option<usize>> = HashMap::new();
+        let mut parent_depths: HashMap<i32, usize> = HashMap::new();
+        // under auto_root's attach the first parentless entry in a rootless tree becomes the root
+        // and the rest go under it.
+        let mut has_root = !self.root_ids().is_empty();
+        let mut depths = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let depth = match (node.parent_id, &node.parent_key) {
+                (Some(parent_id), None) if self.lookup.contains_key(&parent_id) => Some(
+                    *parent_depths
+                        .entry(parent_id)
+                        .or_insert_with(|| self.depth(parent_id))
+                        + 1,
+                ),
+                (None, None) if self.config.auto_root == Some(AutoRoot::Attach) => {
+                    let depth = if has_root { 2 } else { 1 };
+                    has_root = true;
+                    Some(depth)
+                }
+                (None, None) => Some(1),
+                (None, Some(key)) => key_depths
+                    .get(key.as_str())
+                    .copied()
+                    .flatten()
+                    .map(|depth| depth + 1),
+                (Some(_), _) => None,
+            };
+            // which of two entries with the same key ends up keeping it depends on which insert
+            // succeeds, so a key seen at two depths counts as unknown.
+            if let Some(key) = &node.key {
+                key_depths
+                    .entry(key)
+                    .and_modify(|known| {
+                        if *known != depth {
+                            *known = None;
+                        }
+                    })
+                    .or_insert(depth);
+            }
+            depths.push(depth);
+        }
+        depths
+    }
+
+    fn import_node(
+        &mut self,
+        index: usize,
+        node: ImportNode,
+        child_counts: &HashMap<String, usize>,
+        depth_checked: bool,
+        id_map: &mut HashMap<String, i32>,
+    ) -> Result<i32, ImportError> {
+        if let Some(key) = node.key.as_ref().filter(|key| id_map.contains_key(*key)) {
+            return Err(ImportError::DuplicateKey {
+                index,
+                key: key.clone(),
+            });
+        }
+        let parent_id = match (node.parent_id, node.parent_key) {
+            (Some(_), Some(_)) => return Err(ImportError::TwoParents(index)),
+            (parent_id, None) => parent_id,
+            (None, Some(key)) => match id_map.get(&key) {
+                Some(parent_id) => Some(*parent_id),
+                None => return Err(ImportError::UnknownKey { index, key }),
+            },
+        };
+        let capacity = node
+            .key
+            .as_deref()
+            .and_then(|key| child_counts.get(key))
+            .copied()
+            .unwrap_or(0);
+        let added = self
+            .insert_node(
+                node.label,
+                parent_id,
+                node.metadata,
+                None,
+                capacity,
+                depth_checked,
+            )
+            .map_err(|error| ImportError::Add { index, error })?;
+        let id = added.lock().unwrap().id;
+        if let Some(key) = node.key {
+            id_map.insert(key, id);
+        }
+        Ok(id)
+    }
+
+    // Removes each listed node along with its subtree. Ids that don't exist, including ones inside
+    // a subtree removed earlier in the list, are skipped. Returns every removed id, sorted. The
+    // subtrees are all collected before anything is removed, a deadline passing meanwhile leaves
+    // the tree as it was.
+    pub fn remove_many(
+        &mut self,
+        ids: &[i32],
+        deadline: Option<Instant>,
+    ) -> Result<Vec<i32>, DeadlinePassed> {
+        let mut removed = HashSet::new();
+        let mut tops = vec![];
+        for &id in ids {
+            if removed.contains(&id) || !self.lookup.contains_key(&id) {
+                continue;
+            }
+            if passed(deadline) {
+                return Err(DeadlinePassed);
+            }
+            tops.push(id);
+            removed.insert(id);
+            removed.extend(self.descendants(id));
+        }
+
+        for id in tops {
+            // one listed under another listed node goes with that node's subtree.
+            let parent_removed = self
+                .child_to_parent
+                .get(&id)
+                .is_some_and(|parent_id| removed.contains(parent_id));
+            if !parent_removed {
+                self.detach(id);
+            }
+        }
+        for &node_id in &removed {
+            self.child_to_parent.remove(&node_id);
+            self.parent_to_child.remove(&node_id);
+            self.forget(node_id);
+        }
+        let mut removed: Vec<i32> = removed.into_iter().collect();
+        removed.sort_unstable();
+        Ok(removed)
+    }
+
+    // Renumbers the nodes 1..=n in pre-order (roots by id, children in order) and returns every
+    // node's old id -> new id, unchanged ones included. next_id becomes n + 1.
+    pub fn compact(&mut self) -> HashMap<i32, i32> {
+        let mut mapping = HashMap::new();
+        let mut stack: Vec<i32> = self.root_ids().into_iter().rev().collect();
+        while let Some(id) = stack.pop() {
+            mapping.insert(id, mapping.len() as i32 + 1);
+            if let Some(children) = self.parent_to_child.get(&id) {
+                stack.extend(children.iter().rev());
+            }
+        }
+
+        self.lookup = self
+            .lookup
+            .drain()
+            .map(|(id, node)| {
+                node.lock().unwrap().id = mapping[&id];
+                (mapping[&id], node)
+            })
+            .collect();
+        self.child_to_parent = self
+            .child_to_parent
+            .drain()
+            .map(|(child_id, parent_id)| (mapping[&child_id], mapping[&parent_id]))
+            .collect();
+        self.parent_to_child = self
+            .parent_to_child
+            .drain()
+            .map(|(parent_id, children)| {
+                let children = children.iter().map(|child_id| mapping[child_id]).collect();
+                (mapping[&parent_id], children)
+            })
+            .collect();
+        for ids in self
+            .by_label
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values_mut()
+        {
+            *ids = ids.iter().map(|id| mapping[id]).collect();
+        }
+        self.next_id = mapping.len() as i32 + 1;
+        mapping
+    }
+
+    // The forest as a graphviz digraph, nodes in id order followed by the edges in child order.
+    // With color_by_depth each node is filled with DEPTH_PALETTE[depth % len], roots at depth 0.
+    pub fn to_dot(&self, color_by_depth: bool) -> String {
+        let mut dot = String::from("digraph tree {\n");
+        let ids = self.ids();
+        // one walk down from the roots instead of a walk up to the root per node.
+        let mut depths = HashMap::new();
+        if color_by_depth {
+            let mut stack: Vec<(i32, usize)> =
+                self.root_ids().into_iter().map(|id| (id, 0)).collect();
+            while let Some((id, depth)) = stack.pop() {
+                depths.insert(id, depth);
+                for child_id in self.parent_to_child.get(&id).into_iter().flatten() {
+                    stack.push((*child_id, depth + 1));
+                }
+            }
+        }
+        for id in &ids {
+            let label = self.lookup[id].lock().unwrap().label.clone();
+            let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+            if color_by_depth {
+                let color = DEPTH_PALETTE[depths[id] % DEPTH_PALETTE.len()];
+                dot.push_str(&format!(
+                    "  {} [label=\"{}\", style=filled, fillcolor=\"{}\"];\n",
+                    id, label, color
+                ));
+            } else {
+                dot.push_str(&format!("  {} [label=\"{}\"];\n", id, label));
+            }
+        }
+        for id in &ids {
+            for child_id in self.parent_to_child.get(id).into_iter().flatten() {
+                dot.push_str(&format!("  {} -> {};\n", id, child_id));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // One flat_nodes entry per line as {"id","label","parent_id"}, every line ending in a newline.
+    pub fn to_ndjson(&self) -> String {
+        self.flat_nodes()
+            .iter()
+            .map(|node| serde_json::to_string(node).unwrap() + "\n")
+            .collect()
+    }
+
+    // The forest as a nested Markdown list, "- label" indented two spaces per level, roots by id
+    // and children in order. With escape the Markdown special characters in labels are
+    // backslash escaped, otherwise labels are written as they are.
+    pub fn to_markdown(&self, escape: bool) -> String {
+        let mut markdown = String::new();
+        let mut stack: Vec<(i32, usize)> = self
+            .root_ids()
+            .into_iter()
+            .rev()
+            .map(|id| (id, 0))
+            .collect();
+        while let Some((id, level)) = stack.pop() {
+            let label = self.lookup[&id].lock().unwrap().label.clone();
+            let label = if escape {
+                escape_markdown(&label)
+            } else {
+                label
+            };
+            markdown.push_str(&format!("{}- {}\n", "  ".repeat(level), label));
+            if let Some(children) = self.parent_to_child.get(&id) {
+                stack.extend(children.iter().rev().map(|child_id| (*child_id, level + 1)));
+            }
+        }
+        markdown
+    }
+
+    // Number of nodes on each level across the forest, breadth first from the roots at index 0.
+    pub fn level_widths(&self) -> Vec<usize> {
+        let mut widths = vec![];
+        let mut level = self.root_ids();
+        while !level.is_empty() {
+            widths.push(level.len());
+            level = level
+                .iter()
+                .filter_map(|id| self.parent_to_child.get(id))
+                .flatten()
+                .copied()
+                .collect();
+        }
+        widths
+    }
+
+    // (id, label) of up to limit nodes whose label starts with prefix, ignoring case. Sorted by
+    // label, then id so equal labels come back in a stable order.
+    pub fn labels_with_prefix(&self, prefix: &str, limit: usize) -> Vec<(i32, String)> {
+        let prefix = prefix.to_lowercase();
+        let mut matches: Vec<(i32, String)> = self
+            .lookup
+            .iter()
+            .filter_map(|(id, node)| {
+                let label = node.lock().unwrap().label.clone();
+                label
+                    .to_lowercase()
+                    .starts_with(&prefix)
+                    .then_some((*id, label))
+            })
+            .collect();
+        matches.sort_unstable_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        matches.truncate(limit);
+        matches
+    }
+
+    // Ids of the nodes labelled exactly label, sorted. Read from the label index, no scan.
+    pub fn find_by_label(&self, label: &str) -> Vec<i32> {
+        let mut ids: Vec<i32> = self
+            .labels()
+            .get(label)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default();
+        ids.sort_unstable();
+        ids
+    }
+
+    // Groups of two or more nodes sharing a label anywhere in the forest, with ci comparing labels
+    // lowercased. Ids are sorted within a group and groups by their first id.
+    pub fn duplicate_labels(&self, ci: bool) -> Vec<Vec<i32>> {
+        let mut by_label: HashMap<String, Vec<i32>> = HashMap::new();
+        for (label, ids) in self.labels().iter() {
+            let label = if ci {
+                label.to_lowercase()
+            } else {
+                label.clone()
+            };
+            by_label.entry(label).or_default().extend(ids);
+        }
+        let mut groups: Vec<Vec<i32>> = by_label
+            .into_values()
+            .filter(|ids| ids.len() > 1)
+            .map(|mut ids| {
+                ids.sort_unstable();
+                ids
+            })
+            .collect();
+        groups.sort_unstable();
+        groups
+    }
+
+    // Every node id, sorted.
+    pub fn ids(&self) -> Vec<i32> {
+        let mut ids: Vec<i32> = self.lookup.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn len(&self) -> i32 {
+        self.lookup.keys().len() as i32
+    }
+
+    pub fn contains(&self, id: i32) -> bool {
+        self.lookup.contains_key(&id)
+    }
+
+    pub fn get_node(&self, index: &i32) -> Option<RcNodeRef<T>> {
+        self.lookup.get(index).cloned()
+    }
+}
+
+impl<T: Payload> From<&Tree<T>> for Vec<Arc<Mutex<Node<T>>>> {
+    fn from(value: &Tree<T>) -> Self {
+        value.roots()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn builds_default_tree() {
+        let tree = Tree::default();
+        assert_eq!(tree.len(), 0);
+        assert_eq!(tree.child_to_parent.len(), 0);
+        assert_eq!(tree.parent_to_child.len(), 0);
+    }
+
+    #[test]
+    fn can_add_node_to_tree() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        assert_eq!(tree.len(), 1);
+        let node = tree.lookup[&1].lock().unwrap();
+        assert_eq!(node.id, 1);
+        assert_eq!(node.label, "root");
+    }
+
+    #[test]
+    fn can_add_node_to_tree_with_parent() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        assert_eq!(tree.len(), 2);
+        let arc = tree.get_node(&2).unwrap();
+        let node = arc.lock().unwrap();
+        assert_eq!(node.id, 2);
+        assert_eq!(node.label, "child");
+        assert_eq!(tree.child_to_parent.len(), 1);
+        assert_eq!(tree.parent_to_child.len(), 1);
+        assert_eq!(tree.parent_to_child.get(&1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_nodes_past_max_size() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_nodes: Some(2),
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+
+        let result = tree.add_node("child".to_string(), Some(1));
+
+        assert!(result.is_err());
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.next_id, 3);
+    }
+
+    #[test]
+    fn rejects_nodes_past_max_depth() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_depth: Some(2),
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+
+        let result = tree.add_node("grandchild".to_string(), Some(2));
+
+        assert!(result.is_err());
+        assert_eq!(tree.len(), 2);
+        assert!(tree.add_node("sibling".to_string(), Some(1)).is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_sibling_labels_when_unique() {
+        let mut tree = Tree::with_config(TreeConfig {
+            unique_sibling_labels: true,
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("docs".to_string(), Some(1)).unwrap();
+
+        let error = tree.add_node("docs".to_string(), Some(1)).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Cannot add node, parent 1 already has a child labelled \"docs\""
+        );
+        assert!(matches!(
+            tree.clone_subtree(2, Some(1)),
+            Err(CloneError::LabelTaken { parent_id: 1, .. })
+        ));
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn allows_same_label_under_different_parents() {
+        let mut tree = Tree::with_config(TreeConfig {
+            unique_sibling_labels: true,
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("docs".to_string(), Some(1)).unwrap();
+
+        assert!(tree.add_node("docs".to_string(), Some(2)).is_ok());
+        assert!(tree.add_node("root".to_string(), None).is_ok());
+        // the default config allows duplicates.
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("docs".to_string(), Some(1)).unwrap();
+        assert!(tree.add_node("docs".to_string(), Some(1)).is_ok());
+    }
+
+    #[test]
+    fn trims_labels_and_rejects_empty_ones() {
+        let mut tree = Tree::default();
+        let node = tree.add_node("  foo  ".to_string(), None).unwrap();
+        assert_eq!(node.lock().unwrap().label, "foo");
+        let node = tree.add_node(" foo \t bar ".to_string(), None).unwrap();
+        assert_eq!(node.lock().unwrap().label, "foo \t bar");
+
+        let error = tree.add_node("   ".to_string(), None).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Cannot add node, label must not be empty"
+        );
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn collapses_inner_whitespace_when_normalizing() {
+        let mut tree = Tree::with_config(TreeConfig {
+            normalize_labels: true,
+            ..TreeConfig::default()
+        });
+        let node = tree.add_node(" foo \t\n bar ".to_string(), None).unwrap();
+        assert_eq!(node.lock().unwrap().label, "foo bar");
+        assert!(tree.add_node(" \n ".to_string(), None).is_err());
+    }
+
+    #[test]
+    fn rejects_children_past_max_children() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_children: Some(2),
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+
+        let error = tree.add_node("c".to_string(), Some(1)).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Cannot add node under 1, it already has the maximum of 2 children"
+        );
+        assert!(matches!(
+            tree.clone_subtree(2, Some(1)),
+            Err(CloneError::LimitExceeded(_))
+        ));
+
+        // other parents still have room.
+        tree.add_node("a1".to_string(), Some(2)).unwrap();
+        tree.add_node("a2".to_string(), Some(2)).unwrap();
+        tree.add_node("b1".to_string(), Some(3)).unwrap();
+        assert!(matches!(
+            tree.move_node(6, 2, 0),
+            Err(MoveError::ParentFull {
+                parent_id: 2,
+                max_children: 2
+            })
+        ));
+        // reordering within a full parent is fine.
+        tree.move_node(5, 2, 0).unwrap();
+        assert_eq!(child_ids(&tree, 2), vec![5, 4]);
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct Task {
+        done: bool,
+    }
+
+    #[test]
+    fn carries_typed_payload() {
+        let mut tree = Tree::<Task>::typed(TreeConfig::default());
+        let root = tree.add_node("root".to_string(), None).unwrap();
+        root.lock().unwrap().data = Some(Task { done: true });
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        tree.clone_subtree(1, None).unwrap();
+
+        // the child was added without a payload, so it has none rather than a default one.
+        assert_eq!(
+            crate::node::to_json(&Vec::from(&tree)),
+            r#"[{"id":1,"label":"root","data":{"done":true},"children":[{"id":2,"label":"child","children":[]}]},{"id":3,"label":"root","data":{"done":true},"children":[{"id":4,"label":"child","children":[]}]}]"#
+        );
+    }
+
+    #[test]
+    fn snapshot_round_trips_payloads() {
+        let mut tree = Tree::<Task>::typed(TreeConfig::default());
+        let root = tree.add_node("root".to_string(), None).unwrap();
+        root.lock().unwrap().data = Some(Task { done: true });
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        let json = serde_json::to_string(&tree.snapshot()).unwrap();
+
+        root.lock().unwrap().data = Some(Task { done: false });
+        tree.touch(1).unwrap();
+        let mut restored = Tree::<Task>::typed(TreeConfig::default());
+        restored
+            .restore(serde_json::from_str(&json).unwrap(), None)
+            .unwrap();
+
+        assert_eq!(serde_json::to_string(&restored.snapshot()).unwrap(), json);
+        let node = restored.get_node(&1).unwrap();
+        assert_eq!(node.lock().unwrap().data, Some(Task { done: true }));
+        assert_eq!(restored.get_node(&2).unwrap().lock().unwrap().data, None);
+        assert_eq!(Tree::diff(&restored, &tree).changed, vec![1]);
+    }
+
+    #[test]
+    fn renames_many_or_none() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        let update = |id, label: &str| LabelUpdate {
+            id,
+            label: label.to_string(),
+        };
+
+        let result = tree.rename_many(vec![update(1, "renamed"), update(9, "missing")]);
+        assert!(matches!(result, Err(RenameError::NodeNotFound(9))));
+        let result = tree.rename_many(vec![update(1, "renamed"), update(2, "  ")]);
+        assert!(matches!(result, Err(RenameError::EmptyLabel(2))));
+        assert_eq!(tree.get_node(&1).unwrap().lock().unwrap().label, "root");
+
+        tree.rename_many(vec![update(1, " top "), update(2, "b")])
+            .unwrap();
+        assert_eq!(tree.get_node(&1).unwrap().lock().unwrap().label, "top");
+        assert_eq!(tree.get_node(&2).unwrap().lock().unwrap().label, "b");
+    }
+
+    #[test]
+    fn renames_check_final_sibling_labels() {
+        let mut tree = Tree::with_config(TreeConfig {
+            unique_sibling_labels: true,
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        let update = |id, label: &str| LabelUpdate {
+            id,
+            label: label.to_string(),
+        };
+
+        let result = tree.rename_many(vec![update(2, "b")]);
+        assert!(matches!(
+            result,
+            Err(RenameError::LabelTaken { parent_id: 1, .. })
+        ));
+        // swapping is fine, the check sees both new labels.
+        tree.rename_many(vec![update(2, "b"), update(3, "a")])
+            .unwrap();
+        assert_eq!(child_ids(&tree, 1), vec![2, 3]);
+        assert_eq!(tree.get_node(&2).unwrap().lock().unwrap().label, "b");
+    }
+
+    #[test]
+    fn writes_dot_with_optional_depth_colors() {
+        let mut tree = Tree::default();
+        tree.add_node("root \"r\"".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+
+        assert_eq!(
+            tree.to_dot(false),
+            "digraph tree {\n  1 [label=\"root \\\"r\\\"\"];\n  2 [label=\"a\"];\n  1 -> 2;\n}\n"
+        );
+        let colored = tree.to_dot(true);
+        assert!(colored.contains(r#"1 [label="root \"r\"", style=filled, fillcolor="lightblue"];"#));
+        assert!(colored.contains(r#"2 [label="a", style=filled, fillcolor="palegreen"];"#));
+    }
+
+    #[test]
+    fn counts_subtree_size() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(2)).unwrap();
+        tree.add_node("c".to_string(), Some(1)).unwrap();
+
+        assert_eq!(tree.subtree_size(1), Some(4));
+        assert_eq!(tree.subtree_size(2), Some(2));
+        assert_eq!(tree.subtree_size(4), Some(1));
+        assert_eq!(tree.subtree_size(9), None);
+    }
+
+    #[test]
+    fn finds_labels_by_prefix() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("Robin".to_string(), Some(1)).unwrap();
+        tree.add_node("rook".to_string(), Some(1)).unwrap();
+        tree.add_node("crow".to_string(), Some(2)).unwrap();
+
+        assert_eq!(
+            tree.labels_with_prefix("RO", 10),
+            vec![
+                (2, "Robin".to_string()),
+                (3, "rook".to_string()),
+                (1, "root".to_string())
+            ]
+        );
+        assert_eq!(
+            tree.labels_with_prefix("ro", 1),
+            vec![(2, "Robin".to_string())]
+        );
+        assert!(tree.labels_with_prefix("z", 10).is_empty());
+    }
+
+    #[test]
+    fn validates_adds_without_mutating() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_depth: Some(2),
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+
+        assert_eq!(tree.validate_add("  leaf ", Some(1)).unwrap(), "leaf");
+        assert!(tree.validate_add("leaf", Some(2)).is_err());
+        assert!(tree.validate_add("leaf", Some(9)).is_err());
+        assert!(tree.validate_add(" ", None).is_err());
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.next_id(), 3);
+    }
+
+    #[test]
+    fn adds_node_with_metadata() {
+        let mut tree = Tree::default();
+        let metadata = HashMap::from([("color".to_string(), "red".to_string())]);
+        let node = tree
+            .add_node_with_metadata("root".to_string(), None, metadata)
+            .unwrap();
+        assert_eq!(node.lock().unwrap().metadata["color"], "red");
+    }
+
+    #[test]
+    fn sets_metadata() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+
+        tree.set_metadata(1, "owner".to_string(), "sam".to_string())
+            .unwrap();
+        tree.set_metadata(1, "owner".to_string(), "alex".to_string())
+            .unwrap();
+
+        let node = tree.get_node(&1).unwrap();
+        let node = node.lock().unwrap();
+        assert_eq!(node.metadata.len(), 1);
+        assert_eq!(node.metadata["owner"], "alex");
+        assert!(tree
+            .set_metadata(2, "owner".to_string(), "sam".to_string())
+            .is_none());
+    }
+
+    #[test]
+    fn clones_subtree_with_fresh_ids() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+
+        let clone = tree.clone_subtree(1, None).unwrap();
+
+        assert_eq!(tree.len(), 4);
+        assert_eq!(clone.lock().unwrap().id, 3);
+        assert_eq!(clone.lock().unwrap().label, "root");
+        assert_eq!(child_ids(&tree, 3), vec![4]);
+        assert_eq!(tree.get_node(&4).unwrap().lock().unwrap().label, "child");
+        assert_eq!(child_ids(&tree, 1), vec![2]);
+        assert_eq!(Vec::<RcNodeRef>::from(&tree).len(), 2);
+    }
+
+    #[test]
+    fn clones_subtree_under_its_own_descendant() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+
+        tree.clone_subtree(1, Some(2)).unwrap();
+
+        assert_eq!(tree.len(), 6);
+        assert_eq!(child_ids(&tree, 2), vec![4]);
+        assert_eq!(child_ids(&tree, 4), vec![5, 6]);
+    }
+
+    #[test]
+    fn clone_respects_size_limit_without_partial_inserts() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_nodes: Some(3),
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+
+        let result = tree.clone_subtree(1, None);
+
+        assert!(matches!(result, Err(CloneError::LimitExceeded(_))));
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn cannot_clone_missing_nodes() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+
+        assert!(matches!(
+            tree.clone_subtree(2, None),
+            Err(CloneError::NodeNotFound(2))
+        ));
+        assert!(matches!(
+            tree.clone_subtree(1, Some(2)),
+            Err(CloneError::ParentNotFound(2))
+        ));
+    }
+
+    #[test]
+    fn swaps_nodes_across_branches() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("a1".to_string(), Some(2)).unwrap(); // 4
+        tree.add_node("a2".to_string(), Some(2)).unwrap(); // 5
+        tree.add_node("b1".to_string(), Some(3)).unwrap(); // 6
+        tree.add_node("b1x".to_string(), Some(6)).unwrap(); // 7
+
+        tree.swap_nodes(4, 6).unwrap();
+
+        assert_eq!(child_ids(&tree, 2), vec![6, 5]);
+        assert_eq!(child_ids(&tree, 3), vec![4]);
+        assert_eq!(tree.parent_to_child[&2], vec![6, 5]);
+        assert_eq!(tree.parent_to_child[&3], vec![4]);
+        assert_eq!(tree.child_to_parent[&6], 2);
+        assert_eq!(tree.child_to_parent[&4], 3);
+        assert_eq!(child_ids(&tree, 6), vec![7]);
+    }
+
+    #[test]
+    fn swaps_siblings_and_roots() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        tree.add_node("other".to_string(), None).unwrap();
+
+        tree.swap_nodes(2, 3).unwrap();
+        assert_eq!(child_ids(&tree, 1), vec![3, 2]);
+
+        tree.swap_nodes(3, 4).unwrap();
+        assert_eq!(child_ids(&tree, 1), vec![4, 2]);
+        assert!(!tree.child_to_parent.contains_key(&3));
+        assert_eq!(tree.child_to_parent[&4], 1);
+    }
+
+    #[test]
+    fn cannot_swap_with_ancestor() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        tree.add_node("grandchild".to_string(), Some(2)).unwrap();
+
+        assert!(matches!(
+            tree.swap_nodes(1, 3),
+            Err(SwapError::IsAncestor {
+                ancestor: 1,
+                descendant: 3
+            })
+        ));
+        assert!(matches!(
+            tree.swap_nodes(3, 2),
+            Err(SwapError::IsAncestor {
+                ancestor: 2,
+                descendant: 3
+            })
+        ));
+        assert_eq!(child_ids(&tree, 1), vec![2]);
+        assert!(matches!(
+            tree.swap_nodes(1, 9),
+            Err(SwapError::NodeNotFound(9))
+        ));
+    }
+
+    #[test]
+    fn ensure_root_creates_root_in_empty_tree() {
+        let mut tree = Tree::default();
+
+        let root = tree.ensure_root("root").unwrap();
+
+        assert_eq!(root.lock().unwrap().id, 1);
+        assert_eq!(root.lock().unwrap().label, "root");
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn ensure_root_reuses_single_root() {
+        let mut tree = Tree::default();
+        tree.add_node("existing".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+
+        let root = tree.ensure_root("root").unwrap();
+
+        assert_eq!(root.lock().unwrap().label, "existing");
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn ensure_root_rejects_multiple_roots() {
+        let mut tree = Tree::default();
+        tree.add_node("a".to_string(), None).unwrap();
+        tree.add_node("b".to_string(), None).unwrap();
+
+        let result = tree.ensure_root("root");
+
+        assert!(matches!(result, Err(EnsureRootError::MultipleRoots(ids)) if ids == vec![1, 2]));
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn transforms_into_roots_sorted_by_id() {
+        let mut tree = Tree::default();
+        for label in ["a", "b", "c", "d", "e"] {
+            tree.add_node(label.to_string(), None).unwrap();
+        }
+        let ids: Vec<i32> = Vec::<RcNodeRef>::from(&tree)
+            .iter()
+            .map(|node| node.lock().unwrap().id)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn restores_snapshot_with_maps_and_next_id() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        tree.set_metadata(2, "k".to_string(), "v".to_string());
+        tree.move_node(3, 1, 0).unwrap();
+        let snapshot = tree.snapshot();
+        assert_eq!(
+            snapshot
+                .nodes
+                .iter()
+                .map(|node| node.id)
+                .collect::<Vec<_>>(),
+            vec![1, 3, 2]
+        );
+
+        tree.add_node("c".to_string(), Some(2)).unwrap();
+        tree.restore(snapshot, None).unwrap();
+
+        assert_eq!(tree.len(), 3);
+        assert_eq!(child_ids(&tree, 1), vec![3, 2]);
+        assert!(tree.is_ancestor(1, 2));
+        // ids carry on from the snapshot's next_id.
+        let node = tree.add_node("d".to_string(), Some(2)).unwrap();
+        assert_eq!(node.lock().unwrap().id, 4);
+        assert_eq!(child_ids(&tree, 2), vec![4]);
+    }
+
+    #[test]
+    fn rejects_invalid_snapshots_without_changes() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_depth: Some(2),
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        let node = |id, parent_id| SnapshotNode {
+            id,
+            label: "root".to_string(),
+            parent_id,
+            metadata: HashMap::new(),
+            sort_key: None,
+            data: None,
+            updated_at: None,
+        };
+        let before = tree.snapshot();
+
+        let invalid = [
+            vec![node(1, None), node(1, None)],
+            vec![node(2, Some(1)), node(1, None)],
+            vec![node(1, None), node(2, Some(1)), node(3, Some(2))],
+        ];
+        for nodes in invalid {
+            assert!(tree.restore(Snapshot { next_id: 4, nodes }, None).is_err());
+        }
+        assert_eq!(tree.snapshot(), before);
+    }
+
+    #[test]
+    fn diffs_added_removed_relabeled_and_moved_nodes() {
+        let mut before = Tree::default();
+        before.add_node("root".to_string(), None).unwrap();
+        before.add_node("a".to_string(), Some(1)).unwrap();
+        before.add_node("b".to_string(), Some(1)).unwrap();
+        before.add_node("c".to_string(), Some(1)).unwrap();
+
+        let mut after = Tree::default();
+        let mut snapshot = before.snapshot();
+        snapshot.nodes.retain(|node| node.id != 4);
+        snapshot.nodes[1].label = "renamed".to_string();
+        after.restore(snapshot, None).unwrap();
+        after.add_node("d".to_string(), Some(2)).unwrap();
+        after.move_node(3, 2, 0).unwrap();
+
+        assert_eq!(
+            Tree::diff(&before, &after),
+            TreeDiff {
+                added: vec![5],
+                removed: vec![4],
+                relabeled: vec![2],
+                moved: vec![3],
+                changed: vec![],
+            }
+        );
+        assert_eq!(Tree::diff(&before, &before), TreeDiff::default());
+    }
+
+    #[test]
+    fn finds_lowest_common_ancestor() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("a1".to_string(), Some(2)).unwrap(); // 4
+        tree.add_node("a2".to_string(), Some(2)).unwrap(); // 5
+        tree.add_node("a1x".to_string(), Some(4)).unwrap(); // 6
+
+        assert_eq!(tree.lca(6, 5), Some(2));
+        assert_eq!(tree.lca(5, 6), Some(2));
+        assert_eq!(tree.lca(6, 3), Some(1));
+        assert_eq!(tree.lca(4, 6), Some(4));
+        assert_eq!(tree.lca(4, 4), Some(4));
+    }
+
+    #[test]
+    fn lca_is_none_across_roots() {
+        let mut tree = Tree::default();
+        tree.add_node("a".to_string(), None).unwrap();
+        tree.add_node("b".to_string(), None).unwrap();
+        tree.add_node("b1".to_string(), Some(2)).unwrap();
+
+        assert_eq!(tree.lca(1, 3), None);
+        assert_eq!(tree.lca(1, 9), None);
+    }
+
+    #[test]
+    fn measures_distance_between_nodes() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("a1".to_string(), Some(2)).unwrap(); // 4
+        tree.add_node("b1".to_string(), Some(3)).unwrap(); // 5
+        tree.add_node("other".to_string(), None).unwrap(); // 6
+
+        assert_eq!(tree.distance(1, 2), Some(1));
+        assert_eq!(tree.distance(2, 1), Some(1));
+        assert_eq!(tree.distance(4, 5), Some(4));
+        assert_eq!(tree.distance(4, 3), Some(3));
+        assert_eq!(tree.distance(4, 4), Some(0));
+        assert_eq!(tree.distance(4, 6), None);
+    }
+
+    #[test]
+    fn verifies_consistent_tree() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        tree.add_node("c".to_string(), Some(2)).unwrap();
+        tree.move_node(4, 3, 0).unwrap();
+        tree.swap_nodes(2, 3).unwrap();
+        tree.clone_subtree(3, None).unwrap();
+
+        assert_eq!(tree.verify(), Ok(()));
+    }
+
+    #[test]
+    fn verify_reports_discrepancies() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.parent_to_child.remove(&1);
+
+        assert_eq!(
+            tree.verify(),
+            Err(vec![
+                "node 1 has children [2] but parent_to_child has []".to_string(),
+                "child_to_parent 2 -> 1 is missing from parent_to_child".to_string(),
+                "node 2 is not reachable from any root".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn lists_direct_children_only() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("a1".to_string(), Some(2)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+
+        let ids = |id| {
+            tree.children_of(id).map(|children| {
+                children
+                    .iter()
+                    .map(|child| child.lock().unwrap().id)
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(ids(1), Some(vec![2, 4]));
+        assert_eq!(ids(4), Some(vec![]));
+        assert_eq!(ids(9), None);
+    }
+
+    #[test]
+    fn prunes_a_single_level_of_leaves() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("a1".to_string(), Some(2)).unwrap();
+        tree.add_node("a2".to_string(), Some(2)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        tree.add_node("lone".to_string(), None).unwrap();
+
+        assert_eq!(tree.prune_leaves(), vec![3, 4, 5, 6]);
+        assert_eq!(tree.ids(), vec![1, 2]);
+        assert_eq!(child_ids(&tree, 1), vec![2]);
+        assert_eq!(child_ids(&tree, 2), Vec::<i32>::new());
+        assert_eq!(tree.verify(), Ok(()));
+
+        assert_eq!(tree.prune_leaves(), vec![2]);
+        assert_eq!(tree.prune_leaves(), vec![1]);
+        assert_eq!(tree.prune_leaves(), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn measures_level_widths() {
+        let mut tree = Tree::default();
+        assert_eq!(tree.level_widths(), Vec::<usize>::new());
+
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        tree.add_node("a1".to_string(), Some(2)).unwrap();
+        tree.add_node("b1".to_string(), Some(3)).unwrap();
+        tree.add_node("b2".to_string(), Some(3)).unwrap();
+        tree.add_node("other".to_string(), None).unwrap();
+
+        assert_eq!(tree.level_widths(), vec![2, 2, 3]);
+    }
+
+    #[test]
+    fn finds_root_of_node() {
+        let mut tree = Tree::default();
+        tree.add_node("other".to_string(), None).unwrap();
+        tree.add_node("root".to_string(), None).unwrap();
+        for parent_id in 2..6 {
+            tree.add_node("child".to_string(), Some(parent_id)).unwrap();
+        }
+
+        let root_id = |id| tree.root_of(id).map(|root| root.lock().unwrap().id);
+        assert_eq!(root_id(6), Some(2));
+        assert_eq!(root_id(2), Some(2));
+        assert_eq!(root_id(1), Some(1));
+        assert_eq!(root_id(9), None);
+    }
+
+    #[test]
+    fn checks_strict_ancestry() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        tree.add_node("grandchild".to_string(), Some(2)).unwrap();
+        tree.add_node("other".to_string(), None).unwrap();
+
+        assert!(tree.is_ancestor(1, 3));
+        assert!(tree.is_ancestor(2, 3));
+        assert!(!tree.is_ancestor(3, 1));
+        assert!(!tree.is_ancestor(4, 3));
+        assert!(!tree.is_ancestor(2, 2));
+    }
+
+    #[test]
+    fn edge_errors_propagate() {
+        let mut tree = Tree::default();
+        let result = tree.add_node("root".to_string(), Some(2));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn can_add_connections_to_tree() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        let child = tree.add_node("child".to_string(), None).unwrap();
+        tree.add_edge(1, child).unwrap();
+        assert_eq!(tree.child_to_parent.len(), 1);
+        assert_eq!(tree.parent_to_child.len(), 1);
+        assert_eq!(tree.parent_to_child.get(&1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn cannot_add_edge_with_self() {
+        let mut tree = Tree::default();
+        let parent = tree.add_node("root".to_string(), None).unwrap();
+
+        let result = tree.add_edge(1, parent);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn cannot_override_edge() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        let child = tree.add_node("child".to_string(), Some(1)).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        let result = tree.add_edge(3, child);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn cannot_add_edge_with_nonexistent_parent() {
+        let mut tree = Tree::default();
+        let node = tree.add_node("root".to_string(), None).unwrap();
+        let result = tree.add_edge(3, node);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn contains_only_inserted_ids() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        assert!(tree.contains(1));
+        assert!(!tree.contains(2));
+    }
+
+    fn child_ids(tree: &Tree, id: i32) -> Vec<i32> {
+        tree.get_node(&id)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .children()
+            .iter()
+            .map(|child| child.lock().unwrap().id)
+            .collect()
+    }
+
+    #[test]
+    fn moves_node_to_position_under_new_parent() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        tree.add_node("c".to_string(), Some(2)).unwrap();
+
+        tree.move_node(3, 2, 0).unwrap();
+
+        assert_eq!(child_ids(&tree, 2), vec![3, 4]);
+        assert_eq!(tree.parent_to_child[&2], vec![3, 4]);
+        assert_eq!(child_ids(&tree, 1), vec![2]);
+        assert_eq!(tree.parent_to_child[&1], vec![2]);
+        assert_eq!(tree.child_to_parent[&3], 2);
+    }
+
+    #[test]
+    fn move_clamps_index_to_end() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), None).unwrap();
+
+        tree.move_node(3, 1, 99).unwrap();
+
+        assert_eq!(child_ids(&tree, 1), vec![2, 3]);
+        assert_eq!(Vec::<RcNodeRef>::from(&tree).len(), 1);
+    }
+
+    #[test]
+    fn move_removes_empty_parent_entries() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("other".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+
+        tree.move_node(3, 2, 0).unwrap();
+
+        assert!(!tree.parent_to_child.contains_key(&1));
+        assert_eq!(tree.parent_to_child[&2], vec![3]);
+    }
+
+    #[test]
+    fn cannot_move_node_under_its_descendant() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        tree.add_node("grandchild".to_string(), Some(2)).unwrap();
+
+        assert!(matches!(
+            tree.move_node(1, 3, 0),
+            Err(MoveError::Cycle { .. })
+        ));
+        assert!(matches!(
+            tree.move_node(2, 2, 0),
+            Err(MoveError::Cycle { .. })
+        ));
+        assert_eq!(tree.child_to_parent[&3], 2);
+    }
+
+    #[test]
+    fn collects_descendants() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(2)).unwrap();
+        tree.add_node("c".to_string(), Some(3)).unwrap();
+        tree.add_node("d".to_string(), Some(1)).unwrap();
+
+        assert_eq!(tree.descendants(2), HashSet::from([3, 4]));
+        assert_eq!(tree.descendants(1), HashSet::from([2, 3, 4, 5]));
+        assert!(tree.descendants(4).is_empty());
+    }
+
+    #[test]
+    fn validates_moves_without_mutating() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        tree.add_node("grandchild".to_string(), Some(2)).unwrap();
+        tree.add_node("other".to_string(), None).unwrap();
+
+        assert!(tree.validate_move(2, 4).is_ok());
+        assert!(matches!(
+            tree.validate_move(1, 3),
+            Err(MoveError::Cycle { .. })
+        ));
+        assert!(matches!(
+            tree.validate_move(1, 1),
+            Err(MoveError::Cycle { .. })
+        ));
+        assert_eq!(tree.child_to_parent[&2], 1);
+    }
+
+    #[test]
+    fn moving_subtree_carries_descendants_and_keeps_other_orders() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("c".to_string(), Some(1)).unwrap(); // 4
+        tree.add_node("a1".to_string(), Some(2)).unwrap(); // 5
+        tree.add_node("a2".to_string(), Some(2)).unwrap(); // 6
+        tree.add_node("c1".to_string(), Some(4)).unwrap(); // 7
+        tree.add_node("c2".to_string(), Some(4)).unwrap(); // 8
+
+        tree.move_node(2, 4, 1).unwrap();
+
+        assert_eq!(child_ids(&tree, 1), vec![3, 4]);
+        assert_eq!(child_ids(&tree, 4), vec![7, 2, 8]);
+        assert_eq!(child_ids(&tree, 2), vec![5, 6]);
+        assert_eq!(tree.parent_to_child[&2], vec![5, 6]);
+        assert_eq!(tree.child_to_parent[&5], 2);
+        assert_eq!(tree.child_to_parent[&6], 2);
+        assert_eq!(tree.descendants(4), HashSet::from([2, 5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn reorders_node_to_front_and_back() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        for label in ["a", "b", "c"] {
+            tree.add_node(label.to_string(), Some(1)).unwrap();
+        }
+
+        tree.reorder(3, SiblingEnd::Front).unwrap();
+        assert_eq!(child_ids(&tree, 1), vec![3, 2, 4]);
+        tree.reorder(2, SiblingEnd::Back).unwrap();
+        assert_eq!(child_ids(&tree, 1), vec![3, 4, 2]);
+        assert_eq!(tree.verify(), Ok(()));
+
+        assert!(matches!(
+            tree.reorder(1, SiblingEnd::Front),
+            Err(MoveError::IsRoot(1))
+        ));
+        assert!(matches!(
+            tree.reorder(9, SiblingEnd::Back),
+            Err(MoveError::NodeNotFound(9))
+        ));
+    }
+
+    #[test]
+    fn cannot_move_missing_nodes() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+
+        assert!(matches!(
+            tree.move_node(2, 1, 0),
+            Err(MoveError::NodeNotFound(2))
+        ));
+        assert!(matches!(
+            tree.move_node(1, 2, 0),
+            Err(MoveError::ParentNotFound(2))
+        ));
+    }
+
+    #[test]
+    fn transforms_into() {
+        let mut tree = Tree::default();
+
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        let nodes = Vec::<RcNodeRef>::from(&tree);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn transforms_into_with_multiple_children() {
+        let mut tree = Tree::default();
+
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        tree.add_node("child".to_string(), Some(1)).unwrap();
+        let nodes = Vec::<RcNodeRef>::from(&tree);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].lock().unwrap().len(), 2);
+    }
 
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+    #[test]
+    fn compact_renumbers_densely_in_pre_order() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("b1".to_string(), Some(3)).unwrap(); // 4
+        tree.add_node("b1a".to_string(), Some(4)).unwrap(); // 5
+        tree.add_node("other".to_string(), None).unwrap(); // 6
+        tree.prune_leaves(); // removes 2, 5 and 6
 
-pub struct Tree {
-    next_id: i32,
-    // For now this will double as a in-memory store, where the node id is 1 + the node's index.
-    lookup: HashMap<i32, RcNodeRef>,
-    // enforcing that child can only have one parent, to prevent the tree from becoming a graph.
-    // If this was a database we would have a unique constraint on the child_id column.
-    // Its faster to track at insertion time than to check on every query.
-    child_to_parent: HashMap<i32, i32>,
-    parent_to_child: HashMap<i32, Vec<i32>>,
-}
+        let mapping = tree.compact();
 
-impl Default for Tree {
-    fn default() -> Self {
-        Tree {
-            child_to_parent: HashMap::new(),
-            parent_to_child: HashMap::new(),
-            lookup: HashMap::new(),
-            next_id: 1,
-        }
+        assert_eq!(mapping, HashMap::from([(1, 1), (3, 2), (4, 3)]));
+        assert_eq!(tree.ids(), vec![1, 2, 3]);
+        assert_eq!(
+            crate::node::to_json(&tree.roots()),
+            r#"[{"id":1,"label":"root","children":[{"id":2,"label":"b","children":[{"id":3,"label":"b1","children":[]}]}]}]"#
+        );
+        assert_eq!(tree.child_to_parent, HashMap::from([(2, 1), (3, 2)]));
+        assert!(tree.verify().is_ok());
+        assert_eq!(
+            tree.add_node("c".to_string(), Some(1))
+                .unwrap()
+                .lock()
+                .unwrap()
+                .id,
+            4
+        );
     }
-}
 
-#[derive(Debug)]
-pub struct AddNodeError {
-    pub message: String,
-}
+    #[test]
+    fn panicking_add_leaves_tree_untouched() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        // a poisoned parent makes the add panic once it gets as far as locking it.
+        let root = tree.get_node(&1).unwrap();
+        let _ = std::thread::spawn({
+            let root = root.clone();
+            move || {
+                let _guard = root.lock().unwrap();
+                panic!("poisoning the root");
+            }
+        })
+        .join();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tree.add_node("b".to_string(), Some(1))
+        }));
+        assert!(result.is_err());
 
-impl AddNodeError {
-    fn new(message: String) -> Self {
-        AddNodeError { message }
+        root.clear_poison();
+        assert!(tree.verify().is_ok());
+        assert_eq!(tree.ids(), vec![1, 2]);
+        assert_eq!(tree.child_to_parent, HashMap::from([(2, 1)]));
+        assert_eq!(tree.parent_to_child, HashMap::from([(1, vec![2])]));
+        assert!(tree.find_by_label("b").is_empty());
+        assert_eq!(tree.next_id(), 3);
     }
-}
 
-impl Tree {
-    pub fn add_node(
-        &mut self,
-        label: String,
-        parent_id: Option<i32>,
-    ) -> Result<RcNodeRef, AddNodeError> {
-        let id = self.next_id;
-        let node = as_rc_ref(Node::new(id, label));
-        if let Some(parent_id) = parent_id {
-            self.add_edge(parent_id, node.clone())?;
-        }
-        self.lookup.insert(id, node.clone());
-        self.next_id = id + 1;
-        return Ok(node.clone());
+    #[test]
+    fn add_goes_through_a_poisoned_label_index() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = tree.by_label.lock().unwrap();
+            panic!("poisoning the label index");
+        }));
+
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        assert!(tree.verify().is_ok());
+        assert_eq!(tree.ids(), vec![1, 2]);
     }
 
-    fn add_edge(&mut self, parent_id: i32, child_ref: RcNodeRef) -> Result<(), AddNodeError> {
-        let child = child_ref.lock().unwrap();
-        if parent_id == child.id {
-            return Err(AddNodeError::new(format!(
-                "Cannot add connection, parent and child are the same node: {}",
-                parent_id
-            )));
-        }
+    #[test]
+    fn label_pattern_rejects_other_characters() {
+        let mut tree = Tree::with_config(TreeConfig {
+            label_pattern: Some(crate::config::label_pattern(r"[\w .-]+").unwrap()),
+            ..TreeConfig::default()
+        });
 
-        if self.child_to_parent.contains_key(&child.id) {
-            return Err(AddNodeError::new(format!(
-                "Cannot add connection, child {} already has a parent",
-                child.id
-            )));
-        }
+        assert!(tree
+            .add_node("Quarterly report v2".to_string(), None)
+            .is_ok());
+        assert!(matches!(
+            tree.add_node("two\nlines".to_string(), None),
+            Err(AddNodeError::InvalidLabel(_))
+        ));
+        assert!(matches!(
+            tree.add_node("<script>".to_string(), None),
+            Err(AddNodeError::InvalidLabel(_))
+        ));
+        assert!(matches!(
+            tree.rename_node(1, "bell\u{7}"),
+            Err(RenameError::InvalidLabel(1))
+        ));
+        assert!(matches!(
+            tree.rename_many(vec![LabelUpdate {
+                id: 1,
+                label: "a/b".to_string()
+            }]),
+            Err(RenameError::InvalidLabel(1))
+        ));
+        assert_eq!(tree.len(), 1);
+    }
 
-        // we could turn this into a map lookup by changing the way we store nodes from a vec to a
-        // hashmap
-        if let None = self.lookup.get(&parent_id) {
-            return Err(AddNodeError::new(format!(
-                "Cannot add connection, parent {} does not exist",
-                parent_id
-            )));
-        };
+    #[test]
+    fn max_roots_rejects_another_root() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_roots: Some(1),
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
 
-        self.child_to_parent.insert(child.id, parent_id);
-        let mut parent = self.lookup[&parent_id].lock().unwrap();
-        parent.add_child(child_ref.clone());
-        self.parent_to_child
-            .entry(parent_id)
-            .or_insert(vec![])
-            .push(child.id);
-        return Ok(());
+        assert!(matches!(
+            tree.add_node("second".to_string(), None),
+            Err(AddNodeError::TooManyRoots { max_roots: 1 })
+        ));
+        assert!(tree.add_node("child".to_string(), Some(1)).is_ok());
+        assert!(tree.add_node("grandchild".to_string(), Some(2)).is_ok());
+        assert_eq!(tree.roots().len(), 1);
+        // the id wasn't burnt by the rejected root.
+        assert_eq!(tree.next_id(), 4);
     }
 
-    pub fn len(&self) -> i32 {
-        self.lookup.keys().len() as i32
+    #[test]
+    fn writes_nested_markdown_list() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a *bold* one".to_string(), Some(1)).unwrap();
+        tree.add_node("a1".to_string(), Some(2)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        tree.add_node("other".to_string(), None).unwrap();
+
+        assert_eq!(
+            tree.to_markdown(false),
+            "- root\n  - a *bold* one\n    - a1\n  - b\n- other\n"
+        );
+        assert!(tree.to_markdown(true).contains("  - a \\*bold\\* one\n"));
+        assert_eq!(Tree::default().to_markdown(false), "");
     }
 
-    pub fn get_node(&self, index: &i32) -> Option<RcNodeRef> {
-        match self.lookup.get(index) {
-            Some(value) => Some(value.clone()),
-            None => None,
-        }
+    #[test]
+    fn move_after_places_node_behind_sibling() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("c".to_string(), Some(1)).unwrap(); // 4
+        tree.add_node("other".to_string(), None).unwrap(); // 5
+
+        tree.move_after(5, 1, Some(2)).unwrap();
+        assert_eq!(tree.parent_to_child[&1], vec![2, 5, 3, 4]);
+        // within the same parent, moving forward past later siblings.
+        tree.move_after(2, 1, Some(4)).unwrap();
+        assert_eq!(tree.parent_to_child[&1], vec![5, 3, 4, 2]);
+        tree.move_after(4, 1, None).unwrap();
+        assert_eq!(tree.parent_to_child[&1], vec![4, 5, 3, 2]);
+
+        assert!(matches!(
+            tree.move_after(3, 1, Some(3)),
+            Err(MoveError::NotASibling { .. })
+        ));
+        assert!(matches!(
+            tree.move_after(3, 2, Some(4)),
+            Err(MoveError::NotASibling {
+                parent_id: 2,
+                sibling_id: 4
+            })
+        ));
+        assert_eq!(tree.parent_to_child[&1], vec![4, 5, 3, 2]);
     }
-}
 
-impl<'a> From<&Tree> for Vec<Arc<Mutex<Node>>> {
-    fn from(value: &Tree) -> Self {
-        let root_ids: Vec<&i32> = value
-            .lookup
-            .keys()
-            .filter(|key| !value.child_to_parent.contains_key(key))
-            .collect();
-        root_ids
-            .into_iter()
-            .map(|id| value.lookup[id].clone())
-            .collect()
+    #[test]
+    fn move_node_returns_the_old_parent() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("other".to_string(), None).unwrap();
+
+        assert_eq!(tree.move_node(2, 3, 0).unwrap(), Some(1));
+        assert_eq!(tree.move_node(1, 2, 0).unwrap(), None);
+        assert_eq!(tree.move_after(1, 3, Some(2)).unwrap(), Some(2));
     }
-}
 
-#[cfg(test)]
-mod test {
+    #[test]
+    fn collapses_single_child_chains() {
+        let mut tree = Tree::default();
+        tree.add_node("A".to_string(), None).unwrap(); // 1
+        tree.add_node("B".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("C".to_string(), Some(2)).unwrap(); // 3
+        tree.add_node("D".to_string(), Some(3)).unwrap(); // 4
+        tree.add_node("x".to_string(), Some(4)).unwrap(); // 5
+        tree.add_node("y".to_string(), Some(4)).unwrap(); // 6
 
-    use super::*;
+        assert_eq!(tree.single_child_chain(1), vec![2, 3]);
+        let node = tree.collapse_chain(1, "/").unwrap();
+        assert_eq!(node.lock().unwrap().label, "A/B/C");
+        assert_eq!(tree.ids(), vec![1, 4, 5, 6]);
+        assert_eq!(tree.parent_to_child[&1], vec![4]);
+        assert_eq!(tree.child_to_parent[&4], 1);
+        assert_eq!(
+            crate::node::to_json(&tree.roots()),
+            r#"[{"id":1,"label":"A/B/C","children":[{"id":4,"label":"D","children":[{"id":5,"label":"x","children":[]},{"id":6,"label":"y","children":[]}]}]}]"#
+        );
+
+        // branching and leaves have nothing to collapse.
+        tree.collapse_chain(4, "/").unwrap();
+        tree.collapse_chain(5, "/").unwrap();
+        assert_eq!(tree.ids(), vec![1, 4, 5, 6]);
+        assert!(matches!(
+            tree.collapse_chain(9, "/"),
+            Err(CollapseError::NodeNotFound(9))
+        ));
+    }
 
     #[test]
-    fn builds_default_tree() {
-        let tree = Tree::default();
+    fn touch_moves_updated_at_forward() {
+        let mut tree = Tree::default();
+        let node = tree.add_node("root".to_string(), None).unwrap();
+        let created = node.lock().unwrap().updated_at;
+
+        tree.touch(1).unwrap();
+        let touched = node.lock().unwrap().updated_at;
+        assert!(touched > created);
+        tree.touch(1).unwrap();
+        assert!(node.lock().unwrap().updated_at > touched);
+        assert!(matches!(tree.touch(2), Err(TouchError::NodeNotFound(2))));
+    }
+
+    #[test]
+    fn flattens_children_into_the_parent() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("x".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 4
+        tree.add_node("c1".to_string(), Some(3)).unwrap(); // 5
+        tree.add_node("c2".to_string(), Some(3)).unwrap(); // 6
+        tree.add_node("g".to_string(), Some(5)).unwrap(); // 7
+
+        assert_eq!(tree.flatten_node(3).unwrap(), 1);
+        assert_eq!(tree.parent_to_child[&1], vec![2, 5, 6, 4]);
+        assert_eq!(tree.child_to_parent[&5], 1);
+        assert_eq!(tree.child_to_parent[&7], 5);
+        assert!(!tree.contains(3));
+        assert_eq!(
+            crate::node::to_json(&tree.roots()),
+            r#"[{"id":1,"label":"root","children":[{"id":2,"label":"a","children":[]},{"id":5,"label":"c1","children":[{"id":7,"label":"g","children":[]}]},{"id":6,"label":"c2","children":[]},{"id":4,"label":"b","children":[]}]}]"#
+        );
+
+        assert!(matches!(tree.flatten_node(1), Err(FlattenError::IsRoot(1))));
+        assert!(matches!(
+            tree.flatten_node(3),
+            Err(FlattenError::NodeNotFound(3))
+        ));
+    }
+
+    #[test]
+    fn flatten_checks_the_parent_limits() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_children: Some(3),
+            unique_sibling_labels: true,
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("a".to_string(), Some(3)).unwrap(); // 4
+        tree.add_node("c".to_string(), Some(2)).unwrap(); // 5
+        tree.add_node("d".to_string(), Some(2)).unwrap(); // 6
+        tree.add_node("e".to_string(), Some(2)).unwrap(); // 7
+
+        assert!(matches!(
+            tree.flatten_node(3),
+            Err(FlattenError::LabelTaken { parent_id: 1, .. })
+        ));
+        assert!(matches!(
+            tree.flatten_node(2),
+            Err(FlattenError::ParentFull {
+                parent_id: 1,
+                max_children: 3
+            })
+        ));
+        assert_eq!(tree.parent_to_child[&2], vec![5, 6, 7]);
+        assert_eq!(tree.parent_to_child[&3], vec![4]);
+        assert_eq!(tree.parent_to_child[&1], vec![2, 3]);
+    }
+
+    #[test]
+    fn removes_overlapping_subtrees_once() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(2)).unwrap(); // 3
+        tree.add_node("c".to_string(), Some(3)).unwrap(); // 4
+        tree.add_node("d".to_string(), Some(1)).unwrap(); // 5
+
+        // 3 goes with 2, 9 never existed.
+        assert_eq!(tree.remove_many(&[2, 3, 9], None).unwrap(), vec![2, 3, 4]);
+        assert_eq!(tree.ids(), vec![1, 5]);
+        assert_eq!(tree.parent_to_child[&1], vec![5]);
+        assert!(!tree.child_to_parent.contains_key(&3));
+        assert_eq!(tree.remove_many(&[2], None).unwrap(), Vec::<i32>::new());
+
+        assert_eq!(tree.remove_many(&[1], None).unwrap(), vec![1, 5]);
         assert_eq!(tree.len(), 0);
-        assert_eq!(tree.child_to_parent.len(), 0);
-        assert_eq!(tree.parent_to_child.len(), 0);
+        assert!(tree.parent_to_child.is_empty());
+    }
+
+    fn import_node(key: Option<&str>, label: &str, parent_key: Option<&str>) -> ImportNode {
+        ImportNode {
+            key: key.map(str::to_string),
+            label: label.to_string(),
+            parent_id: None,
+            parent_key: parent_key.map(str::to_string),
+            metadata: HashMap::new(),
+        }
     }
 
     #[test]
-    fn can_add_node_to_tree() {
+    fn imports_nodes_by_client_key() {
         let mut tree = Tree::default();
-        tree.add_node("root".to_string(), None).unwrap();
-        assert_eq!(tree.len(), 1);
-        let node = tree.lookup[&1].lock().unwrap();
-        assert_eq!(node.id, 1);
-        assert_eq!(node.label, "root");
+        tree.add_node("existing".to_string(), None).unwrap();
+
+        let imported = tree
+            .import(
+                vec![
+                    import_node(Some("tmp1"), "a", None),
+                    import_node(None, "b", Some("tmp1")),
+                    import_node(Some("tmp2"), "c", Some("tmp1")),
+                ],
+                None,
+            )
+            .unwrap();
+        assert_eq!(imported.ids, vec![2, 3, 4]);
+        assert_eq!(
+            imported.id_map,
+            HashMap::from([("tmp1".to_string(), 2), ("tmp2".to_string(), 4)])
+        );
+        assert_eq!(tree.parent_to_child[&2], vec![3, 4]);
     }
 
     #[test]
-    fn can_add_node_to_tree_with_parent() {
+    fn failed_import_leaves_no_trace() {
         let mut tree = Tree::default();
-        tree.add_node("root".to_string(), None).unwrap();
-        tree.add_node("child".to_string(), Some(1)).unwrap();
-        assert_eq!(tree.len(), 2);
-        let arc = tree.get_node(&2).unwrap();
-        let node = arc.lock().unwrap();
-        assert_eq!(node.id, 2);
-        assert_eq!(node.label, "child");
-        assert_eq!(tree.child_to_parent.len(), 1);
-        assert_eq!(tree.parent_to_child.len(), 1);
-        assert_eq!(tree.parent_to_child.get(&1).unwrap().len(), 1);
+        tree.add_node("existing".to_string(), None).unwrap();
+
+        let result = tree.import(
+            vec![
+                import_node(Some("tmp1"), "a", None),
+                import_node(Some("tmp2"), "b", Some("tmp1")),
+                import_node(None, "c", Some("nope")),
+            ],
+            None,
+        );
+        assert!(matches!(
+            result,
+            Err(ImportError::UnknownKey { index: 2, .. })
+        ));
+        assert_eq!(tree.ids(), vec![1]);
+        assert!(tree.parent_to_child.is_empty());
+        assert_eq!(tree.next_id(), 2);
+
+        let result = tree.import(
+            vec![
+                import_node(Some("tmp1"), "a", None),
+                import_node(Some("tmp1"), "b", None),
+            ],
+            None,
+        );
+        assert!(matches!(
+            result,
+            Err(ImportError::DuplicateKey { index: 1, .. })
+        ));
+        assert_eq!(tree.ids(), vec![1]);
     }
 
     #[test]
-    fn edge_errors_propagate() {
+    fn compacted_and_restored_copy_is_structurally_equal() {
         let mut tree = Tree::default();
-        let result = tree.add_node("root".to_string(), Some(2));
-        assert!(result.is_err());
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("gone".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 4
+        tree.add_node("a1".to_string(), Some(3)).unwrap(); // 5
+        tree.add_node("other".to_string(), None).unwrap(); // 6
+        tree.remove_many(&[2], None).unwrap();
+
+        let mut copy = Tree::default();
+        copy.restore(tree.snapshot(), None).unwrap();
+        copy.compact();
+        assert_eq!(copy.ids(), vec![1, 2, 3, 4, 5]);
+        let mut restored = Tree::default();
+        restored.restore(copy.snapshot(), None).unwrap();
+
+        assert!(tree.structurally_equals(&restored));
+        assert!(restored.structurally_equals(&tree));
+        assert!(tree.structurally_equals(&tree));
+
+        restored.rename_node(4, "c").unwrap();
+        assert!(!tree.structurally_equals(&restored));
+        restored.rename_node(4, "b").unwrap();
+        restored.reorder(4, SiblingEnd::Front).unwrap();
+        assert!(!tree.structurally_equals(&restored));
+        restored.reorder(4, SiblingEnd::Back).unwrap();
+        restored.add_node("extra".to_string(), Some(5)).unwrap();
+        assert!(!tree.structurally_equals(&restored));
     }
 
     #[test]
-    fn can_add_connections_to_tree() {
+    fn best_effort_import_keeps_what_it_can() {
+        let mut tree = Tree::default();
+        tree.add_node("existing".to_string(), None).unwrap();
+
+        let (imported, failed) = tree.import_best_effort(
+            vec![
+                import_node(Some("tmp1"), "a", None),
+                import_node(Some("tmp2"), "b", Some("nope")),
+                import_node(None, "c", Some("tmp2")),
+                import_node(None, "d", Some("tmp1")),
+            ],
+            None,
+        );
+        assert_eq!(imported.ids, vec![2, 3]);
+        assert_eq!(imported.id_map, HashMap::from([("tmp1".to_string(), 2)]));
+        assert_eq!(
+            failed.iter().map(ImportError::index).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(tree.ids(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn resolves_label_paths() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("child".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("leaf".to_string(), Some(2)).unwrap(); // 3
+        tree.add_node("twin".to_string(), Some(1)).unwrap(); // 4
+        tree.add_node("twin".to_string(), Some(1)).unwrap(); // 5
+
+        let id = |result: Result<RcNodeRef, PathError>| result.unwrap().lock().unwrap().id;
+        assert_eq!(id(tree.resolve_path(&["root", "child", "leaf"])), 3);
+        assert_eq!(id(tree.resolve_path(&["root"])), 1);
+        assert!(matches!(
+            tree.resolve_path(&["root", "leaf"]),
+            Err(PathError::NotFound(segment)) if segment == "leaf"
+        ));
+        assert!(matches!(
+            tree.resolve_path(&["root", "twin"]),
+            Err(PathError::Ambiguous(segment)) if segment == "twin"
+        ));
+        assert!(matches!(tree.resolve_path(&[]), Err(PathError::Empty)));
+    }
+
+    #[test]
+    fn groups_duplicate_labels() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("Foo".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("bar".to_string(), Some(2)).unwrap(); // 3
+        tree.add_node("foo".to_string(), Some(3)).unwrap(); // 4
+        tree.add_node("bar".to_string(), Some(1)).unwrap(); // 5
+
+        assert_eq!(tree.duplicate_labels(false), vec![vec![3, 5]]);
+        assert_eq!(tree.duplicate_labels(true), vec![vec![2, 4], vec![3, 5]]);
+    }
+
+    #[test]
+    fn label_index_follows_renames_and_deletes() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(2)).unwrap(); // 3
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 4
+        assert_eq!(tree.find_by_label("a"), vec![2, 4]);
+
+        tree.rename_node(4, "c").unwrap();
+        assert_eq!(tree.find_by_label("a"), vec![2]);
+        assert_eq!(tree.find_by_label("c"), vec![4]);
+        assert!(tree.verify().is_ok());
+
+        tree.remove_many(&[2], None).unwrap();
+        assert!(tree.find_by_label("a").is_empty());
+        assert!(tree.find_by_label("b").is_empty());
+        assert!(tree.verify().is_ok());
+
+        tree.compact();
+        assert_eq!(tree.find_by_label("c"), vec![2]);
+        assert!(tree.verify().is_ok());
+
+        // find_by_label answers from the index, so a label changed behind the tree's back isn't
+        // found.
+        tree.get_node(&2).unwrap().lock().unwrap().label = "d".to_string();
+        assert!(tree.find_by_label("d").is_empty());
+        assert_eq!(tree.find_by_label("c"), vec![2]);
+        assert!(tree.verify().is_err());
+    }
+
+    #[test]
+    fn label_index_follows_every_mutation() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(2)).unwrap(); // 3
+        tree.add_node("c".to_string(), Some(3)).unwrap(); // 4
+        tree.add_node("d".to_string(), Some(4)).unwrap(); // 5
+        tree.add_node("e".to_string(), Some(4)).unwrap(); // 6
+
+        tree.clone_subtree(3, Some(1)).unwrap();
+        assert_eq!(tree.find_by_label("c"), vec![4, 8]);
+        tree.collapse_chain(2, "/").unwrap();
+        assert_eq!(tree.find_by_label("a/b"), vec![2]);
+        assert_eq!(tree.find_by_label("b"), vec![7]);
+        tree.flatten_node(7).unwrap();
+        assert!(tree.find_by_label("b").is_empty());
+        tree.rename_many(vec![LabelUpdate {
+            id: 9,
+            label: "f".to_string(),
+        }])
+        .unwrap();
+        tree.prune_leaves();
+        let mut restored = Tree::default();
+        restored.restore(tree.snapshot(), None).unwrap();
+        for tree in [&tree, &restored] {
+            assert!(tree.verify().is_ok());
+            assert_eq!(tree.find_by_label("a/b"), vec![2]);
+            assert_eq!(tree.find_by_label("c"), vec![4, 8]);
+            assert!(tree.find_by_label("f").is_empty());
+        }
+    }
+
+    #[test]
+    fn nudges_among_siblings() {
         let mut tree = Tree::default();
         tree.add_node("root".to_string(), None).unwrap();
-        let child = tree.add_node("child".to_string(), None).unwrap();
-        tree.add_edge(1, child).unwrap();
-        assert_eq!(tree.child_to_parent.len(), 1);
-        assert_eq!(tree.parent_to_child.len(), 1);
-        assert_eq!(tree.parent_to_child.get(&1).unwrap().len(), 1);
+        for label in ["a", "b", "c"] {
+            tree.add_node(label.to_string(), Some(1)).unwrap();
+        }
+
+        assert!(tree.nudge(3, Direction::Up).unwrap());
+        assert_eq!(tree.parent_to_child[&1], vec![3, 2, 4]);
+        assert!(!tree.nudge(3, Direction::Up).unwrap());
+        assert!(tree.nudge(2, Direction::Down).unwrap());
+        assert_eq!(tree.parent_to_child[&1], vec![3, 4, 2]);
+        assert!(!tree.nudge(2, Direction::Down).unwrap());
+        assert!(tree.verify().is_ok());
+        assert!(matches!(
+            tree.nudge(1, Direction::Up),
+            Err(MoveError::IsRoot(1))
+        ));
+        assert!(matches!(
+            tree.nudge(9, Direction::Up),
+            Err(MoveError::NodeNotFound(9))
+        ));
     }
 
     #[test]
-    fn cannot_add_edge_with_self() {
+    fn locates_nodes_among_their_siblings() {
         let mut tree = Tree::default();
-        let parent = tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        tree.add_node("a".to_string(), Some(1)).unwrap(); // 2
+        tree.add_node("b".to_string(), Some(1)).unwrap(); // 3
+        tree.add_node("other".to_string(), None).unwrap(); // 4
+        tree.reorder(3, SiblingEnd::Front).unwrap();
 
-        let result = tree.add_edge(1, parent);
-        assert!(result.is_err());
+        assert_eq!(tree.locate(3), Some((Some(1), 0)));
+        assert_eq!(tree.locate(2), Some((Some(1), 1)));
+        assert_eq!(tree.locate(1), Some((None, 0)));
+        assert_eq!(tree.locate(4), Some((None, 1)));
+        assert_eq!(tree.locate(9), None);
     }
 
     #[test]
-    fn cannot_override_edge() {
+    fn writes_a_line_per_node() {
         let mut tree = Tree::default();
         tree.add_node("root".to_string(), None).unwrap();
-        let child = tree.add_node("child".to_string(), Some(1)).unwrap();
-        tree.add_node("child".to_string(), Some(1)).unwrap();
-        let result = tree.add_edge(3, child);
-        assert!(result.is_err());
+        tree.add_node("a \"quoted\"".to_string(), Some(1)).unwrap();
+
+        assert_eq!(
+            tree.to_ndjson(),
+            concat!(
+                r#"{"id":1,"label":"root","parent_id":null}"#,
+                "\n",
+                r#"{"id":2,"label":"a \"quoted\"","parent_id":1}"#,
+                "\n",
+            )
+        );
+        assert_eq!(Tree::default().to_ndjson(), "");
     }
 
     #[test]
-    fn cannot_add_edge_with_nonexistent_parent() {
+    fn rebuilds_indexes_from_child_to_parent() {
         let mut tree = Tree::default();
-        let node = tree.add_node("root".to_string(), None).unwrap();
-        let result = tree.add_edge(3, node);
-        assert!(result.is_err());
+        tree.add_node("root".to_string(), None).unwrap(); // 1
+        for label in ["a", "b", "c"] {
+            tree.add_node(label.to_string(), Some(1)).unwrap(); // 2, 3, 4
+        }
+        tree.add_node("a1".to_string(), Some(2)).unwrap(); // 5
+        tree.reorder(2, SiblingEnd::Back).unwrap();
+        assert!(!tree.rebuild_indexes());
+
+        tree.parent_to_child.insert(1, vec![3, 9, 3]);
+        tree.parent_to_child.remove(&2);
+        tree.parent_to_child.insert(4, vec![5]);
+        tree.lookup[&1].lock().unwrap().retain_children(|_| false);
+        tree.by_label.get_mut().unwrap().clear();
+        assert!(tree.verify().is_err());
+
+        assert!(tree.rebuild_indexes());
+        assert!(tree.verify().is_ok());
+        // 3 keeps its place at the front, the others follow by id.
+        assert_eq!(tree.parent_to_child[&1], vec![3, 2, 4]);
+        assert_eq!(tree.parent_to_child[&2], vec![5]);
+        assert!(!tree.parent_to_child.contains_key(&4));
+        assert_eq!(tree.find_by_label("a1"), vec![5]);
+        assert_eq!(
+            crate::node::to_json(&tree.roots()),
+            r#"[{"id":1,"label":"root","children":[{"id":3,"label":"b","children":[]},{"id":2,"label":"a","children":[{"id":5,"label":"a1","children":[]}]},{"id":4,"label":"c","children":[]}]}]"#
+        );
+        assert!(!tree.rebuild_indexes());
     }
 
     #[test]
-    fn transforms_into() {
+    fn restore_rejects_ids_at_i32_max() {
         let mut tree = Tree::default();
+        let snapshot: Snapshot = serde_json::from_value(serde_json::json!({
+            "next_id": 1,
+            "nodes": [{"id": i32::MAX, "label": "root", "parent_id": null}]
+        }))
+        .unwrap();
+        assert!(matches!(
+            tree.restore(snapshot, None),
+            Err(RestoreError::IdOutOfRange(id)) if id == i32::MAX
+        ));
+        assert_eq!(tree.len(), 0);
+    }
 
+    #[test]
+    fn moves_and_swaps_check_sibling_labels_when_unique() {
+        let mut tree = Tree::with_config(TreeConfig {
+            unique_sibling_labels: true,
+            ..TreeConfig::default()
+        });
         tree.add_node("root".to_string(), None).unwrap();
-        tree.add_node("child".to_string(), Some(1)).unwrap();
-        let nodes = Vec::<RcNodeRef>::from(&tree);
-        assert_eq!(nodes.len(), 1);
-        assert_eq!(nodes[0].lock().unwrap().len(), 1);
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        tree.add_node("docs".to_string(), Some(2)).unwrap();
+        tree.add_node("docs".to_string(), Some(3)).unwrap();
+        tree.add_node("notes".to_string(), Some(3)).unwrap();
+
+        assert!(matches!(
+            tree.move_node(4, 3, 0),
+            Err(MoveError::LabelTaken { parent_id: 3, .. })
+        ));
+        assert!(matches!(
+            tree.move_after(4, 3, Some(5)),
+            Err(MoveError::LabelTaken { parent_id: 3, .. })
+        ));
+        // "docs" swapping with "notes" would put a second "docs" under 3.
+        assert!(matches!(
+            tree.swap_nodes(4, 6),
+            Err(SwapError::LabelTaken { parent_id: 3, .. })
+        ));
+        assert_eq!(child_ids(&tree, 2), vec![4]);
+        assert_eq!(child_ids(&tree, 3), vec![5, 6]);
+
+        // same labels trade places, and reordering under the same parent is fine.
+        tree.swap_nodes(4, 5).unwrap();
+        assert_eq!(child_ids(&tree, 2), vec![5]);
+        assert_eq!(child_ids(&tree, 3), vec![4, 6]);
+        tree.move_node(6, 3, 0).unwrap();
+        assert_eq!(child_ids(&tree, 3), vec![6, 4]);
     }
 
     #[test]
-    fn transforms_into_with_multiple_children() {
+    fn import_checks_depth_up_front() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_depth: Some(3),
+            ..TreeConfig::default()
+        });
+        tree.add_node("existing".to_string(), None).unwrap();
+        let nodes = || {
+            vec![
+                import_node(Some("a"), "a", None),
+                import_node(Some("b"), "b", Some("a")),
+                import_node(Some("c"), "c", Some("b")),
+                import_node(None, "d", Some("c")),
+                ImportNode {
+                    parent_id: Some(1),
+                    ..import_node(None, "e", None)
+                },
+            ]
+        };
+
+        assert!(matches!(
+            tree.import(nodes(), None),
+            Err(ImportError::TooDeep {
+                index: 3,
+                max_depth: 3
+            })
+        ));
+        assert_eq!(tree.ids(), vec![1]);
+        assert_eq!(tree.next_id(), 2);
+
+        let (imported, failed) = tree.import_best_effort(nodes(), None);
+        assert_eq!(imported.ids, vec![2, 3, 4, 5]);
+        assert_eq!(failed.len(), 1);
+        assert!(matches!(
+            failed[0],
+            ImportError::TooDeep {
+                index: 3,
+                max_depth: 3
+            }
+        ));
+        assert_eq!(tree.parent_to_child[&1], vec![5]);
+    }
+
+    #[test]
+    fn bulk_writes_stop_once_the_deadline_passes() {
+        let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        let passed = Some(Instant::now());
+
+        assert!(matches!(
+            tree.import(vec![import_node(None, "b", None)], passed),
+            Err(ImportError::TimedOut(0))
+        ));
+        let (imported, failed) = tree.import_best_effort(
+            vec![import_node(None, "b", None), import_node(None, "c", None)],
+            passed,
+        );
+        assert!(imported.ids.is_empty());
+        assert_eq!(failed.len(), 2);
+        assert!(tree.remove_many(&[2], passed).is_err());
+        assert!(matches!(
+            tree.restore(tree.snapshot(), passed),
+            Err(RestoreError::TimedOut)
+        ));
+        assert_eq!(tree.ids(), vec![1, 2]);
+        assert_eq!(tree.next_id(), 3);
+
+        let later = Some(Instant::now() + std::time::Duration::from_secs(60));
+        assert_eq!(tree.remove_many(&[2], later).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn writes_touching_two_nodes_keep_children_in_step() {
         let mut tree = Tree::default();
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(1)).unwrap();
+        tree.add_node("c".to_string(), Some(2)).unwrap();
+        let child_ids = |tree: &Tree, id: i32| -> Vec<i32> {
+            let node = tree.get_node(&id).unwrap();
+            let node = node.lock().unwrap();
+            node.children()
+                .iter()
+                .map(|child| child.lock().unwrap().id)
+                .collect()
+        };
 
+        // under a sibling, then up to the grandparent.
+        tree.move_node(3, 2, 0).unwrap();
+        assert_eq!(child_ids(&tree, 2), vec![3, 4]);
+        tree.move_node(4, 1, 0).unwrap();
+        assert_eq!(child_ids(&tree, 1), vec![4, 2]);
+        tree.flatten_node(2).unwrap();
+        assert_eq!(child_ids(&tree, 1), vec![4, 3]);
+
+        tree.add_node("d".to_string(), Some(3)).unwrap();
+        tree.add_node("e".to_string(), Some(5)).unwrap();
+        tree.collapse_chain(3, "/").unwrap();
+        assert_eq!(child_ids(&tree, 3), vec![6]);
+        assert_eq!(tree.verify(), Ok(()));
+    }
+
+    #[test]
+    fn moves_and_swaps_respect_max_depth() {
+        let mut tree = Tree::with_config(TreeConfig {
+            max_depth: Some(3),
+            ..TreeConfig::default()
+        });
         tree.add_node("root".to_string(), None).unwrap();
-        tree.add_node("child".to_string(), Some(1)).unwrap();
-        tree.add_node("child".to_string(), Some(1)).unwrap();
-        let nodes = Vec::<RcNodeRef>::from(&tree);
-        assert_eq!(nodes.len(), 1);
-        assert_eq!(nodes[0].lock().unwrap().len(), 2);
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        tree.add_node("b".to_string(), Some(2)).unwrap();
+        tree.add_node("other".to_string(), None).unwrap();
+        tree.add_node("c".to_string(), Some(4)).unwrap();
+
+        // a brings b along, the pair would end at depth 4.
+        assert!(matches!(
+            tree.validate_move(2, 5),
+            Err(MoveError::TooDeep {
+                parent_id: 5,
+                max_depth: 3
+            })
+        ));
+        assert!(matches!(
+            tree.move_node(2, 5, 0),
+            Err(MoveError::TooDeep {
+                parent_id: 5,
+                max_depth: 3
+            })
+        ));
+        assert!(tree.move_node(3, 5, 0).is_ok());
+        tree.move_node(3, 2, 0).unwrap();
+
+        assert!(matches!(
+            tree.swap_nodes(3, 4),
+            Err(SwapError::TooDeep {
+                id: 4,
+                max_depth: 3
+            })
+        ));
+        assert!(tree.swap_nodes(2, 5).is_ok());
+        assert_eq!(tree.verify(), Ok(()));
+    }
+
+    #[test]
+    fn auto_root_applies_to_parentless_clones() {
+        let mut tree = Tree::with_config(TreeConfig {
+            auto_root: Some(AutoRoot::Reject),
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        assert!(matches!(
+            tree.clone_subtree(2, None),
+            Err(CloneError::RootExists(1))
+        ));
+        assert_eq!(tree.len(), 2);
+
+        let mut tree = Tree::with_config(TreeConfig {
+            auto_root: Some(AutoRoot::Attach),
+            ..TreeConfig::default()
+        });
+        tree.add_node("root".to_string(), None).unwrap();
+        tree.add_node("a".to_string(), Some(1)).unwrap();
+        let copy = tree.clone_subtree(2, None).unwrap();
+        let copy_id = copy.lock().unwrap().id;
+        assert_eq!(tree.child_to_parent.get(&copy_id), Some(&1));
+    }
+
+    #[test]
+    fn add_edge_errors_name_the_variant() {
+        let mut tree = Tree::default();
+        let root = tree.add_node("root".to_string(), None).unwrap();
+        let child = tree.add_node("child".to_string(), Some(1)).unwrap();
+        tree.add_node("other".to_string(), Some(1)).unwrap();
+
+        assert!(matches!(
+            tree.add_edge(1, root.clone()),
+            Err(AddNodeError::SelfLoop(1))
+        ));
+        assert!(matches!(
+            tree.add_edge(3, child),
+            Err(AddNodeError::AlreadyHasParent(2))
+        ));
+        assert!(matches!(
+            tree.add_edge(9, root),
+            Err(AddNodeError::ParentNotFound(9))
+        ));
     }
 }
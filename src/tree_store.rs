@@ -1,10 +1,38 @@
-use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::{collections::HashMap, fmt, time::Instant};
+
+use tokio::sync::broadcast;
 
 use crate::{
-    node::RcNodeRef,
-    tree::{AddNodeError, Tree},
+    audit::{AuditEntry, AuditLog, AUDIT_CAPACITY},
+    config::TreeConfig,
+    events::TreeEvent,
+    node::{Payload, RcNodeRef},
+    tree::{
+        AddNodeError, Adjacency, CloneError, CollapseError, DeadlinePassed, Direction,
+        EnsureRootError, FlatNode, FlattenError, ImportError, ImportNode, Imported, LabelUpdate,
+        MoveError, PathError, RenameError, RestoreError, SiblingEnd, Snapshot, SwapError,
+        TouchError, Tree,
+    },
 };
 
+// (label, index of the parent in this list) for TreeStore::seed_demo, parents come first.
+const DEMO_TREE: &[(&str, Option<usize>)] = &[
+    ("company", None),
+    ("engineering", Some(0)),
+    ("backend", Some(1)),
+    ("api", Some(2)),
+    ("storage", Some(2)),
+    ("frontend", Some(1)),
+    ("sales", Some(0)),
+    ("emea", Some(6)),
+    ("americas", Some(6)),
+    ("operations", Some(0)),
+];
+
 /*
  * TreeStore is a in-memory store for the tree.
  * In a real application, this would store in the tree in a database.
@@ -12,45 +40,650 @@ use crate::{
  * We would only expose the TreeStore to the rest of the application.
  *
  */
-pub struct TreeStore {
-    lock: Arc<RwLock<Tree>>,
+pub struct TreeStore<T = ()> {
+    lock: Arc<RwLock<Tree<T>>>,
+    // Bumped on every successful mutation, only ever while the write lock is held,
+    // so a reader holding the read lock sees a version that matches the tree.
+    version: AtomicU64,
+    events: broadcast::Sender<TreeEvent>,
+    audit: AuditLog,
 }
 
+// What the read methods return, the lock only errors if a writer panicked while holding it.
+type ReadResult<'a, R, T> = Result<R, PoisonError<RwLockReadGuard<'a, Tree<T>>>>;
+
+// Subscribers that fall further behind than this miss events and are told they lagged.
+const EVENT_CAPACITY: usize = 256;
+
 impl Default for TreeStore {
     fn default() -> Self {
-        TreeStore {
-            lock: Arc::new(RwLock::new(Tree::default())),
-        }
+        TreeStore::with_config(TreeConfig::default())
     }
 }
 
-impl From<PoisonError<RwLockWriteGuard<'_, Tree>>> for AddNodeError {
-    fn from(_: PoisonError<RwLockWriteGuard<'_, Tree>>) -> Self {
-        AddNodeError {
-            message: "failed to get lock".to_string(),
+#[derive(Debug)]
+pub enum WriteError {
+    // the tree has moved on since the caller read it.
+    VersionMismatch { expected: u64, current: u64 },
+    LockPoisoned,
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::VersionMismatch { expected, current } => write!(
+                f,
+                "Tree is at version {}, the request expected version {}",
+                current, expected
+            ),
+            WriteError::LockPoisoned => write!(f, "failed to get lock"),
         }
     }
 }
+
+impl<T> From<PoisonError<RwLockWriteGuard<'_, Tree<T>>>> for WriteError {
+    fn from(_: PoisonError<RwLockWriteGuard<'_, Tree<T>>>) -> Self {
+        WriteError::LockPoisoned
+    }
+}
+
+impl<T> From<PoisonError<RwLockReadGuard<'_, Tree<T>>>> for AddNodeError {
+    fn from(_: PoisonError<RwLockReadGuard<'_, Tree<T>>>) -> Self {
+        AddNodeError::LockPoisoned
+    }
+}
+
+impl<T> From<PoisonError<RwLockWriteGuard<'_, Tree<T>>>> for AddNodeError {
+    fn from(_: PoisonError<RwLockWriteGuard<'_, Tree<T>>>) -> Self {
+        AddNodeError::LockPoisoned
+    }
+}
+impl<T> From<PoisonError<RwLockWriteGuard<'_, Tree<T>>>> for MoveError {
+    fn from(_: PoisonError<RwLockWriteGuard<'_, Tree<T>>>) -> Self {
+        MoveError::LockPoisoned
+    }
+}
+
+impl<T> From<PoisonError<RwLockWriteGuard<'_, Tree<T>>>> for CloneError {
+    fn from(_: PoisonError<RwLockWriteGuard<'_, Tree<T>>>) -> Self {
+        CloneError::LockPoisoned
+    }
+}
+
+impl<T> From<PoisonError<RwLockWriteGuard<'_, Tree<T>>>> for SwapError {
+    fn from(_: PoisonError<RwLockWriteGuard<'_, Tree<T>>>) -> Self {
+        SwapError::LockPoisoned
+    }
+}
+
+impl<T> From<PoisonError<RwLockWriteGuard<'_, Tree<T>>>> for EnsureRootError {
+    fn from(_: PoisonError<RwLockWriteGuard<'_, Tree<T>>>) -> Self {
+        EnsureRootError::LockPoisoned
+    }
+}
+
+impl<T> From<PoisonError<RwLockWriteGuard<'_, Tree<T>>>> for RenameError {
+    fn from(_: PoisonError<RwLockWriteGuard<'_, Tree<T>>>) -> Self {
+        RenameError::LockPoisoned
+    }
+}
+
+impl<T> From<PoisonError<RwLockWriteGuard<'_, Tree<T>>>> for RestoreError {
+    fn from(_: PoisonError<RwLockWriteGuard<'_, Tree<T>>>) -> Self {
+        RestoreError::LockPoisoned
+    }
+}
+
 impl TreeStore {
-    pub fn get_tree(&self) -> Result<Vec<RcNodeRef>, PoisonError<RwLockReadGuard<'_, Tree>>> {
+    pub fn with_config(config: TreeConfig) -> Self {
+        TreeStore::typed(config)
+    }
+}
+
+impl<T: Payload> TreeStore<T> {
+    // A store whose nodes carry a T payload, see Tree::typed.
+    pub fn typed(config: TreeConfig) -> Self {
+        let audit = match &config.audit_log {
+            Some(path) => AuditLog::with_file(AUDIT_CAPACITY, path)
+                .unwrap_or_else(|error| panic!("failed to open audit log {:?}: {}", path, error)),
+            None => AuditLog::new(AUDIT_CAPACITY),
+        };
+        let mut tree = Tree::typed(config);
+        // part of the initial state, so no version bump or event.
+        if tree.config().auto_root.is_some() {
+            let label = tree.config().root_label().to_string();
+            tree.add_node(label, None)
+                .unwrap_or_else(|error| panic!("failed to create the root: {}", error));
+        }
+        TreeStore {
+            audit,
+            lock: Arc::new(RwLock::new(tree)),
+            version: AtomicU64::new(0),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    pub fn get_tree(&self) -> ReadResult<'_, Vec<RcNodeRef<T>>, T> {
         let tree = self.lock.read()?;
         Ok(Vec::from(&*tree))
     }
 
+    pub fn get_versioned_tree(&self) -> ReadResult<'_, (u64, Vec<RcNodeRef<T>>), T> {
+        let tree = self.lock.read()?;
+        Ok((self.version.load(Ordering::SeqCst), Vec::from(&*tree)))
+    }
+
+    pub fn get_versioned_adjacency(&self) -> ReadResult<'_, (u64, Adjacency), T> {
+        let tree = self.lock.read()?;
+        Ok((self.version.load(Ordering::SeqCst), tree.adjacency()))
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    // Runs f under the read lock if the tree is still at version, None once a write has moved it
+    // on. Work spread over several calls (a streamed response) uses this to see one tree.
+    pub fn at_version<R>(&self, version: u64, f: impl FnOnce() -> R) -> Option<R> {
+        let _tree = self.lock.read().ok()?;
+        (self.version() == version).then(f)
+    }
+
+    // The current version and the audit entries after since up to it, None for the entries when
+    // the audit log no longer reaches back to since. The version is read first so an entry
+    // committed meanwhile is left for the next call rather than reported twice.
+    pub fn changes_since(&self, since: u64) -> (u64, Option<Vec<AuditEntry>>) {
+        let version = self.version();
+        (version, self.audit.since(since, version))
+    }
+
+    // Up to limit of the newest audit entries, oldest first.
+    pub fn audit(&self, limit: usize) -> Vec<AuditEntry> {
+        self.audit.recent(limit)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TreeEvent> {
+        self.events.subscribe()
+    }
+
+    // Records a successful mutation, only call this while holding the write lock. rename_node is
+    // the exception, it commits under the read lock, so a reader can see the new label a moment
+    // before the version that goes with it and at_version can find the version moved on.
+    fn commit(&self, event: TreeEvent) {
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        self.audit.record(version, &event);
+        // an error only means nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
+    pub fn contains(&self, id: i32) -> ReadResult<'_, bool, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.contains(id))
+    }
+
+    // The outer None means one of the nodes doesn't exist.
+    pub fn lca(&self, a: i32, b: i32) -> ReadResult<'_, Option<Option<i32>>, T> {
+        let tree = self.lock.read()?;
+        if !tree.contains(a) || !tree.contains(b) {
+            return Ok(None);
+        }
+        Ok(Some(tree.lca(a, b)))
+    }
+
+    // The outer None means one of the nodes doesn't exist.
+    pub fn distance(&self, a: i32, b: i32) -> ReadResult<'_, Option<Option<usize>>, T> {
+        let tree = self.lock.read()?;
+        if !tree.contains(a) || !tree.contains(b) {
+            return Ok(None);
+        }
+        Ok(Some(tree.distance(a, b)))
+    }
+
+    // None means one of the nodes doesn't exist.
+    pub fn is_ancestor(&self, a: i32, b: i32) -> ReadResult<'_, Option<bool>, T> {
+        let tree = self.lock.read()?;
+        if !tree.contains(a) || !tree.contains(b) {
+            return Ok(None);
+        }
+        Ok(Some(tree.is_ancestor(a, b)))
+    }
+
+    pub fn flat_nodes(&self) -> ReadResult<'_, Vec<FlatNode>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.flat_nodes())
+    }
+
+    pub fn ids(&self) -> ReadResult<'_, Vec<i32>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.ids())
+    }
+
+    pub fn find_by_label(&self, label: &str) -> ReadResult<'_, Vec<i32>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.find_by_label(label))
+    }
+
+    pub fn labels_with_prefix(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> ReadResult<'_, Vec<(i32, String)>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.labels_with_prefix(prefix, limit))
+    }
+
+    pub fn to_ndjson(&self) -> ReadResult<'_, String, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.to_ndjson())
+    }
+
+    pub fn to_markdown(&self, escape: bool) -> ReadResult<'_, String, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.to_markdown(escape))
+    }
+
+    pub fn to_dot(&self, color_by_depth: bool) -> ReadResult<'_, String, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.to_dot(color_by_depth))
+    }
+
+    pub fn level_widths(&self) -> ReadResult<'_, Vec<usize>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.level_widths())
+    }
+
+    pub fn children_of(&self, id: i32) -> ReadResult<'_, Option<Vec<RcNodeRef<T>>>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.children_of(id))
+    }
+
+    pub fn siblings(&self, id: i32) -> ReadResult<'_, Option<Vec<RcNodeRef<T>>>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.siblings(id))
+    }
+
+    pub fn leaf_paths(&self, id: i32) -> ReadResult<'_, Option<Vec<Vec<i32>>>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.leaf_paths(id))
+    }
+
+    pub fn locate(&self, id: i32) -> ReadResult<'_, Option<(Option<i32>, usize)>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.locate(id))
+    }
+
+    pub fn subtree_size(&self, id: i32) -> ReadResult<'_, Option<usize>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.subtree_size(id))
+    }
+
+    // The node for each id in order, None for ids that don't exist.
+    pub fn get_nodes(&self, ids: &[i32]) -> ReadResult<'_, Vec<Option<RcNodeRef<T>>>, T> {
+        let tree = self.lock.read()?;
+        Ok(ids.iter().map(|id| tree.get_node(id)).collect())
+    }
+
+    pub fn resolve_path(
+        &self,
+        segments: &[&str],
+    ) -> ReadResult<'_, Result<RcNodeRef<T>, PathError>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.resolve_path(segments))
+    }
+
+    pub fn duplicate_labels(&self, ci: bool) -> ReadResult<'_, Vec<Vec<i32>>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.duplicate_labels(ci))
+    }
+
+    pub fn root_of(&self, id: i32) -> ReadResult<'_, Option<RcNodeRef<T>>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.root_of(id))
+    }
+
+    pub fn verify(&self) -> ReadResult<'_, Result<(), Vec<String>>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.verify())
+    }
+
+    // Runs add_node's validation under the read lock and returns the id and label the node would
+    // get, the tree isn't changed.
+    pub fn validate_add(
+        &self,
+        label: &str,
+        parent_id: Option<i32>,
+    ) -> Result<(i32, String), AddNodeError> {
+        let tree = self.lock.read()?;
+        let label = tree.validate_add(label, parent_id)?;
+        Ok((tree.next_id(), label))
+    }
+
+    // The handlers add through write() so If-Match is honoured, this is the tests' shorthand.
+    #[cfg(test)]
     pub fn add_node(
         &self,
         label: String,
         parent_id: Option<i32>,
-    ) -> Result<RcNodeRef, AddNodeError> {
-        let mut tree = self.lock.write()?;
-        (*tree).add_node(label, parent_id)
+    ) -> Result<RcNodeRef<T>, AddNodeError> {
+        self.lock_write()?
+            .add_node_with_metadata(label, parent_id, HashMap::new())
+    }
+
+    // Whether move_node would accept the move, under the read lock only.
+    pub fn validate_move(&self, id: i32, parent_id: i32) -> Result<(), MoveError> {
+        self.lock
+            .read()
+            .map_err(|_| MoveError::LockPoisoned)?
+            .validate_move(id, parent_id)
+    }
+
+    // Only the read lock is taken to find the node, the node's mutex guards the change, so renames
+    // of different nodes don't wait on each other or on readers. Under unique_sibling_labels the
+    // sibling check and the change must not interleave with another rename, that takes the
+    // write lock.
+    pub fn rename_node(&self, id: i32, label: &str) -> Result<(), RenameError> {
+        let tree = self.lock.read().map_err(|_| RenameError::LockPoisoned)?;
+        if !tree.config().unique_sibling_labels {
+            tree.rename_node(id, label)?;
+            self.commit(TreeEvent::NodesRelabeled { ids: vec![id] });
+            return Ok(());
+        }
+        drop(tree);
+        let mut tree = self.lock_write()?;
+        tree.rename_many(vec![LabelUpdate {
+            id,
+            label: label.to_string(),
+        }])
+    }
+
+    // The snapshot together with the version it was taken at.
+    pub fn snapshot(&self) -> ReadResult<'_, (u64, Snapshot<T>), T> {
+        let tree = self.lock.read()?;
+        Ok((self.version.load(Ordering::SeqCst), tree.snapshot()))
+    }
+
+    // Takes the write lock for a conditional mutation. With if_version set the tree must still be
+    // at that version, the check and the mutation happen under the same lock so another write
+    // can't land in between.
+    pub fn write(&self, if_version: Option<u64>) -> Result<TreeWrite<'_, T>, WriteError> {
+        let write = self.lock_write()?;
+        let current = self.version.load(Ordering::SeqCst);
+        match if_version {
+            Some(expected) if expected != current => {
+                Err(WriteError::VersionMismatch { expected, current })
+            }
+            _ => Ok(write),
+        }
+    }
+
+    // Adds DEMO_TREE, a fixed four level sample, under one write lock. On an empty store the ids
+    // come out as 1..=10 in DEMO_TREE's order, e.g. "backend" is 3 under "engineering" 2.
+    pub fn seed_demo(&self) -> Result<(), AddNodeError> {
+        let mut tree = self.lock_write()?;
+        let mut ids = vec![];
+        for (label, parent) in DEMO_TREE {
+            let parent_id = parent.map(|index| ids[index]);
+            let node = tree.add_node_with_metadata(label.to_string(), parent_id, HashMap::new())?;
+            ids.push(node.lock().unwrap().id);
+        }
+        Ok(())
+    }
+
+    fn lock_write(&self) -> Result<TreeWrite<'_, T>, PoisonError<RwLockWriteGuard<'_, Tree<T>>>> {
+        Ok(TreeWrite {
+            tree: self.lock.write()?,
+            store: self,
+        })
     }
 
-    // Using this for tests so will allow for dead code
-    #[allow(dead_code)]
     pub fn len(&self) -> i32 {
         let tree = self.lock.read().unwrap();
-        (*tree).len() as i32
+        (*tree).len()
+    }
+
+    pub fn default_label(&self) -> ReadResult<'_, Option<String>, T> {
+        let tree = self.lock.read()?;
+        Ok(tree.config().default_label.clone())
+    }
+}
+
+/*
+ * TreeWrite holds the store's write lock, every mutation goes through one and is committed
+ * (version bump and event) before the call returns. Drop it before reading the tree back,
+ * the read lock would otherwise wait on it.
+ */
+pub struct TreeWrite<'a, T = ()> {
+    tree: RwLockWriteGuard<'a, Tree<T>>,
+    store: &'a TreeStore<T>,
+}
+
+impl<T: Payload> TreeWrite<'_, T> {
+    pub fn add_node_with_metadata(
+        &mut self,
+        label: String,
+        parent_id: Option<i32>,
+        metadata: HashMap<String, String>,
+    ) -> Result<RcNodeRef<T>, AddNodeError> {
+        let node = self
+            .tree
+            .add_node_with_metadata(label, parent_id, metadata)?;
+        self.store.commit(TreeEvent::NodeAdded {
+            id: node.lock().unwrap().id,
+        });
+        Ok(node)
+    }
+
+    pub fn add_sorted_node(
+        &mut self,
+        label: String,
+        parent_id: Option<i32>,
+        metadata: HashMap<String, String>,
+        sort_key: Option<i64>,
+    ) -> Result<RcNodeRef<T>, AddNodeError> {
+        let node = self
+            .tree
+            .add_sorted_node(label, parent_id, metadata, sort_key)?;
+        self.store.commit(TreeEvent::NodeAdded {
+            id: node.lock().unwrap().id,
+        });
+        Ok(node)
+    }
+
+    pub fn set_metadata(&mut self, id: i32, key: String, value: String) -> Option<RcNodeRef<T>> {
+        let node = self.tree.set_metadata(id, key, value);
+        if node.is_some() {
+            self.store.commit(TreeEvent::MetadataUpdated { id });
+        }
+        node
+    }
+
+    pub fn move_node(
+        &mut self,
+        id: i32,
+        parent_id: i32,
+        index: usize,
+    ) -> Result<Option<i32>, MoveError> {
+        let old_parent_id = self.tree.move_node(id, parent_id, index)?;
+        self.store.commit(TreeEvent::NodeMoved { id });
+        Ok(old_parent_id)
+    }
+
+    pub fn move_after(
+        &mut self,
+        id: i32,
+        parent_id: i32,
+        after_sibling_id: Option<i32>,
+    ) -> Result<Option<i32>, MoveError> {
+        let old_parent_id = self.tree.move_after(id, parent_id, after_sibling_id)?;
+        self.store.commit(TreeEvent::NodeMoved { id });
+        Ok(old_parent_id)
+    }
+
+    pub fn reorder(&mut self, id: i32, to: SiblingEnd) -> Result<(), MoveError> {
+        self.tree.reorder(id, to)?;
+        self.store.commit(TreeEvent::NodeMoved { id });
+        Ok(())
+    }
+
+    // Nothing is committed when the node was already at that end.
+    pub fn nudge(&mut self, id: i32, direction: Direction) -> Result<bool, MoveError> {
+        let moved = self.tree.nudge(id, direction)?;
+        if moved {
+            self.store.commit(TreeEvent::NodeMoved { id });
+        }
+        Ok(moved)
+    }
+
+    pub fn clone_subtree(
+        &mut self,
+        id: i32,
+        new_parent: Option<i32>,
+    ) -> Result<RcNodeRef<T>, CloneError> {
+        let clone = self.tree.clone_subtree(id, new_parent)?;
+        self.store.commit(TreeEvent::SubtreeCloned {
+            id: clone.lock().unwrap().id,
+            source_id: id,
+        });
+        Ok(clone)
+    }
+
+    // Swapping a node with itself changes nothing, so nothing is committed either.
+    pub fn swap_nodes(&mut self, a: i32, b: i32) -> Result<(), SwapError> {
+        self.tree.swap_nodes(a, b)?;
+        if a != b {
+            self.store.commit(TreeEvent::NodesSwapped { a, b });
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> &TreeConfig {
+        self.tree.config()
+    }
+
+    pub fn ensure_root(&mut self, default_label: &str) -> Result<RcNodeRef<T>, EnsureRootError> {
+        let len = self.tree.len();
+        let root = self.tree.ensure_root(default_label)?;
+        if self.tree.len() != len {
+            self.store.commit(TreeEvent::NodeAdded {
+                id: root.lock().unwrap().id,
+            });
+        }
+        Ok(root)
+    }
+
+    pub fn rename_many(&mut self, updates: Vec<LabelUpdate>) -> Result<(), RenameError> {
+        let mut ids: Vec<i32> = updates.iter().map(|update| update.id).collect();
+        self.tree.rename_many(updates)?;
+        ids.sort_unstable();
+        ids.dedup();
+        if !ids.is_empty() {
+            self.store.commit(TreeEvent::NodesRelabeled { ids });
+        }
+        Ok(())
+    }
+
+    pub fn prune_leaves(&mut self) -> Vec<i32> {
+        let ids = self.tree.prune_leaves();
+        if !ids.is_empty() {
+            self.store
+                .commit(TreeEvent::LeavesPruned { ids: ids.clone() });
+        }
+        ids
+    }
+
+    pub fn flatten_node(&mut self, id: i32) -> Result<i32, FlattenError> {
+        let parent_id = self.tree.flatten_node(id)?;
+        self.store
+            .commit(TreeEvent::NodeFlattened { id, parent_id });
+        Ok(parent_id)
+    }
+
+    // Returns the node's new updated_at.
+    pub fn touch(&mut self, id: i32) -> Result<u64, TouchError> {
+        self.tree.touch(id)?;
+        self.store.commit(TreeEvent::NodeTouched { id });
+        Ok(self.tree.get_node(&id).unwrap().lock().unwrap().updated_at)
+    }
+
+    pub fn collapse_chain(
+        &mut self,
+        id: i32,
+        separator: &str,
+    ) -> Result<RcNodeRef<T>, CollapseError> {
+        let removed = self.tree.single_child_chain(id);
+        let node = self.tree.collapse_chain(id, separator)?;
+        if !removed.is_empty() {
+            self.store.commit(TreeEvent::ChainCollapsed { id, removed });
+        }
+        Ok(node)
+    }
+
+    pub fn import(
+        &mut self,
+        nodes: Vec<ImportNode>,
+        deadline: Option<Instant>,
+    ) -> Result<Imported, ImportError> {
+        let imported = self.tree.import(nodes, deadline)?;
+        if !imported.ids.is_empty() {
+            self.store.commit(TreeEvent::NodesImported {
+                ids: imported.ids.clone(),
+            });
+        }
+        Ok(imported)
+    }
+
+    pub fn import_best_effort(
+        &mut self,
+        nodes: Vec<ImportNode>,
+        deadline: Option<Instant>,
+    ) -> (Imported, Vec<ImportError>) {
+        let (imported, failed) = self.tree.import_best_effort(nodes, deadline);
+        if !imported.ids.is_empty() {
+            self.store.commit(TreeEvent::NodesImported {
+                ids: imported.ids.clone(),
+            });
+        }
+        (imported, failed)
+    }
+
+    pub fn remove_many(
+        &mut self,
+        ids: &[i32],
+        deadline: Option<Instant>,
+    ) -> Result<Vec<i32>, DeadlinePassed> {
+        let removed = self.tree.remove_many(ids, deadline)?;
+        if !removed.is_empty() {
+            self.store.commit(TreeEvent::NodesRemoved {
+                ids: removed.clone(),
+            });
+        }
+        Ok(removed)
+    }
+
+    pub fn compact(&mut self) -> HashMap<i32, i32> {
+        let mapping = self.tree.compact();
+        if mapping.iter().any(|(old_id, new_id)| old_id != new_id) {
+            self.store.commit(TreeEvent::IdsCompacted);
+        }
+        mapping
+    }
+
+    pub fn rebuild_indexes(&mut self) -> bool {
+        let rebuilt = self.tree.rebuild_indexes();
+        if rebuilt {
+            self.store.commit(TreeEvent::IndexesRebuilt);
+        }
+        rebuilt
+    }
+
+    pub fn restore(
+        &mut self,
+        snapshot: Snapshot<T>,
+        deadline: Option<Instant>,
+    ) -> Result<(), RestoreError> {
+        self.tree.restore(snapshot, deadline)?;
+        self.store.commit(TreeEvent::TreeRestored);
+        Ok(())
     }
 }
 
@@ -59,6 +692,8 @@ impl TreeStore {
 mod test {
 
     use super::*;
+    use crate::config::AutoRoot;
+    use std::{sync::mpsc, thread, time::Duration};
 
     #[test]
     fn creates_default_tree() {
@@ -82,6 +717,112 @@ mod test {
         let tree = tree_provider.get_tree().unwrap();
         assert_eq!(tree.len(), 1);
     }
+
+    #[test]
+    fn moves_node() {
+        let tree_provider = TreeStore::default();
+        tree_provider.add_node("root".to_string(), None).unwrap();
+        tree_provider.add_node("child".to_string(), None).unwrap();
+        tree_provider
+            .write(None)
+            .unwrap()
+            .move_node(2, 1, 0)
+            .unwrap();
+        let tree = tree_provider.get_tree().unwrap();
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn bumps_version_on_successful_mutations_only() {
+        let tree_provider = TreeStore::default();
+        assert_eq!(tree_provider.version(), 0);
+
+        tree_provider.add_node("root".to_string(), None).unwrap();
+        tree_provider.add_node("child".to_string(), None).unwrap();
+        assert_eq!(tree_provider.version(), 2);
+
+        assert!(tree_provider
+            .add_node("orphan".to_string(), Some(9))
+            .is_err());
+        assert!(tree_provider
+            .write(None)
+            .unwrap()
+            .move_node(1, 1, 0)
+            .is_err());
+        assert!(tree_provider
+            .write(None)
+            .unwrap()
+            .set_metadata(9, "k".to_string(), "v".to_string())
+            .is_none());
+        assert_eq!(tree_provider.version(), 2);
+
+        tree_provider
+            .write(None)
+            .unwrap()
+            .move_node(2, 1, 0)
+            .unwrap();
+        tree_provider
+            .write(None)
+            .unwrap()
+            .set_metadata(1, "k".to_string(), "v".to_string());
+        let (version, tree) = tree_provider.get_versioned_tree().unwrap();
+        assert_eq!(version, 4);
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn publishes_events_to_subscribers() {
+        let tree_provider = TreeStore::default();
+        let mut events = tree_provider.subscribe();
+
+        tree_provider.add_node("root".to_string(), None).unwrap();
+        tree_provider.add_node("child".to_string(), None).unwrap();
+        assert!(tree_provider
+            .add_node("orphan".to_string(), Some(9))
+            .is_err());
+        tree_provider
+            .write(None)
+            .unwrap()
+            .move_node(2, 1, 0)
+            .unwrap();
+
+        assert_eq!(events.try_recv().unwrap(), TreeEvent::NodeAdded { id: 1 });
+        assert_eq!(events.try_recv().unwrap(), TreeEvent::NodeAdded { id: 2 });
+        assert_eq!(events.try_recv().unwrap(), TreeEvent::NodeMoved { id: 2 });
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn conditional_writes_check_the_version() {
+        let tree_provider = TreeStore::default();
+        tree_provider.add_node("root".to_string(), None).unwrap();
+
+        assert!(matches!(
+            tree_provider.write(Some(0)),
+            Err(WriteError::VersionMismatch {
+                expected: 0,
+                current: 1
+            })
+        ));
+        tree_provider
+            .write(Some(1))
+            .unwrap()
+            .add_node_with_metadata("child".to_string(), Some(1), HashMap::new())
+            .unwrap();
+        tree_provider.write(None).unwrap().swap_nodes(1, 1).unwrap();
+        assert_eq!(tree_provider.version(), 2);
+    }
+
+    #[test]
+    fn rejects_cyclic_move() {
+        let tree_provider = TreeStore::default();
+        tree_provider.add_node("root".to_string(), None).unwrap();
+        tree_provider
+            .add_node("child".to_string(), Some(1))
+            .unwrap();
+        let result = tree_provider.write(None).unwrap().move_node(1, 2, 0);
+        assert!(matches!(result, Err(MoveError::Cycle { .. })));
+    }
     //
     // #[test]
     // fn handles_multi_thread_access() {
@@ -98,4 +839,183 @@ mod test {
     //     let tree = tree_provider.get_tree().unwrap();
     //     assert_eq!(tree.len(), 2)
     // }
+
+    #[test]
+    fn renames_different_nodes_without_blocking_readers() {
+        let tree_provider = Arc::new(TreeStore::default());
+        tree_provider.add_node("root".to_string(), None).unwrap();
+        tree_provider.add_node("a".to_string(), Some(1)).unwrap();
+        tree_provider.add_node("b".to_string(), Some(1)).unwrap();
+
+        // a reader in the middle of reading the tree, a rename taking the write lock would wait
+        // for it.
+        let reader = tree_provider.lock.read().unwrap();
+        let (done, renamed) = mpsc::channel();
+        for (id, label) in [(2, "a2"), (3, "b2")] {
+            let tree_provider = Arc::clone(&tree_provider);
+            let done = done.clone();
+            thread::spawn(move || {
+                tree_provider.rename_node(id, label).unwrap();
+                done.send(id).unwrap();
+            });
+        }
+        let mut ids: Vec<i32> = (0..2)
+            .map(|_| renamed.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(reader.get_node(&2).unwrap().lock().unwrap().label, "a2");
+        assert_eq!(reader.get_node(&3).unwrap().lock().unwrap().label, "b2");
+        assert_eq!(reader.find_by_label("a2"), vec![2]);
+        drop(reader);
+        assert_eq!(tree_provider.version(), 5);
+    }
+
+    #[test]
+    fn rename_node_checks_sibling_labels() {
+        let tree_provider = TreeStore::with_config(TreeConfig {
+            unique_sibling_labels: true,
+            ..TreeConfig::default()
+        });
+        tree_provider.add_node("root".to_string(), None).unwrap();
+        tree_provider.add_node("a".to_string(), Some(1)).unwrap();
+        tree_provider.add_node("b".to_string(), Some(1)).unwrap();
+
+        assert!(matches!(
+            tree_provider.rename_node(3, "a"),
+            Err(RenameError::LabelTaken { parent_id: 1, .. })
+        ));
+        assert!(tree_provider.rename_node(3, "b").is_ok());
+        assert!(matches!(
+            tree_provider.rename_node(9, "c"),
+            Err(RenameError::NodeNotFound(9))
+        ));
+        assert_eq!(tree_provider.version(), 4);
+    }
+
+    #[test]
+    fn auto_root_starts_with_a_root() {
+        let tree_provider = TreeStore::with_config(TreeConfig {
+            root_label: Some("home".to_string()),
+            auto_root: Some(AutoRoot::Reject),
+            ..TreeConfig::default()
+        });
+        assert_eq!(tree_provider.len(), 1);
+        assert_eq!(tree_provider.version(), 0);
+        let tree = tree_provider.get_tree().unwrap();
+        assert_eq!(tree[0].lock().unwrap().label, "home");
+        drop(tree);
+
+        assert!(matches!(
+            tree_provider.add_node("other".to_string(), None),
+            Err(AddNodeError::RootExists(1))
+        ));
+        assert!(tree_provider.add_node("a".to_string(), Some(1)).is_ok());
+    }
+
+    #[test]
+    fn auto_root_can_attach_parentless_nodes() {
+        let tree_provider = TreeStore::with_config(TreeConfig {
+            auto_root: Some(AutoRoot::Attach),
+            ..TreeConfig::default()
+        });
+        let node = tree_provider.add_node("a".to_string(), None).unwrap();
+        let id = node.lock().unwrap().id;
+        assert_eq!(tree_provider.is_ancestor(1, id).unwrap(), Some(true));
+        assert_eq!(tree_provider.get_tree().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn concurrent_swaps_of_one_pair_are_serialized() {
+        let tree_provider = Arc::new(TreeStore::default());
+        tree_provider.add_node("root".to_string(), None).unwrap();
+        tree_provider.add_node("left".to_string(), Some(1)).unwrap();
+        tree_provider
+            .add_node("right".to_string(), Some(1))
+            .unwrap();
+        tree_provider.add_node("a".to_string(), Some(2)).unwrap();
+        tree_provider.add_node("b".to_string(), Some(3)).unwrap();
+
+        let (done, finished) = mpsc::channel();
+        for (a, b) in [(4, 5), (5, 4)] {
+            let tree_provider = Arc::clone(&tree_provider);
+            let done = done.clone();
+            thread::spawn(move || {
+                for _ in 0..500 {
+                    tree_provider.write(None).unwrap().swap_nodes(a, b).unwrap();
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..2 {
+            finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        // an even number of swaps puts both back.
+        let tree = tree_provider.lock.read().unwrap();
+        let child_of = |id| {
+            let parent = tree.get_node(&id).unwrap();
+            let child = parent.lock().unwrap().children()[0].clone();
+            let id = child.lock().unwrap().id;
+            id
+        };
+        assert_eq!((child_of(2), child_of(3)), (4, 5));
+        assert_eq!(tree_provider.version(), 1005);
+    }
+
+    #[test]
+    fn seeds_the_demo_tree() {
+        let tree_provider = TreeStore::default();
+        tree_provider.seed_demo().unwrap();
+
+        assert_eq!(tree_provider.len(), 10);
+        assert_eq!(tree_provider.version(), 10);
+        let tree = tree_provider.get_tree().unwrap();
+        assert_eq!(tree.len(), 1);
+        let backend = tree_provider.children_of(2).unwrap().unwrap()[0].clone();
+        let backend = backend.lock().unwrap();
+        assert_eq!((backend.id, backend.label.as_str()), (3, "backend"));
+        let children: Vec<String> = backend
+            .children()
+            .iter()
+            .map(|child| child.lock().unwrap().label.clone())
+            .collect();
+        assert_eq!(children, vec!["api", "storage"]);
+    }
+
+    #[test]
+    fn reports_the_lock_poisoned_after_a_write_panicked() {
+        let tree_provider = TreeStore::default();
+        tree_provider.add_node("root".to_string(), None).unwrap();
+        // a poisoned root makes the next add under it panic while holding the write lock.
+        let root = tree_provider.get_tree().unwrap()[0].clone();
+        let _ = std::thread::spawn({
+            let root = root.clone();
+            move || {
+                let _guard = root.lock().unwrap();
+                panic!("poisoning the root");
+            }
+        })
+        .join();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tree_provider.add_node("a".to_string(), Some(1))
+        }));
+        assert!(result.is_err());
+
+        assert!(matches!(
+            tree_provider.add_node("b".to_string(), None),
+            Err(AddNodeError::LockPoisoned)
+        ));
+        assert!(tree_provider.get_tree().is_err());
+        // the add panicked before changing anything and nothing was committed.
+        root.clear_poison();
+        let tree = tree_provider
+            .lock
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        assert!(tree.verify().is_ok());
+        assert_eq!(tree.ids(), vec![1]);
+        drop(tree);
+        assert_eq!(tree_provider.version(), 1);
+    }
 }